@@ -29,7 +29,7 @@ use virtio_drivers::{
     device::{
         blk::VirtIOBlk,
         console::VirtIOConsole,
-        gpu::VirtIOGpu,
+        gpu::{Format, VirtIOGpu},
         socket::{VirtIOSocket, VsockAddr, VsockConnectionManager, VsockEventType},
     },
     transport::{
@@ -167,10 +167,12 @@ fn virtio_gpu<T: Transport>(transport: T) {
     let width = width as usize;
     let height = height as usize;
     info!("GPU resolution is {}x{}", width, height);
-    let fb = gpu.setup_framebuffer().expect("failed to get fb");
+    let (fb, info) = gpu
+        .setup_framebuffer(Format::B8G8R8A8UNORM)
+        .expect("failed to get fb");
     for y in 0..height {
         for x in 0..width {
-            let idx = (y * width + x) * 4;
+            let idx = y * info.stride as usize + x * 4;
             fb[idx] = x as u8;
             fb[idx + 1] = y as u8;
             fb[idx + 2] = (x + y) as u8;
@@ -226,7 +228,7 @@ fn virtio_socket<T: Transport>(transport: T) -> virtio_drivers::Result<()> {
     for k in 0..EXCHANGE_NUM {
         let mut buffer = [0u8; 24];
         let socket_event = socket.wait_for_event()?;
-        let VsockEventType::Received {length, ..} = socket_event.event_type else {
+        let VsockEventType::Received { length, .. } = socket_event.event_type else {
             panic!("Received unexpected socket event {:?}", socket_event);
         };
         let read_length = socket.recv(host_address, port, &mut buffer)?;
@@ -404,22 +406,26 @@ fn allocate_bars(
             address_type, size, ..
         } = info
         {
-            match address_type {
-                MemoryBarType::Width32 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_32(device_function, bar_index, address);
-                    }
+            match (address_type, u32::try_from(size)) {
+                (_, Ok(0)) => {}
+                (MemoryBarType::Width32, Ok(size)) => {
+                    let address = allocator.allocate_memory_32(size);
+                    debug!("Allocated address {:#010x}", address);
+                    root.set_bar_32(device_function, bar_index, address);
                 }
-                MemoryBarType::Width64 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_64(device_function, bar_index, address.into());
-                    }
+                (MemoryBarType::Width64, Ok(size)) => {
+                    let address = allocator.allocate_memory_32(size);
+                    debug!("Allocated address {:#010x}", address);
+                    root.set_bar_64(device_function, bar_index, address.into());
+                }
+                // Only 32-bit memory is allocated, so very large BARs such as shared memory
+                // regions are left unallocated.
+                (MemoryBarType::Width32 | MemoryBarType::Width64, Err(_)) => {
+                    warn!(
+                        "Not allocating BAR {} of size {:#x}, which doesn't fit in 32-bit memory",
+                        bar_index, size
+                    );
                 }
-
                 _ => panic!("Memory BAR address type {:?} not supported.", address_type),
             }
         }
@@ -13,7 +13,12 @@ use core::ptr::NonNull;
 use fdt::{node::FdtNode, standard_nodes::Compatible, Fdt};
 use log::LevelFilter;
 use virtio_drivers::{
-    device::{blk::VirtIOBlk, gpu::VirtIOGpu, input::VirtIOInput, net::VirtIONet},
+    device::{
+        blk::VirtIOBlk,
+        gpu::{Format, VirtIOGpu},
+        input::VirtIOInput,
+        net::VirtIONet,
+    },
     transport::{
         mmio::{MmioTransport, VirtIOHeader},
         DeviceType, Transport,
@@ -69,7 +74,7 @@ fn virtio_probe(node: FdtNode) {
         );
         let header = NonNull::new(vaddr as *mut VirtIOHeader).unwrap();
         let virtio_header_ref = unsafe { (vaddr as *mut VirtIOHeader).as_ref().unwrap() };
-        info!("VirtIOHeader : {:?}",*virtio_header_ref);
+        info!("VirtIOHeader : {:?}", *virtio_header_ref);
         match unsafe { MmioTransport::new(header) } {
             Err(e) => warn!("Error creating VirtIO MMIO transport: {}", e),
             Ok(transport) => {
@@ -117,7 +122,9 @@ fn virtio_gpu<T: Transport>(transport: T) {
     let height = height as usize;
     info!("GPU resolution is {}x{}", width, height);
     // 设置显示缓冲区
-    let fb = gpu.setup_framebuffer().expect("failed to get fb");
+    let (fb, info) = gpu
+        .setup_framebuffer(Format::B8G8R8A8UNORM)
+        .expect("failed to get fb");
     let mut index = 0;
     let pic_info = [
         255, 247, 234, 240, 241, 236, 239, 253, 255, 252, 113, 48, 230, 88, 22, 218, 95, 2, 236,
@@ -461,7 +468,7 @@ fn virtio_gpu<T: Transport>(transport: T) {
             //  |(255,255,255) | (100,100,100) |
             //  |--------------| --------------|
             //  |(200,200,200) | (50,50,50)    |
-            let idx = (y * width + x) * 4;
+            let idx = y * info.stride as usize + x * 4;
             fb[idx] = pic_info[index + 2] as u8; // Blue
             fb[idx + 1] = pic_info[index + 1] as u8; // Green
             fb[idx + 2] = pic_info[index] as u8; //Red
@@ -505,7 +512,8 @@ fn virtio_net<T: Transport>(transport: T) {
             match net.receive() {
                 Ok(buf) => {
                     info!("RECV {} bytes: {:02x?}", buf.packet_len(), buf.packet());
-                    let tx_buf = virtio_drivers::device::net::TxBuffer::from(buf.packet());
+                    let mut tx_buf = net.new_tx_buffer(buf.packet_len()).unwrap();
+                    tx_buf.packet_mut().copy_from_slice(buf.packet());
                     net.send(tx_buf).expect("failed to send");
                     net.recycle_rx_buffer(buf).unwrap();
                     break;
@@ -18,7 +18,11 @@ mod tcp;
 
 use self::hal::HalImpl;
 use virtio_drivers::{
-    device::{blk::VirtIOBlk, gpu::VirtIOGpu, net::VirtIONet},
+    device::{
+        blk::VirtIOBlk,
+        gpu::{Format, VirtIOGpu},
+        net::VirtIONet,
+    },
     transport::{
         pci::{
             bus::{BarInfo, Cam, Command, DeviceFunction, PciRoot},
@@ -93,10 +97,12 @@ fn virtio_gpu<T: Transport>(transport: T) {
     let width = width as usize;
     let height = height as usize;
     info!("GPU resolution is {}x{}", width, height);
-    let fb = gpu.setup_framebuffer().expect("failed to get fb");
+    let (fb, info) = gpu
+        .setup_framebuffer(Format::B8G8R8A8UNORM)
+        .expect("failed to get fb");
     for y in 0..height {
         for x in 0..width {
-            let idx = (y * width + x) * 4;
+            let idx = y * info.stride as usize + x * 4;
             fb[idx] = x as u8;
             fb[idx + 1] = y as u8;
             fb[idx + 2] = (x + y) as u8;
@@ -128,7 +134,8 @@ fn virtio_net<T: Transport>(transport: T) {
             match net.receive() {
                 Ok(buf) => {
                     info!("RECV {} bytes: {:02x?}", buf.packet_len(), buf.packet());
-                    let tx_buf = virtio_drivers::device::net::TxBuffer::from(buf.packet());
+                    let mut tx_buf = net.new_tx_buffer(buf.packet_len()).unwrap();
+                    tx_buf.packet_mut().copy_from_slice(buf.packet());
                     net.send(tx_buf).expect("failed to send");
                     net.recycle_rx_buffer(buf).unwrap();
                     break;
@@ -0,0 +1,185 @@
+//! The control queues used by multiport VirtIO console devices.
+
+use super::QUEUE_SIZE;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{boxed::Box, vec::Vec};
+use core::mem::size_of;
+use zerocopy::{AsBytes, FromBytes};
+
+const QUEUE_CONTROL_RECEIVEQ: u16 = 2;
+const QUEUE_CONTROL_TRANSMITQ: u16 = 3;
+
+/// The size of each buffer for control messages from the device, which must be big enough for the
+/// header and a port name.
+const CONTROL_BUFFER_LEN: usize = 256;
+
+/// An event reported by a multiport console device on its control queue.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConsoleEvent {
+    /// A new port has been added, with the given ID.
+    PortAdded(u32),
+    /// The port with the given ID has been removed.
+    PortRemoved(u32),
+    /// The port with the given ID has been marked as a console.
+    ConsolePort(u32),
+    /// The port with the given ID has been given a name.
+    PortNamed(u32),
+    /// The host side of the port with the given ID has been opened.
+    PortOpened(u32),
+    /// The host side of the port with the given ID has been closed.
+    PortClosed(u32),
+    /// The console on the port with the given ID has changed size.
+    Resized {
+        /// The ID of the port. This is always 0 for changes reported through the configuration
+        /// space.
+        id: u32,
+        /// The new height of the console in characters.
+        rows: u16,
+        /// The new width of the console in characters.
+        columns: u16,
+    },
+}
+
+/// The header of every control message, in both directions.
+#[repr(C)]
+#[derive(AsBytes, Clone, Debug, Eq, FromBytes, PartialEq)]
+pub(crate) struct ControlHeader {
+    pub id: u32,
+    pub event: ControlEvent,
+    pub value: u16,
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, PartialEq)]
+pub(crate) struct ControlEvent(u16);
+
+impl ControlEvent {
+    /// Sent by the driver once it is ready to receive control messages.
+    pub const DEVICE_READY: ControlEvent = ControlEvent(0);
+    /// Sent by the device to add a port.
+    pub const DEVICE_ADD: ControlEvent = ControlEvent(1);
+    /// Sent by the device to remove a port.
+    pub const DEVICE_REMOVE: ControlEvent = ControlEvent(2);
+    /// Sent by the driver once it has set up a port added by the device.
+    pub const PORT_READY: ControlEvent = ControlEvent(3);
+    /// Sent by the device to mark a port as a console.
+    pub const CONSOLE_PORT: ControlEvent = ControlEvent(4);
+    /// Sent by the device when the console size changes.
+    pub const RESIZE: ControlEvent = ControlEvent(5);
+    /// Sent by either side to open or close its end of a port.
+    pub const PORT_OPEN: ControlEvent = ControlEvent(6);
+    /// Sent by the device to name a port, with the name following the header.
+    pub const PORT_NAME: ControlEvent = ControlEvent(7);
+}
+
+/// A control message received from the device.
+#[derive(Debug)]
+pub(crate) struct ControlMessage {
+    pub header: ControlHeader,
+    /// Any data following the header.
+    pub data: Vec<u8>,
+}
+
+/// The control receive and transmit queues, along with the buffers for received messages.
+pub(crate) struct Control<H: Hal> {
+    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    /// Buffers for messages from the device, indexed by the token with which they were added to
+    /// the receive queue.
+    queue_bufs_rx: Box<[[u8; CONTROL_BUFFER_LEN]; QUEUE_SIZE]>,
+}
+
+impl<H: Hal> Control<H> {
+    /// Sets up the control queues.
+    pub(crate) fn new<T: Transport>(transport: &mut T) -> Result<Self> {
+        Ok(Self {
+            receiveq: VirtQueue::new(transport, QUEUE_CONTROL_RECEIVEQ)?,
+            transmitq: VirtQueue::new(transport, QUEUE_CONTROL_TRANSMITQ)?,
+            queue_bufs_rx: Box::new([[0; CONTROL_BUFFER_LEN]; QUEUE_SIZE]),
+        })
+    }
+
+    /// Adds all the receive buffers to the control receive queue.
+    pub(crate) fn fill(&mut self, transport: &mut impl Transport) -> Result<()> {
+        for index in 0..QUEUE_SIZE {
+            self.add_rx_buffer(index as u16)?;
+        }
+        if self.receiveq.should_notify() {
+            transport.notify(QUEUE_CONTROL_RECEIVEQ);
+        }
+        Ok(())
+    }
+
+    /// Adds the receive buffer with the given index to the control receive queue, and checks that
+    /// it gets the same token.
+    fn add_rx_buffer(&mut self, index: u16) -> Result<()> {
+        // Safe because the buffer lasts as long as the queue, and is not otherwise accessed until
+        // it is popped.
+        let token = unsafe {
+            self.receiveq.add(
+                &[],
+                &mut [self.queue_bufs_rx[usize::from(index)].as_mut_slice()],
+            )
+        }?;
+        if token != index {
+            return Err(Error::WrongToken);
+        }
+        Ok(())
+    }
+
+    /// Returns the next message which the device has sent on the control queue, if any, and gives
+    /// its buffer back to the device.
+    pub(crate) fn pop(&mut self, transport: &mut impl Transport) -> Result<Option<ControlMessage>> {
+        let Some(token) = self.receiveq.peek_used() else {
+            return Ok(None);
+        };
+        let buffer = self
+            .queue_bufs_rx
+            .get_mut(usize::from(token))
+            .ok_or(Error::WrongToken)?;
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it is
+        // still valid.
+        let len = unsafe {
+            self.receiveq
+                .pop_used(token, &[], &mut [buffer.as_mut_slice()])?
+        } as usize;
+        let message = if (size_of::<ControlHeader>()..=CONTROL_BUFFER_LEN).contains(&len) {
+            let (header, data) = buffer[..len].split_at(size_of::<ControlHeader>());
+            ControlHeader::read_from(header).map(|header| ControlMessage {
+                header,
+                data: data.to_vec(),
+            })
+        } else {
+            None
+        };
+        self.add_rx_buffer(token)?;
+        if self.receiveq.should_notify() {
+            transport.notify(QUEUE_CONTROL_RECEIVEQ);
+        }
+        message.map(Some).ok_or(Error::IoError)
+    }
+
+    /// Sends a control message to the device, and waits for it to be consumed.
+    pub(crate) fn send(
+        &mut self,
+        transport: &mut impl Transport,
+        id: u32,
+        event: ControlEvent,
+        value: u16,
+    ) -> Result<()> {
+        let header = ControlHeader { id, event, value };
+        self.transmitq
+            .add_notify_wait_pop(&[header.as_bytes()], &mut [], transport)?;
+        Ok(())
+    }
+
+    /// Clears the control queues from the transport, so the device doesn't try to access them
+    /// after they have been freed.
+    pub(crate) fn unset_queues(&self, transport: &mut impl Transport) {
+        transport.queue_unset(QUEUE_CONTROL_RECEIVEQ);
+        transport.queue_unset(QUEUE_CONTROL_TRANSMITQ);
+    }
+}
@@ -0,0 +1,177 @@
+//! Implementations of the `embedded-io` traits for VirtIO consoles.
+
+use super::{ConsolePort, VirtIOConsole};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use core::hint::spin_loop;
+use embedded_io::{ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+
+impl embedded_io::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidParam => ErrorKind::InvalidInput,
+            Self::DmaError => ErrorKind::OutOfMemory,
+            Self::Unsupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Blocks until `recv_bytes` returns some data, unless `buf` is empty.
+fn read_blocking(
+    buf: &mut [u8],
+    mut recv_bytes: impl FnMut(&mut [u8]) -> Result<usize>,
+) -> Result<usize> {
+    loop {
+        let len = recv_bytes(buf)?;
+        if len > 0 || buf.is_empty() {
+            return Ok(len);
+        }
+        spin_loop();
+    }
+}
+
+/// Blocks until `send_bytes_nb` accepts some data, unless `buf` is empty.
+fn write_blocking(
+    buf: &[u8],
+    mut send_bytes_nb: impl FnMut(&[u8]) -> Result<usize>,
+) -> Result<usize> {
+    loop {
+        let len = send_bytes_nb(buf)?;
+        if len > 0 || buf.is_empty() {
+            return Ok(len);
+        }
+        spin_loop();
+    }
+}
+
+impl<H: Hal, T: Transport> ErrorType for VirtIOConsole<H, T> {
+    type Error = Error;
+}
+
+/// Reads from port 0, blocking until at least one byte is available and then returning whatever
+/// has been received so far.
+impl<H: Hal, T: Transport> Read for VirtIOConsole<H, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read_blocking(buf, |buf| self.recv_bytes(buf))
+    }
+}
+
+impl<H: Hal, T: Transport> ReadReady for VirtIOConsole<H, T> {
+    fn read_ready(&mut self) -> Result<bool> {
+        self.ports[0].can_recv()
+    }
+}
+
+/// Writes to port 0, blocking until at least one byte has been submitted to the device.
+impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write_blocking(buf, |buf| self.send_bytes_nb(buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        VirtIOConsole::flush(self)
+    }
+}
+
+impl<H: Hal, T: Transport> WriteReady for VirtIOConsole<H, T> {
+    fn write_ready(&mut self) -> Result<bool> {
+        self.ports[0].can_send()
+    }
+}
+
+impl<H: Hal, T: Transport> ErrorType for ConsolePort<'_, H, T> {
+    type Error = Error;
+}
+
+/// Reads from the port, blocking until at least one byte is available and then returning whatever
+/// has been received so far.
+impl<H: Hal, T: Transport> Read for ConsolePort<'_, H, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read_blocking(buf, |buf| self.recv_bytes(buf))
+    }
+}
+
+impl<H: Hal, T: Transport> ReadReady for ConsolePort<'_, H, T> {
+    fn read_ready(&mut self) -> Result<bool> {
+        self.console.ports[self.id as usize].can_recv()
+    }
+}
+
+/// Writes to the port, blocking until at least one byte has been submitted to the device.
+impl<H: Hal, T: Transport> Write for ConsolePort<'_, H, T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write_blocking(buf, |buf| self.send_bytes_nb(buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        ConsolePort::flush(self)
+    }
+}
+
+impl<H: Hal, T: Transport> WriteReady for ConsolePort<'_, H, T> {
+    fn write_ready(&mut self) -> Result<bool> {
+        self.console.ports[self.id as usize].can_send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::console::{Config, QUEUE_RECEIVEQ_PORT_0, QUEUE_SIZE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    #[test]
+    fn partial_read() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(console.read_ready(), Ok(false));
+
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"hello");
+        assert_eq!(console.read_ready(), Ok(true));
+
+        // Only as much as fits in the buffer is read, and the rest is kept for the next read.
+        let mut buf = [0; 2];
+        assert_eq!(Read::read(&mut console, &mut buf), Ok(2));
+        assert_eq!(&buf, b"he");
+        assert_eq!(console.read_ready(), Ok(true));
+
+        let mut buf = [0; 8];
+        assert_eq!(Read::read(&mut console, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"llo");
+        assert_eq!(console.read_ready(), Ok(false));
+    }
+}
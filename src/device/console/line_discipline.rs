@@ -0,0 +1,469 @@
+//! A TTY-style line discipline on top of a VirtIO console.
+
+use super::VirtIOConsole;
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Result;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1b;
+
+/// The default number of lines kept in the history.
+const DEFAULT_HISTORY_LEN: usize = 16;
+
+/// How input is processed by a [`LineDiscipline`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineMode {
+    /// Input is collected into lines which can be edited before they are made available to read.
+    /// Carriage returns and CRLF pairs are translated to newlines, and the erase, kill and
+    /// interrupt characters are handled specially.
+    Canonical,
+    /// Each character is made available to read as soon as it is received, with no special
+    /// handling.
+    Raw,
+}
+
+/// The state of an escape sequence being received.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Escape {
+    None,
+    /// An escape character has been received.
+    Started,
+    /// A control sequence introducer (`ESC [`) has been received.
+    Csi,
+}
+
+/// A TTY-style line discipline wrapping port 0 of a [`VirtIOConsole`].
+///
+/// In [`LineMode::Canonical`] (the default) received characters are echoed back and collected into
+/// a line, which can be edited with the erase (DEL or backspace) and kill (Ctrl-U) characters, and
+/// replaced by previous lines from the history with the up and down arrow keys. The line is only
+/// made available to read once a newline, carriage return or CRLF pair is received. The interrupt
+/// character (Ctrl-C) discards the current line and calls the handler set by
+/// [`set_interrupt_handler`](Self::set_interrupt_handler).
+///
+/// In [`LineMode::Raw`] characters are made available to read immediately, without any processing.
+///
+/// In both modes, newlines written with [`write`](Self::write) are translated to CRLF.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::console::{LineDiscipline, VirtIOConsole};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let console = VirtIOConsole::<HalImpl, _>::new(transport)?;
+/// let mut tty = LineDiscipline::new(console);
+/// tty.set_interrupt_handler(|| println!("Interrupted"));
+///
+/// tty.write(b"$ ")?;
+/// let line = loop {
+///     if let Some(line) = tty.read_line()? {
+///         break line;
+///     }
+/// };
+/// println!("Got command {:?}", line);
+/// # Ok(())
+/// # }
+/// ```
+pub struct LineDiscipline<H: Hal, T: Transport> {
+    console: VirtIOConsole<H, T>,
+    mode: LineMode,
+    echo: bool,
+    erase: u8,
+    kill: u8,
+    interrupt: u8,
+    interrupt_handler: Option<Box<dyn FnMut()>>,
+    /// The line currently being edited in canonical mode.
+    line: Vec<u8>,
+    /// Input which is ready to be read.
+    input: VecDeque<u8>,
+    /// Previous lines, oldest first.
+    history: VecDeque<Vec<u8>>,
+    history_len: usize,
+    /// The position in `history` of the line currently recalled with the arrow keys, if any.
+    history_position: Option<usize>,
+    escape: Escape,
+    /// Whether the last character received in canonical mode was a carriage return, so that a
+    /// following newline should be ignored.
+    after_cr: bool,
+}
+
+impl<H: Hal, T: Transport> LineDiscipline<H, T> {
+    /// Wraps the given console, in canonical mode with echo enabled.
+    pub fn new(console: VirtIOConsole<H, T>) -> Self {
+        Self {
+            console,
+            mode: LineMode::Canonical,
+            echo: true,
+            erase: DELETE,
+            kill: CTRL_U,
+            interrupt: CTRL_C,
+            interrupt_handler: None,
+            line: Vec::new(),
+            input: VecDeque::new(),
+            history: VecDeque::new(),
+            history_len: DEFAULT_HISTORY_LEN,
+            history_position: None,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    /// Returns a mutable reference to the underlying console.
+    pub fn console_mut(&mut self) -> &mut VirtIOConsole<H, T> {
+        &mut self.console
+    }
+
+    /// Unwraps the underlying console. Any input which has not yet been read is lost.
+    pub fn into_inner(self) -> VirtIOConsole<H, T> {
+        self.console
+    }
+
+    /// Returns the current input mode.
+    pub fn mode(&self) -> LineMode {
+        self.mode
+    }
+
+    /// Sets the input mode.
+    ///
+    /// When switching to raw mode, any partially edited line is made available to read as it is.
+    pub fn set_mode(&mut self, mode: LineMode) {
+        if mode == LineMode::Raw {
+            self.input.extend(self.line.drain(..));
+            self.escape = Escape::None;
+            self.after_cr = false;
+        }
+        self.mode = mode;
+    }
+
+    /// Sets whether received characters are echoed back to the console in canonical mode.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Sets the character which erases the previous character of the line in canonical mode.
+    /// Backspace is always treated as an erase character too.
+    pub fn set_erase_char(&mut self, erase: u8) {
+        self.erase = erase;
+    }
+
+    /// Sets the character which erases the whole line in canonical mode.
+    pub fn set_kill_char(&mut self, kill: u8) {
+        self.kill = kill;
+    }
+
+    /// Sets the character which discards the line and calls the interrupt handler in canonical
+    /// mode.
+    pub fn set_interrupt_char(&mut self, interrupt: u8) {
+        self.interrupt = interrupt;
+    }
+
+    /// Sets a function to be called whenever the interrupt character is received in canonical
+    /// mode.
+    pub fn set_interrupt_handler(&mut self, handler: impl FnMut() + 'static) {
+        self.interrupt_handler = Some(Box::new(handler));
+    }
+
+    /// Sets the maximum number of lines to keep in the history, discarding the oldest if there are
+    /// already more than that.
+    pub fn set_history_len(&mut self, history_len: usize) {
+        self.history_len = history_len;
+        while self.history.len() > history_len {
+            self.history.pop_front();
+        }
+        self.history_position = None;
+    }
+
+    /// Returns the lines in the history, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &[u8]> {
+        self.history.iter().map(Vec::as_slice)
+    }
+
+    /// Processes all characters which have been received by the console so far, without waiting
+    /// for more.
+    pub fn poll(&mut self) -> Result<()> {
+        let mut echo = Vec::new();
+        while let Some(c) = self.console.recv(true)? {
+            match self.mode {
+                LineMode::Raw => self.input.push_back(c),
+                LineMode::Canonical => self.process_canonical(c, &mut echo),
+            }
+        }
+        if self.echo && !echo.is_empty() {
+            self.console.send_bytes(&echo)?;
+        }
+        Ok(())
+    }
+
+    /// Reads as much input as is ready and fits in `buf`, without waiting for more.
+    ///
+    /// In canonical mode only complete lines are ready, each ending with a newline. Returns the
+    /// number of bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.poll()?;
+        let len = buf.len().min(self.input.len());
+        for (dest, src) in buf.iter_mut().zip(self.input.drain(..len)) {
+            *dest = src;
+        }
+        Ok(len)
+    }
+
+    /// Returns the next line of input, without the newline, if a complete line is ready.
+    pub fn read_line(&mut self) -> Result<Option<Vec<u8>>> {
+        self.poll()?;
+        let Some(end) = self.input.iter().position(|&c| c == b'\n') else {
+            return Ok(None);
+        };
+        let line = self.input.drain(..=end).take(end).collect();
+        Ok(Some(line))
+    }
+
+    /// Writes the given bytes to the console, translating each newline to CRLF, and waits for the
+    /// device to consume them.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut translated = Vec::with_capacity(data.len());
+        for &c in data {
+            if c == b'\n' {
+                translated.push(b'\r');
+            }
+            translated.push(c);
+        }
+        self.console.send_bytes(&translated)
+    }
+
+    /// Handles a single received character in canonical mode, adding anything which should be
+    /// echoed to `echo`.
+    fn process_canonical(&mut self, c: u8, echo: &mut Vec<u8>) {
+        // The line was already ended by the carriage return.
+        if core::mem::replace(&mut self.after_cr, c == b'\r') && c == b'\n' {
+            return;
+        }
+        match self.escape {
+            Escape::Started => {
+                self.escape = if c == b'[' { Escape::Csi } else { Escape::None };
+                return;
+            }
+            Escape::Csi => {
+                // Parameters and intermediate bytes continue the sequence, anything else ends it.
+                if (0x20..0x40).contains(&c) {
+                    return;
+                }
+                self.escape = Escape::None;
+                match c {
+                    b'A' => self.recall_history(true, echo),
+                    b'B' => self.recall_history(false, echo),
+                    _ => {}
+                }
+                return;
+            }
+            Escape::None => {}
+        }
+
+        match c {
+            ESCAPE => self.escape = Escape::Started,
+            b'\r' | b'\n' => {
+                echo.extend_from_slice(b"\r\n");
+                let line = core::mem::take(&mut self.line);
+                self.input.extend(&line);
+                self.input.push_back(b'\n');
+                self.add_history(line);
+            }
+            _ if c == self.erase || c == BACKSPACE => {
+                if self.line.pop().is_some() {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+            }
+            _ if c == self.kill => self.erase_line(echo),
+            _ if c == self.interrupt => {
+                self.line.clear();
+                self.history_position = None;
+                echo.extend_from_slice(b"^C\r\n");
+                if let Some(handler) = &mut self.interrupt_handler {
+                    handler();
+                }
+            }
+            _ => {
+                self.line.push(c);
+                echo.push(c);
+            }
+        }
+    }
+
+    /// Erases the whole of the current line, on screen as well as in the buffer.
+    fn erase_line(&mut self, echo: &mut Vec<u8>) {
+        for _ in self.line.drain(..) {
+            echo.extend_from_slice(b"\x08 \x08");
+        }
+    }
+
+    /// Adds a completed line to the history, if it is not empty or a repeat of the previous line.
+    fn add_history(&mut self, line: Vec<u8>) {
+        self.history_position = None;
+        if self.history_len == 0 || line.is_empty() || self.history.back() == Some(&line) {
+            return;
+        }
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(line);
+    }
+
+    /// Replaces the current line with the previous (if `older` is true) or next line from the
+    /// history.
+    fn recall_history(&mut self, older: bool, echo: &mut Vec<u8>) {
+        let position = match (self.history_position, older) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (Some(position), true) if position > 0 => Some(position - 1),
+            (Some(position), false) if position + 1 < self.history.len() => Some(position + 1),
+            // Going down past the newest line gives an empty line again.
+            (Some(_), false) => None,
+            _ => return,
+        };
+        self.history_position = position;
+        self.erase_line(echo);
+        if let Some(position) = position {
+            self.line.extend_from_slice(&self.history[position]);
+            echo.extend_from_slice(&self.line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::console::{Config, QUEUE_RECEIVEQ_PORT_0, QUEUE_SIZE, QUEUE_TRANSMITQ_PORT_0},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, WriteOnly},
+    };
+    use alloc::{rc::Rc, sync::Arc, vec};
+    use core::{cell::Cell, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn canonical_editing() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let mut tty = LineDiscipline::new(console);
+        // Echo would need a device thread to consume it.
+        tty.set_echo(false);
+        let interrupted = Rc::new(Cell::new(0));
+        let interrupted_clone = interrupted.clone();
+        tty.set_interrupt_handler(move || interrupted_clone.set(interrupted_clone.get() + 1));
+
+        let receive = |data: &[u8]| {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, data);
+        };
+
+        // Nothing is read until the line is complete, and erased characters are dropped.
+        receive(b"lsx\x7f");
+        assert_eq!(tty.read_line(), Ok(None));
+        receive(b" /\r");
+        assert_eq!(tty.read_line(), Ok(Some(b"ls /".to_vec())));
+        assert_eq!(tty.read_line(), Ok(None));
+
+        // Kill and interrupt both discard the line.
+        receive(b"rm -rf\x15echo\x03");
+        assert_eq!(tty.read_line(), Ok(None));
+        assert_eq!(interrupted.get(), 1);
+
+        // The up arrow recalls the previous line, which can then be edited.
+        receive(b"\x1b[A\x08\x08\x08\x08cd\n");
+        let mut buf = [0; 8];
+        assert_eq!(tty.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"cd\n");
+        assert_eq!(
+            tty.history().collect::<Vec<_>>(),
+            vec![&b"ls /"[..], &b"cd"[..]]
+        );
+
+        // In raw mode everything is passed through.
+        tty.set_mode(LineMode::Raw);
+        receive(b"a\x03\r");
+        assert_eq!(tty.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"a\x03\r");
+        assert_eq!(interrupted.get(), 1);
+    }
+
+    #[test]
+    fn canonical_echo() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let mut tty = LineDiscipline::new(console);
+
+        // Type a line, erase a character, kill the line, then type another line ended by CRLF.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"ab\x7fc\x15ls\r\n");
+
+        // Start a thread to simulate the device receiving the echo.
+        let device_state = state.clone();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&device_state, QUEUE_TRANSMITQ_PORT_0);
+            device_state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0)
+        });
+
+        // The CRLF only ends a single line.
+        assert_eq!(tty.read_line(), Ok(Some(b"ls".to_vec())));
+        assert_eq!(tty.read_line(), Ok(None));
+        assert_eq!(
+            handle.join().unwrap(),
+            b"ab\x08 \x08c\x08 \x08\x08 \x08ls\r\n"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! A `log` implementation which writes to a VirtIO console.
+
+use super::VirtIOConsole;
+use crate::hal::Hal;
+use crate::transport::Transport;
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// A [`Log`] implementation which writes each record as a line to port 0 of a [`VirtIOConsole`].
+///
+/// The console is protected by a spin lock, which is held while each record is written so that
+/// records logged concurrently aren't interleaved. Records must therefore not be logged from a
+/// context which may have interrupted another record being logged on the same CPU, such as an
+/// interrupt handler, or it will deadlock.
+///
+/// The logger can only be shared between threads if the console can be sent between them, which
+/// requires the transport to be [`Send`].
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use log::LevelFilter;
+/// use virtio_drivers::device::console::{ConsoleLogger, VirtIOConsole};
+///
+/// # fn example<HalImpl: Hal + 'static, T: Transport + Send + 'static>(transport: T) -> Result<(), Error> {
+/// let console = VirtIOConsole::<HalImpl, _>::new(transport)?;
+/// let logger = Box::leak(Box::new(ConsoleLogger::new(console, LevelFilter::Info)));
+/// log::set_logger(logger).unwrap();
+/// log::set_max_level(LevelFilter::Info);
+///
+/// log::info!("Logging to the VirtIO console");
+/// # Ok(())
+/// # }
+/// ```
+pub struct ConsoleLogger<H: Hal, T: Transport> {
+    level: LevelFilter,
+    locked: AtomicBool,
+    console: UnsafeCell<VirtIOConsole<H, T>>,
+}
+
+// Safe because the console is only accessed through a `ConsoleGuard`, of which there is only ever
+// one at a time, so sharing the logger only lets the console be used by one thread at a time,
+// which is sound as it is `Send`.
+unsafe impl<H: Hal, T: Transport> Sync for ConsoleLogger<H, T> where VirtIOConsole<H, T>: Send {}
+
+impl<H: Hal, T: Transport> ConsoleLogger<H, T> {
+    /// Creates a new logger which writes records up to the given level to the given console.
+    pub fn new(console: VirtIOConsole<H, T>, level: LevelFilter) -> Self {
+        Self {
+            level,
+            locked: AtomicBool::new(false),
+            console: UnsafeCell::new(console),
+        }
+    }
+
+    /// Unwraps the underlying console driver.
+    pub fn into_inner(self) -> VirtIOConsole<H, T> {
+        self.console.into_inner()
+    }
+
+    /// Waits for exclusive access to the console, which lasts until the returned guard is dropped.
+    fn lock(&self) -> ConsoleGuard<'_, H, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        ConsoleGuard { logger: self }
+    }
+}
+
+/// Exclusive access to the console of a [`ConsoleLogger`], which releases the lock when dropped,
+/// including if a write panics.
+struct ConsoleGuard<'a, H: Hal, T: Transport> {
+    logger: &'a ConsoleLogger<H, T>,
+}
+
+impl<H: Hal, T: Transport> Deref for ConsoleGuard<'_, H, T> {
+    type Target = VirtIOConsole<H, T>;
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because the guard holds the lock, so nothing else can access the console until it is
+        // dropped.
+        unsafe { &*self.logger.console.get() }
+    }
+}
+
+impl<H: Hal, T: Transport> DerefMut for ConsoleGuard<'_, H, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safe because the guard holds the lock, so nothing else can access the console until it is
+        // dropped.
+        unsafe { &mut *self.logger.console.get() }
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for ConsoleGuard<'_, H, T> {
+    fn drop(&mut self) {
+        self.logger.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<H: Hal, T: Transport> Log for ConsoleLogger<H, T>
+where
+    VirtIOConsole<H, T>: Send,
+{
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // There's nowhere to report an error to.
+            let _ = writeln!(self.lock(), "[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.lock().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::console::{Config, QUEUE_SIZE, QUEUE_TRANSMITQ_PORT_0},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use log::Level;
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn log_records() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let logger = ConsoleLogger::new(console, LevelFilter::Info);
+
+        // Start a thread to simulate the device reading the first line written, which may be split
+        // across several requests.
+        let handle = thread::spawn(move || {
+            let mut data = Vec::new();
+            while !data.ends_with(b"\n") {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+                data.extend(
+                    state
+                        .lock()
+                        .unwrap()
+                        .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0),
+                );
+            }
+            data
+        });
+
+        // The debug record is above the level filter, so should be dropped.
+        assert!(!logger.enabled(&Metadata::builder().level(Level::Debug).build()));
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .args(format_args!("hidden"))
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("hello {}", 42))
+                .build(),
+        );
+
+        assert_eq!(handle.join().unwrap(), b"[INFO] hello 42\n");
+    }
+}
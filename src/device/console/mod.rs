@@ -0,0 +1,833 @@
+//! Driver for VirtIO console devices.
+
+mod control;
+#[cfg(feature = "embedded-io")]
+mod io;
+mod line_discipline;
+mod logger;
+mod port;
+
+pub use self::control::ConsoleEvent;
+pub use self::line_discipline::{LineDiscipline, LineMode};
+pub use self::logger::ConsoleLogger;
+pub use self::port::ConsolePort;
+
+use self::control::{Control, ControlEvent, ControlMessage};
+use self::port::{Port, MAX_PORTS};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
+use crate::{Error, Result};
+use alloc::{string::String, vec::Vec};
+use bitflags::bitflags;
+use core::{
+    fmt::{self, Write},
+    ptr::NonNull,
+};
+use log::{info, warn};
+
+const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
+const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
+const QUEUE_SIZE: usize = 2;
+
+/// Driver for a VirtIO console device.
+///
+/// If the device supports `VIRTIO_CONSOLE_F_MULTIPORT` then it may have several ports, which are
+/// added and named by the device through messages on a control queue. Call
+/// [`poll_event`](Self::poll_event) to process these messages, and then use
+/// [`port`](Self::port) or [`port_by_name`](Self::port_by_name) to talk to a particular port.
+/// Otherwise there is only port 0, which is also what [`send`](Self::send) and
+/// [`recv`](Self::recv) use.
+///
+/// Changes to the size of the console are reported by [`poll_event`](Self::poll_event) as
+/// [`ConsoleEvent::Resized`], whether the device signals them through the configuration space or a
+/// control message. If the device supports it, [`emergency_write`](Self::emergency_write) can be
+/// used to output characters without any virtqueues, e.g. from a panic handler.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::console::VirtIOConsole;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
+///
+/// let info = console.info();
+/// println!("VirtIO console {}x{}", info.rows, info.columns);
+///
+/// console.send_bytes(b"Hello console!\n")?;
+///
+/// let c = console.recv(true)?;
+/// println!("Read {:?} from console.", c);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Talking to a named port of a multiport device:
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::console::VirtIOConsole;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
+///
+/// // Wait for the device to add and name the port.
+/// while console.port_by_name("org.qemu.guest_agent.0").is_none() {
+///     console.poll_event()?;
+/// }
+/// let mut port = console.port_by_name("org.qemu.guest_agent.0").unwrap();
+/// port.open()?;
+/// port.send_bytes(b"{\"execute\":\"guest-ping\"}\n")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOConsole<H: Hal, T: Transport> {
+    transport: T,
+    config_space: NonNull<Config>,
+    /// The ports of the device, indexed by port ID. There is only port 0 unless
+    /// `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    ports: Vec<Port<H>>,
+    /// The control queues, if `VIRTIO_CONSOLE_F_MULTIPORT` was negotiated.
+    control: Option<Control<H>>,
+    negotiated_features: Features,
+    /// The rows and columns last read from the configuration space, if `VIRTIO_CONSOLE_F_SIZE` was
+    /// negotiated.
+    size: Option<(u16, u16)>,
+}
+
+/// Information about a console device, read from its configuration space.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConsoleInfo {
+    /// The console height in characters.
+    pub rows: u16,
+    /// The console width in characters.
+    pub columns: u16,
+    /// The maxumum number of ports supported by the console device.
+    pub max_ports: u32,
+}
+
+impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
+    /// Creates a new VirtIO console driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let mut negotiated_features = Features::empty();
+        transport.begin_init(|features| {
+            let features = Features::from_bits_truncate(features);
+            info!("Device features {:?}", features);
+            let supported_features = Features::SIZE | Features::MULTIPORT | Features::EMERG_WRITE;
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
+        });
+        let config_space = transport.config_space::<Config>()?;
+        let multiport = negotiated_features.contains(Features::MULTIPORT);
+        let max_ports = if multiport {
+            // Safe because config_space is a valid pointer to the device configuration space.
+            let max_nr_ports = unsafe { volread!(config_space, max_nr_ports) }.max(1);
+            // Only use as many ports as there are queues for, which the transport reports as having
+            // a maximum size of 0.
+            let available = (0..max_nr_ports.min(MAX_PORTS))
+                .take_while(|&id| {
+                    let (receiveq, transmitq) = Port::<H>::queue_indices(id);
+                    transport.max_queue_size(receiveq) != 0
+                        && transport.max_queue_size(transmitq) != 0
+                })
+                .count() as u32;
+            if available < max_nr_ports {
+                warn!(
+                    "Device supports {} ports but only {} can be used",
+                    max_nr_ports, available
+                );
+            }
+            available.max(1)
+        } else {
+            1
+        };
+
+        // Set up the queues of every port which the device may add, as queues can't be added once
+        // the device is running.
+        let mut ports = Vec::new();
+        for id in 0..max_ports {
+            ports.push(Port::new(&mut transport, id)?);
+        }
+        let control = if multiport {
+            Some(Control::new(&mut transport)?)
+        } else {
+            // Port 0 always exists if the device doesn't support multiport.
+            ports[0].added = true;
+            None
+        };
+
+        transport.finish_init();
+        let mut console = VirtIOConsole {
+            transport,
+            config_space,
+            ports,
+            control,
+            negotiated_features,
+            size: None,
+        };
+        console.size = console.read_size();
+        for port in &mut console.ports {
+            port.poll_retrieve(&mut console.transport)?;
+        }
+        if let Some(control) = &mut console.control {
+            control.fill(&mut console.transport)?;
+            control.send(&mut console.transport, 0, ControlEvent::DEVICE_READY, 1)?;
+        }
+        Ok(console)
+    }
+
+    /// Returns a struct with information about the console device, such as the number of rows and columns.
+    pub fn info(&self) -> ConsoleInfo {
+        // Safe because config_space is a valid pointer to the device configuration space.
+        unsafe {
+            let columns = volread!(self.config_space, cols);
+            let rows = volread!(self.config_space, rows);
+            let max_ports = volread!(self.config_space, max_nr_ports);
+            ConsoleInfo {
+                rows,
+                columns,
+                max_ports,
+            }
+        }
+    }
+
+    /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
+    /// request on port 0 if there is one.
+    ///
+    /// Returns true if new data has been received.
+    pub fn ack_interrupt(&mut self) -> Result<bool> {
+        if !self.transport.ack_interrupt() {
+            return Ok(false);
+        }
+
+        self.ports[0].finish_receive()
+    }
+
+    /// Returns the next available character from port 0 of the console, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        self.ports[0].recv(&mut self.transport, pop)
+    }
+
+    /// Copies as many characters as have been received on port 0 and fit into `buf`, without
+    /// waiting for more.
+    ///
+    /// Returns the number of bytes copied, which is 0 if no data has been received.
+    pub fn recv_bytes(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.ports[0].recv_bytes(&mut self.transport, buf)
+    }
+
+    /// Sends a character to port 0 of the console, and waits for the device to consume it.
+    ///
+    /// Prefer [`send_bytes`](Self::send_bytes) for more than a single character, as each call
+    /// makes a separate request to the device.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.send_bytes(&[chr])
+    }
+
+    /// Sends the given bytes to port 0 of the console, and waits for the device to consume them.
+    ///
+    /// The bytes are copied into transmit buffers of up to a page each, so each page only needs a
+    /// single request to the device.
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.ports[0].send_bytes(&mut self.transport, data)
+    }
+
+    /// Submits as much of `data` as fits in the free transmit buffers of port 0, without waiting
+    /// for the device to consume it.
+    ///
+    /// Returns the number of bytes submitted, which is 0 if all transmit buffers are still in use.
+    /// Buffers are reclaimed by later calls to this or to
+    /// [`finish_transmit`](Self::finish_transmit).
+    pub fn send_bytes_nb(&mut self, data: &[u8]) -> Result<usize> {
+        self.ports[0].send_bytes_nb(&mut self.transport, data)
+    }
+
+    /// Reclaims any transmit buffers of port 0 which the device has finished with, and returns how
+    /// many there were.
+    pub fn finish_transmit(&mut self) -> Result<usize> {
+        self.ports[0].finish_transmit()
+    }
+
+    /// Waits until the device has consumed all data submitted to port 0.
+    pub fn flush(&mut self) -> Result<()> {
+        self.ports[0].flush()
+    }
+
+    /// Returns a handle to the port with the given ID, if the device has added it.
+    pub fn port(&mut self, id: u32) -> Option<ConsolePort<'_, H, T>> {
+        if self.ports.get(id as usize)?.added {
+            Some(ConsolePort { console: self, id })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a handle to the port with the given name, if the device has added and named it.
+    pub fn port_by_name(&mut self, name: &str) -> Option<ConsolePort<'_, H, T>> {
+        let id = self
+            .ports
+            .iter()
+            .position(|port| port.added && port.name.as_deref() == Some(name))?;
+        Some(ConsolePort {
+            console: self,
+            id: id as u32,
+        })
+    }
+
+    /// Writes a character to the console through the emergency write register, without using any
+    /// virtqueue.
+    ///
+    /// This only takes a shared reference and doesn't wait for anything, so it can be used from a
+    /// panic handler even if the queues are in a bad state. Returns [`Error::Unsupported`] if the
+    /// device doesn't support `VIRTIO_CONSOLE_F_EMERG_WRITE`.
+    pub fn emergency_write(&self, chr: u8) -> Result<()> {
+        if !self.negotiated_features.contains(Features::EMERG_WRITE) {
+            return Err(Error::Unsupported);
+        }
+        // Safe because config_space is a valid pointer to the device configuration space.
+        unsafe {
+            volwrite!(self.config_space, emerg_wr, chr.into());
+        }
+        Ok(())
+    }
+
+    /// Reads the console size from the configuration space, if `VIRTIO_CONSOLE_F_SIZE` was
+    /// negotiated.
+    fn read_size(&self) -> Option<(u16, u16)> {
+        if self.negotiated_features.contains(Features::SIZE) {
+            // Safe because config_space is a valid pointer to the device configuration space.
+            unsafe {
+                Some((
+                    volread!(self.config_space, rows),
+                    volread!(self.config_space, cols),
+                ))
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Processes messages which the device has sent on the control queue, until one of them results
+    /// in an event or there are none left.
+    ///
+    /// This should be called after the device raises an interrupt, or regularly, to find out about
+    /// the console being resized, and ports being added, named, opened and so on. Returns
+    /// `Ok(None)` if there are no more events.
+    pub fn poll_event(&mut self) -> Result<Option<ConsoleEvent>> {
+        // A configuration change interrupt doesn't say what changed, so check whether the size is
+        // different to last time.
+        let size = self.read_size();
+        if size != self.size {
+            self.size = size;
+            if let Some((rows, columns)) = size {
+                return Ok(Some(ConsoleEvent::Resized {
+                    id: 0,
+                    rows,
+                    columns,
+                }));
+            }
+        }
+        loop {
+            let Some(control) = &mut self.control else {
+                return Ok(None);
+            };
+            let Some(message) = control.pop(&mut self.transport)? else {
+                return Ok(None);
+            };
+            if let Some(event) = self.handle_control_message(message)? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Updates the state of the relevant port for the given control message, and replies to the
+    /// device if necessary.
+    fn handle_control_message(&mut self, message: ControlMessage) -> Result<Option<ConsoleEvent>> {
+        let id = message.header.id;
+        let event = message.header.event;
+        let Some(port) = self.ports.get_mut(id as usize) else {
+            warn!("Control message {:?} for invalid port", message.header);
+            if event == ControlEvent::DEVICE_ADD {
+                self.send_control(id, ControlEvent::PORT_READY, 0)?;
+            }
+            return Ok(None);
+        };
+        match event {
+            ControlEvent::DEVICE_ADD => {
+                port.added = true;
+                self.send_control(id, ControlEvent::PORT_READY, 1)?;
+                Ok(Some(ConsoleEvent::PortAdded(id)))
+            }
+            ControlEvent::DEVICE_REMOVE => {
+                port.added = false;
+                port.name = None;
+                port.is_console = false;
+                port.host_connected = false;
+                port.guest_connected = false;
+                Ok(Some(ConsoleEvent::PortRemoved(id)))
+            }
+            ControlEvent::CONSOLE_PORT => {
+                port.is_console = true;
+                // Console ports are always open from the guest side.
+                port.guest_connected = true;
+                self.send_control(id, ControlEvent::PORT_OPEN, 1)?;
+                Ok(Some(ConsoleEvent::ConsolePort(id)))
+            }
+            ControlEvent::PORT_NAME => {
+                // The name may be followed by a NUL terminator.
+                let name = message.data.split(|&b| b == 0).next().unwrap_or_default();
+                port.name = Some(String::from_utf8_lossy(name).into_owned());
+                Ok(Some(ConsoleEvent::PortNamed(id)))
+            }
+            ControlEvent::RESIZE => {
+                // This follows the layout used by Linux, with the rows before the columns.
+                let size = message.data.get(..4).ok_or(Error::IoError)?;
+                Ok(Some(ConsoleEvent::Resized {
+                    id,
+                    rows: u16::from_le_bytes([size[0], size[1]]),
+                    columns: u16::from_le_bytes([size[2], size[3]]),
+                }))
+            }
+            ControlEvent::PORT_OPEN => {
+                port.host_connected = message.header.value != 0;
+                Ok(Some(if port.host_connected {
+                    ConsoleEvent::PortOpened(id)
+                } else {
+                    ConsoleEvent::PortClosed(id)
+                }))
+            }
+            _ => {
+                warn!("Ignoring unexpected control message {:?}", message.header);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sends a message to the device on the control queue.
+    fn send_control(&mut self, id: u32, event: ControlEvent, value: u16) -> Result<()> {
+        self.control
+            .as_mut()
+            .ok_or(Error::Unsupported)?
+            .send(&mut self.transport, id, event, value)
+    }
+}
+
+// Safe because `config_space` points to the device's MMIO region, which isn't tied to any
+// particular thread and is only accessed through the `VirtIOConsole`.
+unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOConsole<H, T> {}
+
+impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for port in &self.ports {
+            port.unset_queues(&mut self.transport);
+        }
+        if let Some(control) = &self.control {
+            control.unset_queues(&mut self.transport);
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Write for VirtIOConsole<H, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.send_bytes(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[repr(C)]
+struct Config {
+    cols: ReadOnly<u16>,
+    rows: ReadOnly<u16>,
+    max_nr_ports: ReadOnly<u32>,
+    emerg_wr: WriteOnly<u32>,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Features: u64 {
+        const SIZE                  = 1 << 0;
+        const MULTIPORT             = 1 << 1;
+        const EMERG_WRITE           = 1 << 2;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        PAGE_SIZE,
+    };
+    use alloc::{sync::Arc, vec};
+    use control::ControlHeader;
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+    use zerocopy::AsBytes;
+
+    fn control_message(id: u32, event: ControlEvent, value: u16, data: &[u8]) -> Vec<u8> {
+        let mut message = ControlHeader { id, event, value }.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn receive() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Nothing is available to receive.
+        assert_eq!(console.recv(false).unwrap(), None);
+        assert_eq!(console.recv(true).unwrap(), None);
+
+        // Still nothing after a spurious interrupt.
+        assert_eq!(console.ack_interrupt(), Ok(false));
+        assert_eq!(console.recv(false).unwrap(), None);
+
+        // Make a character available, and simulate an interrupt.
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
+
+            state.interrupt_pending = true;
+        }
+        assert_eq!(console.ack_interrupt(), Ok(true));
+        assert_eq!(state.lock().unwrap().interrupt_pending, false);
+
+        // Receive the character. If we don't pop it it is still there to read again.
+        assert_eq!(console.recv(false).unwrap(), Some(42));
+        assert_eq!(console.recv(true).unwrap(), Some(42));
+        assert_eq!(console.recv(true).unwrap(), None);
+    }
+
+    #[test]
+    fn send() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for characters.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a character.");
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMITQ_PORT_0);
+            println!("Transmit queue was notified.");
+
+            let data = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+            assert_eq!(data, b"Q");
+        });
+
+        assert_eq!(console.send(b'Q'), Ok(()));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn multiport() {
+        const QUEUE_CONTROL_RECEIVEQ: u16 = 2;
+        const QUEUE_CONTROL_TRANSMITQ: u16 = 3;
+        const QUEUE_TRANSMITQ_PORT_1: u16 = 5;
+
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(2),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: (0..6).map(|_| QueueStatus::default()).collect(),
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: Features::MULTIPORT.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Start a thread to simulate the device reading the control messages and data sent by the
+        // driver.
+        let device_state = state.clone();
+        let handle = thread::spawn(move || {
+            for expected in [
+                control_message(0, ControlEvent::DEVICE_READY, 1, &[]),
+                control_message(1, ControlEvent::PORT_READY, 1, &[]),
+                control_message(1, ControlEvent::PORT_OPEN, 1, &[]),
+            ] {
+                State::wait_until_queue_notified(&device_state, QUEUE_CONTROL_TRANSMITQ);
+                let data = device_state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(QUEUE_CONTROL_TRANSMITQ);
+                assert_eq!(data, expected);
+            }
+            State::wait_until_queue_notified(&device_state, QUEUE_TRANSMITQ_PORT_1);
+            let data = device_state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_1);
+            assert_eq!(data, b"Q");
+        });
+
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(console.poll_event(), Ok(None));
+        assert!(console.port(1).is_none());
+
+        // The device adds and names port 1.
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            QUEUE_CONTROL_RECEIVEQ,
+            &control_message(1, ControlEvent::DEVICE_ADD, 0, &[]),
+        );
+        assert_eq!(console.poll_event(), Ok(Some(ConsoleEvent::PortAdded(1))));
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            QUEUE_CONTROL_RECEIVEQ,
+            &control_message(1, ControlEvent::PORT_NAME, 0, b"org.test.0\0"),
+        );
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            QUEUE_CONTROL_RECEIVEQ,
+            &control_message(1, ControlEvent::PORT_OPEN, 1, &[]),
+        );
+        assert_eq!(console.poll_event(), Ok(Some(ConsoleEvent::PortNamed(1))));
+        assert_eq!(console.poll_event(), Ok(Some(ConsoleEvent::PortOpened(1))));
+        assert_eq!(console.poll_event(), Ok(None));
+
+        // The console on port 1 is resized.
+        state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            QUEUE_CONTROL_RECEIVEQ,
+            &control_message(1, ControlEvent::RESIZE, 0, &[24, 0, 80, 0]),
+        );
+        assert_eq!(
+            console.poll_event(),
+            Ok(Some(ConsoleEvent::Resized {
+                id: 1,
+                rows: 24,
+                columns: 80
+            }))
+        );
+
+        // Open the port and send a character on it.
+        assert!(console.port_by_name("org.test.1").is_none());
+        let mut port = console.port_by_name("org.test.0").unwrap();
+        assert_eq!(port.id(), 1);
+        assert!(port.is_host_connected());
+        assert!(!port.is_open());
+        port.open().unwrap();
+        assert!(port.is_open());
+        port.send(b'Q').unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn send_bytes_nb() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // More data than fits in both transmit buffers is only partly accepted, in one request per
+        // buffer.
+        let data: Vec<u8> = (0..PAGE_SIZE * 3).map(|i| i as u8).collect();
+        assert_eq!(console.send_bytes_nb(&data), Ok(PAGE_SIZE * 2));
+        assert_eq!(console.send_bytes_nb(&data[PAGE_SIZE * 2..]), Ok(0));
+        assert_eq!(console.finish_transmit(), Ok(0));
+
+        // Once the device has consumed them the buffers can be reused.
+        for i in 0..2 {
+            let sent = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+            assert_eq!(sent, &data[PAGE_SIZE * i..PAGE_SIZE * (i + 1)]);
+        }
+        assert_eq!(console.finish_transmit(), Ok(2));
+        assert_eq!(console.send_bytes_nb(b"abc"), Ok(3));
+        assert_eq!(
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0),
+            b"abc"
+        );
+        assert_eq!(console.flush(), Ok(()));
+    }
+
+    #[test]
+    fn resize_and_emergency_write() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(80),
+            rows: ReadOnly::new(25),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: (Features::SIZE | Features::EMERG_WRITE).bits(),
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(console.poll_event(), Ok(None));
+
+        // Simulate the device changing the size of the console.
+        // Safe because nothing else is accessing the config space at the same time.
+        unsafe {
+            (*config_space_ptr.as_ptr()).rows = ReadOnly::new(50);
+        }
+        assert_eq!(
+            console.poll_event(),
+            Ok(Some(ConsoleEvent::Resized {
+                id: 0,
+                rows: 50,
+                columns: 80
+            }))
+        );
+        assert_eq!(console.poll_event(), Ok(None));
+
+        assert_eq!(console.emergency_write(b'!'), Ok(()));
+        // Safe because nothing else is accessing the config space at the same time.
+        let emerg_wr = unsafe {
+            core::ptr::addr_of!((*config_space_ptr.as_ptr()).emerg_wr)
+                .cast::<u32>()
+                .read_volatile()
+        };
+        assert_eq!(emerg_wr, u32::from(b'!'));
+    }
+
+    #[test]
+    fn recv_bytes() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let mut buf = [0; 2];
+        assert_eq!(console.recv_bytes(&mut buf), Ok(0));
+
+        // Only as much as has been received is returned, without waiting for the buffer to fill.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"abc");
+        assert_eq!(console.recv_bytes(&mut buf), Ok(2));
+        assert_eq!(&buf, b"ab");
+        assert_eq!(console.recv_bytes(&mut buf), Ok(1));
+        assert_eq!(buf[0], b'c');
+        assert_eq!(console.recv_bytes(&mut buf), Ok(0));
+
+        // A new receive request was made once the data was used up.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"d");
+        assert_eq!(console.recv(true), Ok(Some(b'd')));
+    }
+}
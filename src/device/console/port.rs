@@ -0,0 +1,378 @@
+//! Per-port state and handles for VirtIO console devices.
+
+use super::{ControlEvent, VirtIOConsole, QUEUE_SIZE};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::{boxed::Box, string::String};
+use core::{
+    cmp::min,
+    fmt::{self, Write},
+    hint::spin_loop,
+};
+
+/// The size of each transmit buffer.
+const TX_BUFFER_LEN: usize = PAGE_SIZE;
+/// The number of ports whose queue indices fit in a `u16`.
+pub(crate) const MAX_PORTS: u32 = (u16::MAX as u32 - 1) / 2;
+
+/// The queues and receive buffer of a single port, along with what the device has told us about
+/// it.
+pub(crate) struct Port<H: Hal> {
+    receiveq_index: u16,
+    transmitq_index: u16,
+    receiveq: VirtQueue<H, QUEUE_SIZE>,
+    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    queue_buf_rx: Box<[u8; PAGE_SIZE]>,
+    cursor: usize,
+    pending_len: usize,
+    /// The token of the outstanding receive request, if there is one.
+    receive_token: Option<u16>,
+    /// `QUEUE_SIZE` transmit buffers of `TX_BUFFER_LEN` bytes each, which stay shared with the
+    /// device.
+    tx_dma: Dma<H>,
+    /// For each transmit buffer, the token of the request using it, if it has been submitted and
+    /// not yet reclaimed.
+    tx_tokens: [Option<u16>; QUEUE_SIZE],
+    /// Whether the device has added the port. This is always true for port 0 if multiport is not
+    /// negotiated.
+    pub(crate) added: bool,
+    /// The name of the port, if the device has given it one.
+    pub(crate) name: Option<String>,
+    /// Whether the device has marked this port as a console.
+    pub(crate) is_console: bool,
+    /// Whether the host side of the port is open.
+    pub(crate) host_connected: bool,
+    /// Whether we have told the device that the guest side of the port is open.
+    pub(crate) guest_connected: bool,
+}
+
+impl<H: Hal> Port<H> {
+    /// Returns the indices of the receive and transmit queues of the port with the given ID, which
+    /// must be less than [`MAX_PORTS`].
+    pub(crate) fn queue_indices(id: u32) -> (u16, u16) {
+        assert!(id < MAX_PORTS);
+        // Port 0 uses the first pair of queues, and the control queues come before all other ports.
+        let receiveq_index = if id == 0 { 0 } else { id as u16 * 2 + 2 };
+        (receiveq_index, receiveq_index + 1)
+    }
+
+    /// Sets up the queues for the port with the given ID, which must be less than [`MAX_PORTS`].
+    pub(crate) fn new<T: Transport>(transport: &mut T, id: u32) -> Result<Self> {
+        let (receiveq_index, transmitq_index) = Self::queue_indices(id);
+        Ok(Self {
+            receiveq_index,
+            transmitq_index,
+            receiveq: VirtQueue::new(transport, receiveq_index)?,
+            transmitq: VirtQueue::new(transport, transmitq_index)?,
+            queue_buf_rx: Box::new([0; PAGE_SIZE]),
+            cursor: 0,
+            pending_len: 0,
+            receive_token: None,
+            tx_dma: Dma::new(
+                QUEUE_SIZE * TX_BUFFER_LEN / PAGE_SIZE,
+                BufferDirection::DriverToDevice,
+            )?,
+            tx_tokens: [None; QUEUE_SIZE],
+            added: false,
+            name: None,
+            is_console: false,
+            host_connected: false,
+            guest_connected: false,
+        })
+    }
+
+    /// Makes a request to the device to receive data, if there is not already an outstanding
+    /// receive request or some data already received and not yet returned.
+    pub(crate) fn poll_retrieve(&mut self, transport: &mut impl Transport) -> Result<()> {
+        if self.receive_token.is_none() && self.cursor == self.pending_len {
+            // Safe because the buffer lasts at least as long as the queue, and there are no other
+            // outstanding requests using the buffer.
+            self.receive_token = Some(unsafe {
+                self.receiveq
+                    .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
+            }?);
+            if self.receiveq.should_notify() {
+                transport.notify(self.receiveq_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// If there is an outstanding receive request and it has finished, completes it.
+    ///
+    /// Returns true if new data has been received.
+    pub(crate) fn finish_receive(&mut self) -> Result<bool> {
+        let mut flag = false;
+        if let Some(receive_token) = self.receive_token {
+            if self.receive_token == self.receiveq.peek_used() {
+                // Safe because we are passing the same buffer as we passed to `VirtQueue::add` in
+                // `poll_retrieve` and it is still valid.
+                let len = unsafe {
+                    self.receiveq.pop_used(
+                        receive_token,
+                        &[],
+                        &mut [self.queue_buf_rx.as_mut_slice()],
+                    )?
+                };
+                flag = true;
+                assert_ne!(len, 0);
+                self.cursor = 0;
+                self.pending_len = len as usize;
+                // Clear `receive_token` so that when the buffer is used up the next call to
+                // `poll_retrieve` will add a new pending request.
+                self.receive_token.take();
+            }
+        }
+        Ok(flag)
+    }
+
+    /// Returns the next available character received on the port, if any.
+    pub(crate) fn recv(&mut self, transport: &mut impl Transport, pop: bool) -> Result<Option<u8>> {
+        self.finish_receive()?;
+        if self.cursor == self.pending_len {
+            return Ok(None);
+        }
+        let ch = self.queue_buf_rx[self.cursor];
+        if pop {
+            self.cursor += 1;
+            self.poll_retrieve(transport)?;
+        }
+        Ok(Some(ch))
+    }
+
+    /// Copies as many received characters as are available and fit into `buf`, without waiting for
+    /// more.
+    ///
+    /// Returns the number of bytes copied.
+    pub(crate) fn recv_bytes(
+        &mut self,
+        transport: &mut impl Transport,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.finish_receive()?;
+        let len = min(buf.len(), self.pending_len - self.cursor);
+        buf[..len].copy_from_slice(&self.queue_buf_rx[self.cursor..self.cursor + len]);
+        self.cursor += len;
+        self.poll_retrieve(transport)?;
+        Ok(len)
+    }
+
+    /// Returns whether there is received data available to read.
+    pub(crate) fn can_recv(&mut self) -> Result<bool> {
+        self.finish_receive()?;
+        Ok(self.cursor < self.pending_len)
+    }
+
+    /// Returns whether there is a free transmit buffer, so that sending won't block.
+    pub(crate) fn can_send(&mut self) -> Result<bool> {
+        self.finish_transmit()?;
+        Ok(self.tx_tokens.contains(&None))
+    }
+
+    /// Copies as much of `data` as fits into free transmit buffers and submits them to the device,
+    /// without waiting for it to consume them.
+    ///
+    /// Returns the number of bytes submitted, which is 0 if all buffers are in use.
+    pub(crate) fn send_bytes_nb(
+        &mut self,
+        transport: &mut impl Transport,
+        data: &[u8],
+    ) -> Result<usize> {
+        self.finish_transmit()?;
+        let mut sent = 0;
+        while sent < data.len() {
+            let Some(slot) = self.tx_tokens.iter().position(Option::is_none) else {
+                break;
+            };
+            let chunk = &data[sent..sent + min(data.len() - sent, TX_BUFFER_LEN)];
+            // Safe because the buffer is not in use by the device, and the chunk is no bigger than
+            // the buffer.
+            unsafe {
+                self.tx_dma
+                    .vaddr(slot * TX_BUFFER_LEN)
+                    .as_ptr()
+                    .copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+            }
+            // Safe because the buffer stays shared with the device for as long as the queue exists,
+            // and isn't written again until the device has used it.
+            let token = unsafe {
+                self.transmitq.add_shared(
+                    &[(self.tx_dma.paddr() + slot * TX_BUFFER_LEN, chunk.len())],
+                    &[],
+                )
+            }?;
+            self.tx_tokens[slot] = Some(token);
+            sent += chunk.len();
+        }
+        if sent > 0 && self.transmitq.should_notify() {
+            transport.notify(self.transmitq_index);
+        }
+        Ok(sent)
+    }
+
+    /// Reclaims any transmit buffers which the device has finished with.
+    ///
+    /// Returns the number of buffers reclaimed.
+    pub(crate) fn finish_transmit(&mut self) -> Result<usize> {
+        let mut reclaimed = 0;
+        while let Some(token) = self.transmitq.peek_used() {
+            let slot = self
+                .tx_tokens
+                .iter()
+                .position(|&slot_token| slot_token == Some(token))
+                .ok_or(Error::WrongToken)?;
+            self.transmitq.pop_used_shared(token)?;
+            self.tx_tokens[slot] = None;
+            reclaimed += 1;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Waits until the device has finished with all submitted transmit buffers.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        while self.tx_tokens.iter().any(Option::is_some) {
+            if self.finish_transmit()? == 0 {
+                spin_loop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends all of `data` on the port, and waits for the device to consume it.
+    pub(crate) fn send_bytes(&mut self, transport: &mut impl Transport, data: &[u8]) -> Result<()> {
+        let mut sent = 0;
+        while sent < data.len() {
+            let newly_sent = self.send_bytes_nb(transport, &data[sent..])?;
+            if newly_sent == 0 {
+                spin_loop();
+            }
+            sent += newly_sent;
+        }
+        self.flush()
+    }
+
+    /// Clears the port's queues from the transport, so the device doesn't try to access them after
+    /// they have been freed.
+    pub(crate) fn unset_queues(&self, transport: &mut impl Transport) {
+        transport.queue_unset(self.receiveq_index);
+        transport.queue_unset(self.transmitq_index);
+    }
+}
+
+/// A handle to a single port of a multiport [`VirtIOConsole`].
+///
+/// This is obtained from [`VirtIOConsole::port`] or [`VirtIOConsole::port_by_name`], and borrows
+/// the console for as long as it is used.
+pub struct ConsolePort<'a, H: Hal, T: Transport> {
+    pub(crate) console: &'a mut VirtIOConsole<H, T>,
+    pub(crate) id: u32,
+}
+
+impl<H: Hal, T: Transport> ConsolePort<'_, H, T> {
+    fn port(&self) -> &Port<H> {
+        &self.console.ports[self.id as usize]
+    }
+
+    /// Returns the ID of the port.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the name which the device has given the port, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.port().name.as_deref()
+    }
+
+    /// Returns whether the device has marked the port as a console.
+    pub fn is_console(&self) -> bool {
+        self.port().is_console
+    }
+
+    /// Returns whether the host side of the port is currently open.
+    pub fn is_host_connected(&self) -> bool {
+        self.port().host_connected
+    }
+
+    /// Returns whether the guest side of the port is currently open.
+    pub fn is_open(&self) -> bool {
+        self.port().guest_connected
+    }
+
+    /// Tells the device that the guest side of the port is open.
+    pub fn open(&mut self) -> Result<()> {
+        self.set_guest_connected(true)
+    }
+
+    /// Tells the device that the guest side of the port is closed.
+    pub fn close(&mut self) -> Result<()> {
+        self.set_guest_connected(false)
+    }
+
+    fn set_guest_connected(&mut self, connected: bool) -> Result<()> {
+        self.console
+            .send_control(self.id, ControlEvent::PORT_OPEN, connected.into())?;
+        self.console.ports[self.id as usize].guest_connected = connected;
+        Ok(())
+    }
+
+    /// Returns the next available character received on the port, if any.
+    ///
+    /// If no data has been received this will not block but immediately return `Ok<None>`.
+    pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].recv(&mut console.transport, pop)
+    }
+
+    /// Copies as many characters as have been received on the port and fit into `buf`, without
+    /// waiting for more.
+    ///
+    /// Returns the number of bytes copied, which is 0 if no data has been received.
+    pub fn recv_bytes(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].recv_bytes(&mut console.transport, buf)
+    }
+
+    /// Sends a character on the port, and waits for the device to consume it.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        self.send_bytes(&[chr])
+    }
+
+    /// Sends the given bytes on the port, and waits for the device to consume them.
+    ///
+    /// The bytes are copied into transmit buffers of up to a page each, so each page only needs a
+    /// single request to the device.
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].send_bytes(&mut console.transport, data)
+    }
+
+    /// Submits as much of `data` as fits in the free transmit buffers of the port, without waiting
+    /// for the device to consume it.
+    ///
+    /// Returns the number of bytes submitted, which is 0 if all transmit buffers are still in use.
+    /// Buffers are reclaimed by later calls to this or to
+    /// [`finish_transmit`](Self::finish_transmit).
+    pub fn send_bytes_nb(&mut self, data: &[u8]) -> Result<usize> {
+        let console = &mut *self.console;
+        console.ports[self.id as usize].send_bytes_nb(&mut console.transport, data)
+    }
+
+    /// Reclaims any transmit buffers of the port which the device has finished with, and returns
+    /// how many there were.
+    pub fn finish_transmit(&mut self) -> Result<usize> {
+        self.console.ports[self.id as usize].finish_transmit()
+    }
+
+    /// Waits until the device has consumed all data submitted on the port.
+    pub fn flush(&mut self) -> Result<()> {
+        self.console.ports[self.id as usize].flush()
+    }
+}
+
+impl<H: Hal, T: Transport> Write for ConsolePort<'_, H, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.send_bytes(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
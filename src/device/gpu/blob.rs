@@ -0,0 +1,489 @@
+//! Blob resources for VirtIO GPU devices, whose memory is shared between the guest and the host
+//! rather than copied between them.
+
+use super::resource::{Backing, MemEntry, Resource};
+use super::{Command, ContextId, CtrlHeader, Features, Format, Rect, ResourceId, VirtIOGpu};
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::transport::Transport;
+use crate::{nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::ptr::NonNull;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The blob memory is in the guest, attached at creation.
+const BLOB_MEM_GUEST: u32 = 1;
+/// The blob memory is allocated by the host, as created by a 3D context.
+const BLOB_MEM_HOST3D: u32 = 2;
+
+/// The bits of the `map_info` field of `RESP_OK_MAP_INFO` which hold the caching type.
+const MAP_CACHE_MASK: u32 = 0x0f;
+
+bitflags! {
+    /// How a blob resource may be used.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct BlobFlags: u32 {
+        /// The resource may be mapped into the guest with [`VirtIOGpu::map_blob`].
+        const MAPPABLE = 1 << 0;
+        /// The resource may be shared with other contexts or processes.
+        const SHAREABLE = 1 << 1;
+        /// The resource may be shared with other virtio devices.
+        const CROSS_DEVICE = 1 << 2;
+    }
+}
+
+/// How the host caches the memory of a mapped blob resource, which the guest should match when
+/// accessing it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapCaching {
+    /// The memory is cached.
+    Cached,
+    /// The memory is uncached.
+    Uncached,
+    /// The memory is uncached, but writes may be combined.
+    WriteCombined,
+    /// The device reported some other caching type.
+    Unknown(u32),
+}
+
+impl From<u32> for MapCaching {
+    fn from(map_info: u32) -> Self {
+        match map_info & MAP_CACHE_MASK {
+            1 => Self::Cached,
+            2 => Self::Uncached,
+            3 => Self::WriteCombined,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// What the driver knows about a blob resource.
+pub(crate) struct Blob {
+    size: u64,
+    flags: BlobFlags,
+    /// The offset within the host-visible shared memory region at which the resource is mapped,
+    /// if it is.
+    pub(crate) map_offset: Option<u64>,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Creates a 2D blob resource of the given format and size, backed by a buffer allocated by
+    /// the driver which is shared with the host.
+    ///
+    /// Unlike with [`VirtIOGpu::create_resource_2d_with_backing`], the host sees changes to the
+    /// buffer without them being transferred, so the resource only needs to be flushed after
+    /// drawing to it. The buffer can be accessed with [`VirtIOGpu::resource_buffer`].
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support
+    /// `VIRTIO_GPU_F_RESOURCE_BLOB`.
+    pub fn create_blob_resource_2d(
+        &mut self,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<ResourceId> {
+        self.check_blob()?;
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let len = format.bytes_per_pixel() as usize * width as usize * height as usize;
+        if len > u32::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        let dma = Dma::new(pages(len), BufferDirection::Both)?;
+        let entry = MemEntry::new(dma.paddr(), len as u32);
+        let flags = BlobFlags::SHAREABLE;
+        let id = self.alloc_resource_id();
+        let rsp: CtrlHeader = self.request_with_data(
+            ResourceCreateBlob {
+                header: CtrlHeader::with_type(Command::RESOURCE_CREATE_BLOB),
+                resource_id: id.0,
+                blob_mem: BLOB_MEM_GUEST,
+                blob_flags: flags.bits(),
+                nr_entries: 1,
+                blob_id: 0,
+                size: len as u64,
+            },
+            entry.as_bytes(),
+        )?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources.insert(
+            id,
+            Resource {
+                format: Some(format),
+                width,
+                height,
+                backing: Backing::Owned { dma, len },
+                blob: Some(Blob {
+                    size: len as u64,
+                    flags,
+                    map_offset: None,
+                }),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Creates a blob resource from host memory which was allocated by commands submitted in the
+    /// given context, and identified there by `blob_id`.
+    ///
+    /// If `flags` includes [`BlobFlags::MAPPABLE`], the memory can then be mapped into the guest
+    /// with [`VirtIOGpu::map_blob`].
+    pub fn create_blob_resource_3d(
+        &mut self,
+        ctx: ContextId,
+        blob_id: u64,
+        size: u64,
+        flags: BlobFlags,
+    ) -> Result<ResourceId> {
+        self.check_blob()?;
+        self.check_context(ctx)?;
+        if size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let id = self.alloc_resource_id();
+        let rsp: CtrlHeader = self.request(ResourceCreateBlob {
+            header: CtrlHeader::with_context(Command::RESOURCE_CREATE_BLOB, ctx.0),
+            resource_id: id.0,
+            blob_mem: BLOB_MEM_HOST3D,
+            blob_flags: flags.bits(),
+            nr_entries: 0,
+            blob_id,
+            size,
+        })?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources.insert(
+            id,
+            Resource {
+                format: None,
+                width: 0,
+                height: 0,
+                backing: Backing::Detached,
+                blob: Some(Blob {
+                    size,
+                    flags,
+                    map_offset: None,
+                }),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Displays the given rectangle of a 2D blob resource on the given scanout.
+    ///
+    /// The resource must have been created with [`VirtIOGpu::create_blob_resource_2d`]. Use
+    /// [`VirtIOGpu::set_scanout_resource`] to disable the scanout again.
+    pub fn set_scanout_blob(&mut self, scanout_id: u32, id: ResourceId, rect: Rect) -> Result {
+        if scanout_id as usize >= self.framebuffers.len() {
+            return Err(Error::InvalidParam);
+        }
+        let resource = self.resources.get(&id).ok_or(Error::InvalidParam)?;
+        let (Some(format), Some(stride)) = (resource.format, resource.stride()) else {
+            return Err(Error::InvalidParam);
+        };
+        if !resource.is_blob() || !resource.contains(&rect) {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: CtrlHeader = self.request(SetScanoutBlob {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT_BLOB),
+            rect,
+            scanout_id,
+            resource_id: id.0,
+            width: resource.width,
+            height: resource.height,
+            format,
+            _padding: 0,
+            strides: [stride, 0, 0, 0],
+            offsets: [0; 4],
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Maps the host memory of the given blob resource into the device's host-visible shared
+    /// memory region, and returns a pointer to it along with how the host caches it.
+    ///
+    /// The pointer remains valid until the resource is unmapped with [`VirtIOGpu::unmap_blob`] or
+    /// unreferenced. Returns [`Error::Unsupported`] if the device doesn't have a host-visible
+    /// shared memory region.
+    pub fn map_blob(&mut self, id: ResourceId) -> Result<(NonNull<[u8]>, MapCaching)> {
+        self.check_blob()?;
+        let region = self.host_visible_region.ok_or(Error::Unsupported)?;
+        let blob = self
+            .resources
+            .get(&id)
+            .and_then(|resource| resource.blob.as_ref())
+            .ok_or(Error::InvalidParam)?;
+        if !blob.flags.contains(BlobFlags::MAPPABLE) || blob.map_offset.is_some() {
+            return Err(Error::InvalidParam);
+        }
+        let size = blob.size;
+        let offset = self.alloc_host_visible(size)?;
+        let rsp: RespMapInfo = self.request(ResourceMapBlob {
+            header: CtrlHeader::with_type(Command::RESOURCE_MAP_BLOB),
+            resource_id: id.0,
+            _padding: 0,
+            offset,
+        })?;
+        rsp.header.check_type(Command::OK_MAP_INFO)?;
+        self.resources
+            .get_mut(&id)
+            .unwrap()
+            .blob
+            .as_mut()
+            .unwrap()
+            .map_offset = Some(offset);
+
+        // Safe because the device has just mapped the resource at this offset within the shared
+        // memory region, which the transport reported as valid.
+        let vaddr = unsafe { H::mmio_phys_to_virt(region.paddr + offset as usize, size as usize) };
+        Ok((
+            nonnull_slice_from_raw_parts(vaddr, size as usize),
+            MapCaching::from(rsp.map_info),
+        ))
+    }
+
+    /// Unmaps a blob resource which was mapped with [`VirtIOGpu::map_blob`].
+    ///
+    /// The pointer returned when it was mapped must no longer be used.
+    pub fn unmap_blob(&mut self, id: ResourceId) -> Result {
+        let blob = self
+            .resources
+            .get(&id)
+            .and_then(|resource| resource.blob.as_ref())
+            .ok_or(Error::InvalidParam)?;
+        if blob.map_offset.is_none() {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: CtrlHeader = self.request(ResourceUnmapBlob {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNMAP_BLOB),
+            resource_id: id.0,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources
+            .get_mut(&id)
+            .unwrap()
+            .blob
+            .as_mut()
+            .unwrap()
+            .map_offset = None;
+        Ok(())
+    }
+
+    /// Returns [`Error::Unsupported`] if blob resources were not negotiated.
+    fn check_blob(&self) -> Result {
+        if self.negotiated_features.contains(Features::RESOURCE_BLOB) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Finds the lowest page-aligned offset within the host-visible shared memory region at which
+    /// the given number of bytes don't overlap any mapped resource.
+    ///
+    /// Returns [`Error::InvalidParam`] if there is no such offset.
+    fn alloc_host_visible(&self, size: u64) -> Result<u64> {
+        let region = self.host_visible_region.ok_or(Error::Unsupported)?;
+        let mut mapped = self
+            .resources
+            .values()
+            .filter_map(|resource| {
+                let blob = resource.blob.as_ref()?;
+                Some((blob.map_offset?, blob.size))
+            })
+            .map(|(start, size)| Ok((start, page_align(size)?)))
+            .collect::<Result<Vec<(u64, u64)>>>()?;
+        mapped.sort_unstable();
+
+        let size = page_align(size)?;
+        let mut offset: u64 = 0;
+        for (start, len) in mapped {
+            if offset.checked_add(size).ok_or(Error::InvalidParam)? <= start {
+                break;
+            }
+            offset = offset.max(start.checked_add(len).ok_or(Error::InvalidParam)?);
+        }
+        if offset.checked_add(size).ok_or(Error::InvalidParam)? > region.len {
+            return Err(Error::InvalidParam);
+        }
+        Ok(offset)
+    }
+}
+
+/// Rounds the given size up to a whole number of pages, or returns [`Error::InvalidParam`] if
+/// that overflows.
+fn page_align(size: u64) -> Result<u64> {
+    let page_size = PAGE_SIZE as u64;
+    let size = size.checked_add(page_size - 1).ok_or(Error::InvalidParam)?;
+    Ok(size & !(page_size - 1))
+}
+
+/// The header of a `RESOURCE_CREATE_BLOB` command, which is followed by `nr_entries`
+/// [`MemEntry`]s.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes)]
+struct ResourceCreateBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32,
+    blob_id: u64,
+    size: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct SetScanoutBlob {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: Format,
+    _padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceMapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+struct RespMapInfo {
+    header: CtrlHeader,
+    map_info: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceUnmapBlob {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{
+            Config, FramebufferInfo, RespDisplayInfo, CONTROL_QUEUE_SIZE, QUEUE_TRANSMIT,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::mem::size_of;
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn page_align_overflow() {
+        assert_eq!(page_align(0), Ok(0));
+        assert_eq!(page_align(1), Ok(PAGE_SIZE as u64));
+        assert_eq!(page_align(u64::MAX), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn blob_framebuffer() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::RESOURCE_BLOB.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate the device, checking that the framebuffer is shared rather than transferred.
+        let handle = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..4 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        commands.push(header.hdr_type);
+                        match header.hdr_type {
+                            Command::GET_DISPLAY_INFO => {
+                                let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                                CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                    .write_to_prefix(&mut response[..])
+                                    .unwrap();
+                                let pmode = &mut response[size_of::<CtrlHeader>()..];
+                                Rect::new(0, 0, 4, 2).write_to_prefix(&mut *pmode).unwrap();
+                                // Enabled.
+                                pmode[size_of::<Rect>()] = 1;
+                                return response;
+                            }
+                            Command::RESOURCE_CREATE_BLOB => {
+                                let create =
+                                    ResourceCreateBlob::read_from_prefix(request.as_slice())
+                                        .unwrap();
+                                assert_eq!(create.blob_mem, BLOB_MEM_GUEST);
+                                assert_eq!(create.nr_entries, 1);
+                                assert_eq!(create.size, 32);
+                            }
+                            _ => {}
+                        }
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            commands
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let (framebuffer, info) = gpu.setup_framebuffer(Format::B8G8R8X8UNORM).unwrap();
+        assert_eq!(framebuffer.len(), 32);
+        assert_eq!(
+            info,
+            FramebufferInfo {
+                format: Format::B8G8R8X8UNORM,
+                width: 4,
+                height: 2,
+                stride: 16,
+            }
+        );
+        gpu.flush().unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                Command::GET_DISPLAY_INFO,
+                Command::RESOURCE_CREATE_BLOB,
+                Command::SET_SCANOUT_BLOB,
+                Command::RESOURCE_FLUSH,
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Hardware cursor support for VirtIO GPU devices.
+
+use super::{Format, ResourceId, VirtIOGpu, CURSOR_RECT};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use log::warn;
+
+/// A cursor image which has been uploaded to the device, and can be shown with
+/// [`VirtIOGpu::show_cursor`] as often as needed without uploading it again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cursor {
+    resource_id: ResourceId,
+    hot_x: u32,
+    hot_y: u32,
+}
+
+impl Cursor {
+    /// Returns the ID of the resource holding the cursor image.
+    pub fn resource_id(&self) -> ResourceId {
+        self.resource_id
+    }
+
+    /// Returns the position within the image of the point which the cursor position refers to.
+    pub fn hotspot(&self) -> (u32, u32) {
+        (self.hot_x, self.hot_y)
+    }
+
+    /// Returns the same cursor image with a different hotspot.
+    ///
+    /// Call [`VirtIOGpu::show_cursor`] again for the change to take effect.
+    pub fn with_hotspot(self, hot_x: u32, hot_y: u32) -> Self {
+        Self {
+            hot_x,
+            hot_y,
+            ..self
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Uploads a cursor image of the given format and size to the device.
+    ///
+    /// The image may be up to 64x64 pixels, and is stored line by line with no padding between
+    /// lines. Images smaller than 64x64 are padded with transparent pixels at the right and
+    /// bottom. `hot_x` and `hot_y` give the position within the image of the point which the
+    /// cursor position refers to, e.g. the tip of an arrow.
+    pub fn create_cursor(
+        &mut self,
+        image: &[u8],
+        format: Format,
+        width: u32,
+        height: u32,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> Result<Cursor> {
+        let bytes_per_pixel = format.bytes_per_pixel() as usize;
+        if width == 0
+            || height == 0
+            || width > CURSOR_RECT.width
+            || height > CURSOR_RECT.height
+            || hot_x >= CURSOR_RECT.width
+            || hot_y >= CURSOR_RECT.height
+            || image.len() != width as usize * height as usize * bytes_per_pixel
+        {
+            return Err(Error::InvalidParam);
+        }
+        let resource_id = self.create_resource_2d_with_backing(
+            Format::B8G8R8A8UNORM,
+            CURSOR_RECT.width,
+            CURSOR_RECT.height,
+        )?;
+        let buffer = self.resource_buffer(resource_id).unwrap();
+        let stride = CURSOR_RECT.width as usize * 4;
+        for (src_line, dst_line) in image
+            .chunks_exact(width as usize * bytes_per_pixel)
+            .zip(buffer.chunks_exact_mut(stride))
+        {
+            for (src, dst) in src_line
+                .chunks_exact(bytes_per_pixel)
+                .zip(dst_line.chunks_exact_mut(4))
+            {
+                dst.copy_from_slice(&to_bgra(format, [src[0], src[1], src[2], src[3]]));
+            }
+        }
+        if let Err(e) = self.transfer_to_host(resource_id, CURSOR_RECT) {
+            self.unref_cursor_after_error(resource_id);
+            return Err(e);
+        }
+        Ok(Cursor {
+            resource_id,
+            hot_x,
+            hot_y,
+        })
+    }
+
+    /// Shows the given cursor on the given scanout, with its hotspot at the given position.
+    ///
+    /// The cursor is removed from any other scanout which it was previously shown on.
+    pub fn show_cursor(
+        &mut self,
+        scanout_id: u32,
+        cursor: &Cursor,
+        pos_x: u32,
+        pos_y: u32,
+    ) -> Result {
+        if scanout_id as usize >= self.framebuffers.len()
+            || !self.resources.contains_key(&cursor.resource_id)
+        {
+            return Err(Error::InvalidParam);
+        }
+        if scanout_id != self.cursor_scanout {
+            self.hide_cursor()?;
+        }
+        self.update_cursor(
+            cursor.resource_id.0,
+            scanout_id,
+            pos_x,
+            pos_y,
+            cursor.hot_x,
+            cursor.hot_y,
+            false,
+        )?;
+        self.cursor_resource = Some(cursor.resource_id);
+        self.cursor_scanout = scanout_id;
+        Ok(())
+    }
+
+    /// Hides the cursor, if it is shown.
+    pub fn hide_cursor(&mut self) -> Result {
+        if self.cursor_resource.take().is_some() {
+            self.update_cursor(0, self.cursor_scanout, 0, 0, 0, 0, false)?;
+        }
+        Ok(())
+    }
+
+    /// Hides the given cursor if it is shown, and frees it on the device.
+    pub fn destroy_cursor(&mut self, cursor: Cursor) -> Result {
+        if self.cursor_resource == Some(cursor.resource_id) {
+            self.hide_cursor()?;
+        }
+        self.unref_resource(cursor.resource_id)
+    }
+
+    /// Set the pointer shape and position.
+    ///
+    /// `cursor_image` must be a 64x64 image in [`Format::B8G8R8A8UNORM`]. The previous cursor set
+    /// by this method is freed. Use [`VirtIOGpu::create_cursor`] and [`VirtIOGpu::show_cursor`]
+    /// for other sizes, formats and scanouts.
+    pub fn setup_cursor(
+        &mut self,
+        cursor_image: &[u8],
+        pos_x: u32,
+        pos_y: u32,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> Result {
+        let cursor = self.create_cursor(
+            cursor_image,
+            Format::B8G8R8A8UNORM,
+            CURSOR_RECT.width,
+            CURSOR_RECT.height,
+            hot_x,
+            hot_y,
+        )?;
+        if let Err(e) = self.show_cursor(self.cursor_scanout, &cursor, pos_x, pos_y) {
+            self.unref_cursor_after_error(cursor.resource_id);
+            return Err(e);
+        }
+        // The old cursor image is no longer needed once the device has switched to the new one.
+        if let Some(old_resource_id) = self.owned_cursor.replace(cursor.resource_id) {
+            self.unref_resource(old_resource_id)?;
+        }
+        Ok(())
+    }
+
+    /// Move the pointer without updating the shape.
+    pub fn move_cursor(&mut self, pos_x: u32, pos_y: u32) -> Result {
+        let resource_id = self.cursor_resource.map_or(0, |id| id.0);
+        self.update_cursor(resource_id, self.cursor_scanout, pos_x, pos_y, 0, 0, true)?;
+        Ok(())
+    }
+
+    /// Frees a cursor resource which is no longer needed because of an earlier error. Any failure
+    /// is only logged, so that the caller can report the original error instead.
+    fn unref_cursor_after_error(&mut self, resource_id: ResourceId) {
+        if let Err(e) = self.unref_resource(resource_id) {
+            warn!("Failed to free cursor resource {:?}: {}", resource_id, e);
+        }
+    }
+}
+
+/// Converts a pixel of the given format to [`Format::B8G8R8A8UNORM`], treating any unused
+/// component as fully opaque.
+fn to_bgra(format: Format, pixel: [u8; 4]) -> [u8; 4] {
+    let [p0, p1, p2, p3] = pixel;
+    match format {
+        Format::B8G8R8A8UNORM => [p0, p1, p2, p3],
+        Format::B8G8R8X8UNORM => [p0, p1, p2, 0xff],
+        Format::A8R8G8B8UNORM => [p3, p2, p1, p0],
+        Format::X8R8G8B8UNORM => [p3, p2, p1, 0xff],
+        Format::R8G8B8A8UNORM => [p2, p1, p0, p3],
+        Format::X8B8G8R8UNORM => [p1, p2, p3, 0xff],
+        Format::A8B8G8R8UNORM => [p1, p2, p3, p0],
+        Format::R8G8B8X8UNORM => [p2, p1, p0, 0xff],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{
+            Command, Config, CtrlHeader, Features, UpdateCursor, CONTROL_QUEUE_SIZE, QUEUE_CURSOR,
+            QUEUE_SIZE, QUEUE_TRANSMIT,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+    use zerocopy::{AsBytes, FromBytes};
+
+    #[test]
+    fn pixel_formats() {
+        let pixel = [1, 2, 3, 4];
+        assert_eq!(to_bgra(Format::B8G8R8A8UNORM, pixel), [1, 2, 3, 4]);
+        assert_eq!(to_bgra(Format::R8G8B8A8UNORM, pixel), [3, 2, 1, 4]);
+        assert_eq!(to_bgra(Format::A8R8G8B8UNORM, pixel), [4, 3, 2, 1]);
+        assert_eq!(to_bgra(Format::X8B8G8R8UNORM, pixel), [2, 3, 4, 0xff]);
+    }
+
+    #[test]
+    fn show_and_hide_cursor() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(2),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate the device, recording the cursor updates.
+        let handle = thread::spawn(move || {
+            for _ in 0..3 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |_| {
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            let mut updates = Vec::new();
+            for _ in 0..3 {
+                State::wait_until_queue_notified(&state, QUEUE_CURSOR);
+                let request = state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<{ QUEUE_SIZE as usize }>(QUEUE_CURSOR);
+                let update = UpdateCursor::read_from_prefix(request.as_slice()).unwrap();
+                updates.push((
+                    update.resource_id,
+                    update.pos.scanout_id,
+                    update.hot_x,
+                    update.hot_y,
+                ));
+            }
+            updates
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        // A 2x1 image of a red and a green pixel.
+        let image = [0xff, 0, 0, 0xff, 0, 0xff, 0, 0xff];
+        let cursor = gpu
+            .create_cursor(&image, Format::R8G8B8A8UNORM, 2, 1, 1, 0)
+            .unwrap();
+        let buffer = gpu.resource_buffer(cursor.resource_id()).unwrap();
+        assert_eq!(
+            &buffer[..12],
+            &[0, 0, 0xff, 0xff, 0, 0xff, 0, 0xff, 0, 0, 0, 0]
+        );
+        assert!(buffer[12..].iter().all(|&byte| byte == 0));
+
+        gpu.show_cursor(0, &cursor, 10, 10).unwrap();
+        // Moving to another scanout hides the cursor on the first one.
+        gpu.show_cursor(1, &cursor.with_hotspot(0, 0), 10, 10)
+            .unwrap();
+
+        let id = u32::from(cursor.resource_id());
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![(id, 0, 1, 0), (0, 0, 0, 0), (id, 1, 0, 0)]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Accumulation of damaged (dirty) regions of a framebuffer, so that only they need to be
+//! transferred to the host.
+
+use super::Rect;
+use alloc::vec::Vec;
+
+/// The maximum number of separate rectangles which a [`DamageTracker`] keeps by default.
+const DEFAULT_MAX_RECTS: usize = 8;
+
+/// Accumulates rectangles which have been drawn to, merging overlapping ones so that each pixel
+/// only needs to be transferred once.
+///
+/// Overlapping or touching rectangles are merged into their bounding box. If there are more than
+/// the maximum number of separate rectangles, the pair whose merger wastes the least area is
+/// merged, so the number of commands sent to the device stays bounded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DamageTracker {
+    rects: Vec<Rect>,
+    max_rects: usize,
+}
+
+impl Default for DamageTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RECTS)
+    }
+}
+
+impl DamageTracker {
+    /// Creates a new empty tracker which keeps at most the given number of separate rectangles.
+    pub fn new(max_rects: usize) -> Self {
+        Self {
+            rects: Vec::new(),
+            max_rects: max_rects.max(1),
+        }
+    }
+
+    /// Records that the given rectangle has been drawn to.
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        self.insert(rect);
+        while self.rects.len() > self.max_rects {
+            self.merge_cheapest_pair();
+        }
+    }
+
+    /// Adds the given rectangle, merging it with any which it touches.
+    fn insert(&mut self, mut rect: Rect) {
+        // Merging two rectangles may make the result touch another one, so keep going until
+        // nothing touches.
+        while let Some(index) = self.rects.iter().position(|other| other.touches(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(index));
+        }
+        self.rects.push(rect);
+    }
+
+    /// Returns whether no damage has been recorded since the tracker was last cleared.
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// Returns the separate damaged rectangles, which don't overlap each other.
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    /// Returns the smallest rectangle containing all the damage, if there is any.
+    pub fn bounding_box(&self) -> Option<Rect> {
+        self.rects.iter().copied().reduce(|a, b| a.union(&b))
+    }
+
+    /// Removes and returns all the damaged rectangles.
+    pub fn take(&mut self) -> Vec<Rect> {
+        core::mem::take(&mut self.rects)
+    }
+
+    /// Forgets all damage recorded so far.
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    /// Merges the two rectangles whose bounding box covers the least area outside them.
+    fn merge_cheapest_pair(&mut self) {
+        let len = self.rects.len();
+        let best = (0..len)
+            .flat_map(|i| (i + 1..len).map(move |j| (i, j)))
+            .min_by_key(|&(i, j)| {
+                let (a, b) = (&self.rects[i], &self.rects[j]);
+                a.union(b).area() - a.area() - b.area()
+            });
+        if let Some((i, j)) = best {
+            // Remove the later one first so that the index of the earlier one doesn't change.
+            let b = self.rects.swap_remove(j);
+            let a = self.rects.swap_remove(i);
+            self.insert(a.union(&b));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_overlapping() {
+        let mut damage = DamageTracker::default();
+        damage.add(Rect::new(0, 0, 10, 10));
+        damage.add(Rect::new(100, 100, 10, 10));
+        damage.add(Rect::new(5, 5, 10, 10));
+        // Touching but not overlapping.
+        damage.add(Rect::new(15, 0, 5, 5));
+        damage.add(Rect::new(50, 50, 0, 10));
+
+        assert_eq!(
+            damage.rects(),
+            &[Rect::new(100, 100, 10, 10), Rect::new(0, 0, 20, 15)]
+        );
+        assert_eq!(damage.bounding_box(), Some(Rect::new(0, 0, 110, 110)));
+
+        // Joining two existing rectangles merges all three.
+        damage.add(Rect::new(10, 10, 95, 95));
+        assert_eq!(damage.take(), vec![Rect::new(0, 0, 110, 110)]);
+        assert!(damage.is_empty());
+    }
+
+    #[test]
+    fn limit_rects() {
+        let mut damage = DamageTracker::new(2);
+        damage.add(Rect::new(0, 0, 10, 10));
+        damage.add(Rect::new(100, 0, 10, 10));
+        damage.add(Rect::new(0, 20, 10, 10));
+
+        // The two rectangles closest together are merged.
+        assert_eq!(
+            damage.rects(),
+            &[Rect::new(100, 0, 10, 10), Rect::new(0, 0, 10, 30)]
+        );
+    }
+}
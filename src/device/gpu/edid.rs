@@ -0,0 +1,331 @@
+//! A parser for the EDID (Extended Display Identification Data) blobs which a VirtIO GPU device
+//! reports for its displays.
+
+use crate::{Error, Result};
+use alloc::{string::String, vec::Vec};
+
+/// The length of the base EDID block. Any extension blocks which follow it are ignored.
+const EDID_BLOCK_LEN: usize = 128;
+
+/// The fixed pattern with which every EDID starts.
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// The offset of the first of the four 18-byte descriptors in the base block.
+const DESCRIPTORS_OFFSET: usize = 54;
+const DESCRIPTOR_LEN: usize = 18;
+const DESCRIPTOR_COUNT: usize = 4;
+
+/// The display descriptor tag for the monitor name.
+const DESCRIPTOR_MONITOR_NAME: u8 = 0xfc;
+
+/// The modes indicated by each bit of the established timings bytes, starting from the most
+/// significant bit of the first byte.
+const ESTABLISHED_TIMINGS: [(u32, u32, u32); 17] = [
+    (720, 400, 70),
+    (720, 400, 88),
+    (640, 480, 60),
+    (640, 480, 67),
+    (640, 480, 72),
+    (640, 480, 75),
+    (800, 600, 56),
+    (800, 600, 60),
+    (800, 600, 72),
+    (800, 600, 75),
+    (832, 624, 75),
+    (1024, 768, 87),
+    (1024, 768, 60),
+    (1024, 768, 70),
+    (1024, 768, 75),
+    (1280, 1024, 75),
+    (1152, 870, 75),
+];
+
+/// A display mode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Mode {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The vertical refresh rate in Hz, rounded to the nearest integer.
+    pub refresh_rate: u32,
+}
+
+/// A detailed timing descriptor from an EDID.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DetailedTiming {
+    /// The pixel clock in kHz.
+    pub pixel_clock_khz: u32,
+    /// The number of visible pixels in each line.
+    pub h_active: u32,
+    /// The number of pixels in the horizontal blanking interval.
+    pub h_blank: u32,
+    /// The number of pixels from the end of the visible area to the start of the sync pulse.
+    pub h_sync_offset: u32,
+    /// The width of the horizontal sync pulse in pixels.
+    pub h_sync_width: u32,
+    /// The number of visible lines.
+    pub v_active: u32,
+    /// The number of lines in the vertical blanking interval.
+    pub v_blank: u32,
+    /// The number of lines from the end of the visible area to the start of the sync pulse.
+    pub v_sync_offset: u32,
+    /// The height of the vertical sync pulse in lines.
+    pub v_sync_width: u32,
+    /// The width of the visible area in millimetres, or 0 if unknown.
+    pub width_mm: u32,
+    /// The height of the visible area in millimetres, or 0 if unknown.
+    pub height_mm: u32,
+    /// Whether the mode is interlaced.
+    pub interlaced: bool,
+}
+
+impl DetailedTiming {
+    /// Parses an 18-byte descriptor, returning `None` if it is a display descriptor rather than a
+    /// detailed timing descriptor.
+    fn parse(descriptor: &[u8]) -> Option<Self> {
+        let d = |i: usize| u32::from(descriptor[i]);
+        let pixel_clock = d(0) | d(1) << 8;
+        if pixel_clock == 0 {
+            return None;
+        }
+        Some(Self {
+            pixel_clock_khz: pixel_clock * 10,
+            h_active: d(2) | (d(4) & 0xf0) << 4,
+            h_blank: d(3) | (d(4) & 0x0f) << 8,
+            v_active: d(5) | (d(7) & 0xf0) << 4,
+            v_blank: d(6) | (d(7) & 0x0f) << 8,
+            h_sync_offset: d(8) | (d(11) & 0xc0) << 2,
+            h_sync_width: d(9) | (d(11) & 0x30) << 4,
+            v_sync_offset: d(10) >> 4 | (d(11) & 0x0c) << 2,
+            v_sync_width: d(10) & 0x0f | (d(11) & 0x03) << 4,
+            width_mm: d(12) | (d(14) & 0xf0) << 4,
+            height_mm: d(13) | (d(14) & 0x0f) << 8,
+            interlaced: descriptor[17] & 0x80 != 0,
+        })
+    }
+
+    /// Returns the visible resolution and refresh rate of the timing.
+    pub fn mode(&self) -> Mode {
+        let h_total = u64::from(self.h_active + self.h_blank);
+        let v_total = u64::from(self.v_active + self.v_blank);
+        let pixels_per_frame = h_total * v_total;
+        let refresh_rate = (u64::from(self.pixel_clock_khz) * 1000 + pixels_per_frame / 2)
+            .checked_div(pixels_per_frame)
+            .unwrap_or(0);
+        Mode {
+            width: self.h_active,
+            height: self.v_active,
+            refresh_rate: refresh_rate as u32,
+        }
+    }
+}
+
+/// The information parsed from the base block of an EDID.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edid {
+    /// The EDID version and revision, e.g. `(1, 4)`.
+    pub version: (u8, u8),
+    /// The detailed timing descriptors, in order. The first is the display's preferred (native)
+    /// timing.
+    pub detailed_timings: Vec<DetailedTiming>,
+    /// All modes which the display claims to support, from the detailed, established and standard
+    /// timings, without duplicates.
+    pub modes: Vec<Mode>,
+    /// The physical size (width, height) of the display in millimetres, if known.
+    pub physical_size_mm: Option<(u32, u32)>,
+    /// The monitor name, if the EDID includes one.
+    pub monitor_name: Option<String>,
+}
+
+impl Edid {
+    /// Parses the base block of the given EDID blob.
+    ///
+    /// Returns [`Error::InvalidParam`] if the blob is too short, doesn't start with the EDID
+    /// header or has an incorrect checksum.
+    pub fn parse(edid: &[u8]) -> Result<Self> {
+        if edid.len() < EDID_BLOCK_LEN || edid[..EDID_HEADER.len()] != EDID_HEADER {
+            return Err(Error::InvalidParam);
+        }
+        let block = &edid[..EDID_BLOCK_LEN];
+        if block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let version = (block[18], block[19]);
+
+        let mut detailed_timings = Vec::new();
+        let mut monitor_name = None;
+        for descriptor in block[DESCRIPTORS_OFFSET..]
+            .chunks_exact(DESCRIPTOR_LEN)
+            .take(DESCRIPTOR_COUNT)
+        {
+            if let Some(timing) = DetailedTiming::parse(descriptor) {
+                detailed_timings.push(timing);
+            } else if descriptor[3] == DESCRIPTOR_MONITOR_NAME {
+                monitor_name = Some(parse_descriptor_text(&descriptor[5..]));
+            }
+        }
+
+        let mut modes = Vec::new();
+        let mut add_mode = |mode: Mode| {
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
+        };
+        for timing in &detailed_timings {
+            add_mode(timing.mode());
+        }
+        let established =
+            u32::from(block[35]) << 16 | u32::from(block[36]) << 8 | u32::from(block[37]);
+        for (bit, &(width, height, refresh_rate)) in ESTABLISHED_TIMINGS.iter().enumerate() {
+            if established & (1 << (23 - bit)) != 0 {
+                add_mode(Mode {
+                    width,
+                    height,
+                    refresh_rate,
+                });
+            }
+        }
+        for standard in block[38..54].chunks_exact(2) {
+            if let Some(mode) = parse_standard_timing(standard, version) {
+                add_mode(mode);
+            }
+        }
+
+        // Prefer the more precise size from the preferred timing, falling back to the size in
+        // centimetres from the basic display parameters.
+        let physical_size_mm = match detailed_timings.first() {
+            Some(timing) if timing.width_mm != 0 && timing.height_mm != 0 => {
+                Some((timing.width_mm, timing.height_mm))
+            }
+            _ if block[21] != 0 && block[22] != 0 => {
+                Some((u32::from(block[21]) * 10, u32::from(block[22]) * 10))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            version,
+            detailed_timings,
+            modes,
+            physical_size_mm,
+            monitor_name,
+        })
+    }
+
+    /// Returns the display's preferred (native) mode, if it has one.
+    pub fn preferred_mode(&self) -> Option<Mode> {
+        self.detailed_timings.first().map(DetailedTiming::mode)
+    }
+}
+
+/// Parses a 2-byte standard timing, returning `None` if it is unused.
+fn parse_standard_timing(standard: &[u8], version: (u8, u8)) -> Option<Mode> {
+    if standard[0] == 0x00 || standard == [0x01, 0x01] {
+        return None;
+    }
+    let width = (u32::from(standard[0]) + 31) * 8;
+    let height = match standard[1] >> 6 {
+        // Before EDID 1.3 this meant 1:1.
+        0 if version < (1, 3) => width,
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+    Some(Mode {
+        width,
+        height,
+        refresh_rate: u32::from(standard[1] & 0x3f) + 60,
+    })
+}
+
+/// Parses the text of a display descriptor, which is terminated by a line feed and padded with
+/// spaces.
+fn parse_descriptor_text(text: &[u8]) -> String {
+    text.iter()
+        .take_while(|&&c| c != b'\n')
+        .map(|&c| char::from(c))
+        .collect::<String>()
+        .trim_end()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an EDID like the one QEMU generates for a 1280x800 display.
+    fn example_edid() -> Vec<u8> {
+        let mut edid = vec![0; EDID_BLOCK_LEN];
+        edid[..8].copy_from_slice(&EDID_HEADER);
+        // Version 1.4.
+        edid[18] = 1;
+        edid[19] = 4;
+        // 30cm x 19cm.
+        edid[21] = 30;
+        edid[22] = 19;
+        // 640x480@60 and 800x600@60.
+        edid[35] = 0b0010_0001;
+        // Standard timings: 1920x1080@60, then unused entries.
+        edid[38..40].copy_from_slice(&[(1920 / 8 - 31) as u8, 0b1100_0000]);
+        for standard in edid[40..54].chunks_exact_mut(2) {
+            standard.copy_from_slice(&[0x01, 0x01]);
+        }
+        // Preferred timing: 1280x800 at 71.0 MHz, which is 60 Hz with these blanking intervals,
+        // and 301mm x 188mm.
+        edid[54..72].copy_from_slice(&[
+            0xbc, 0x1b, 0x00, 0xa0, 0x50, 0x20, 0x17, 0x30, 0x30, 0x20, 0x36, 0x00, 0x2d, 0xbc,
+            0x10, 0x00, 0x00, 0x1a,
+        ]);
+        // Monitor name.
+        edid[72..90].copy_from_slice(b"\0\0\0\xfc\0QEMU Monitor\n");
+        let checksum = edid.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        edid[127] = 0u8.wrapping_sub(checksum);
+        edid
+    }
+
+    #[test]
+    fn parse_edid() {
+        let edid = Edid::parse(&example_edid()).unwrap();
+
+        assert_eq!(edid.version, (1, 4));
+        assert_eq!(edid.detailed_timings.len(), 1);
+        let timing = edid.detailed_timings[0];
+        assert_eq!(timing.pixel_clock_khz, 71_000);
+        assert_eq!((timing.h_active, timing.h_blank), (1280, 160));
+        assert_eq!((timing.v_active, timing.v_blank), (800, 23));
+        assert_eq!((timing.h_sync_offset, timing.h_sync_width), (48, 32));
+        assert_eq!((timing.v_sync_offset, timing.v_sync_width), (3, 6));
+        assert_eq!(
+            edid.preferred_mode(),
+            Some(Mode {
+                width: 1280,
+                height: 800,
+                refresh_rate: 60
+            })
+        );
+        assert_eq!(
+            edid.modes
+                .iter()
+                .map(|mode| (mode.width, mode.height, mode.refresh_rate))
+                .collect::<Vec<_>>(),
+            vec![
+                (1280, 800, 60),
+                (640, 480, 60),
+                (800, 600, 60),
+                (1920, 1080, 60)
+            ]
+        );
+        assert_eq!(edid.physical_size_mm, Some((301, 188)));
+        assert_eq!(edid.monitor_name.as_deref(), Some("QEMU Monitor"));
+    }
+
+    #[test]
+    fn parse_invalid_edid() {
+        let mut edid = example_edid();
+        assert_eq!(Edid::parse(&edid[..100]), Err(Error::InvalidParam));
+        edid[127] = edid[127].wrapping_add(1);
+        assert_eq!(Edid::parse(&edid), Err(Error::InvalidParam));
+    }
+}
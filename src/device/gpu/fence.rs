@@ -0,0 +1,299 @@
+//! Fenced commands, which are submitted to a VirtIO GPU device without waiting for them to
+//! complete.
+
+use super::{
+    Command, ContextId, CtrlHeader, Rect, ResourceFlush, ResourceId, TransferToHost2D, VirtIOGpu,
+    GPU_FLAG_FENCE, QUEUE_TRANSMIT,
+};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{boxed::Box, vec::Vec};
+use core::{hint::spin_loop, mem::size_of};
+use log::warn;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The maximum number of signalled fences which are remembered until they are reported by
+/// [`VirtIOGpu::poll_fences`], and of failed fences which are remembered until they are checked.
+const MAX_SIGNALLED_FENCES: usize = 64;
+
+/// The ID of a fence, which is signalled when the command submitted with it has completed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FenceId(pub(crate) u64);
+
+impl From<FenceId> for u64 {
+    fn from(id: FenceId) -> u64 {
+        id.0
+    }
+}
+
+/// A fenced command which has been submitted to the device, along with the buffers which must be
+/// kept alive until it completes.
+pub(crate) struct InFlight {
+    fence: FenceId,
+    req: Box<[u8]>,
+    rsp: Box<[u8]>,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Like [`VirtIOGpu::transfer_to_host`], but returns as soon as the command has been
+    /// submitted, with a fence which is signalled once the transfer has completed.
+    ///
+    /// The backing memory of the resource must not be modified until then.
+    pub fn transfer_to_host_fenced(&mut self, id: ResourceId, rect: Rect) -> Result<FenceId> {
+        let offset = self.transfer_offset(id, &rect)?;
+        self.request_fenced(
+            TransferToHost2D {
+                header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
+                rect,
+                offset,
+                resource_id: id.0,
+                _padding: 0,
+            }
+            .as_bytes(),
+        )
+    }
+
+    /// Like [`VirtIOGpu::flush_resource`], but returns as soon as the command has been submitted,
+    /// with a fence which is signalled once the flush has completed.
+    pub fn flush_resource_fenced(&mut self, id: ResourceId, rect: Rect) -> Result<FenceId> {
+        self.check_rect(id, &rect)?;
+        self.request_fenced(
+            ResourceFlush {
+                header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
+                rect,
+                resource_id: id.0,
+                _padding: 0,
+            }
+            .as_bytes(),
+        )
+    }
+
+    /// Like [`VirtIOGpu::submit_3d`], but returns as soon as the commands have been submitted,
+    /// with a fence which is signalled once they have been executed.
+    pub fn submit_3d_fenced(&mut self, ctx: ContextId, commands: &[u8]) -> Result<FenceId> {
+        let req = self.submit_3d_request(ctx, commands)?;
+        self.request_fenced(&req)
+    }
+
+    /// Reclaims any fenced commands which the device has completed, and returns the fences which
+    /// have been signalled since this was last called, in the order they were signalled.
+    ///
+    /// Only the 64 most recently signalled fences are remembered, so if this isn't called often
+    /// enough older ones are missed. [`VirtIOGpu::is_fence_signalled`] can still be used to check
+    /// them.
+    pub fn poll_fences(&mut self) -> Result<Vec<FenceId>> {
+        self.reclaim_all_fenced()?;
+        Ok(self.signalled_fences.drain(..).collect())
+    }
+
+    /// Returns whether the given fence has been signalled.
+    ///
+    /// Returns [`Error::IoError`] if the device rejected the command submitted with the fence.
+    /// This is only reported once, and only for the 64 most recent failures.
+    pub fn is_fence_signalled(&mut self, fence: FenceId) -> Result<bool> {
+        if fence.0 >= self.next_fence_id {
+            return Err(Error::InvalidParam);
+        }
+        self.reclaim_all_fenced()?;
+        if self.failed_fences.remove(&fence) {
+            return Err(Error::IoError);
+        }
+        Ok(!self
+            .in_flight
+            .values()
+            .any(|command| command.fence == fence))
+    }
+
+    /// Blocks until the given fence has been signalled.
+    ///
+    /// Returns [`Error::IoError`] if the device rejected the command submitted with the fence.
+    pub fn wait_fence(&mut self, fence: FenceId) -> Result {
+        while !self.is_fence_signalled(fence)? {
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Submits the given request with a new fence, without waiting for it to complete.
+    fn request_fenced(&mut self, req: &[u8]) -> Result<FenceId> {
+        let mut req: Box<[u8]> = req.into();
+        let mut header = CtrlHeader::read_from_prefix(&*req).ok_or(Error::InvalidParam)?;
+        let fence = FenceId(self.next_fence_id);
+        header.flags |= GPU_FLAG_FENCE;
+        header.fence_id = fence.0;
+        header.write_to_prefix(&mut *req).unwrap();
+        let mut rsp: Box<[u8]> = FromBytes::new_box_slice_zeroed(size_of::<CtrlHeader>());
+
+        self.wait_for_descriptors(2)?;
+        // Safe because the buffers are kept in `self.in_flight` until the request is popped in
+        // `reclaim_fenced`.
+        let token = unsafe { self.control_queue.add(&[&req], &mut [&mut rsp]) }?;
+        if self.control_queue.should_notify() {
+            self.transport.notify(QUEUE_TRANSMIT);
+        }
+        self.next_fence_id += 1;
+        self.in_flight.insert(token, InFlight { fence, req, rsp });
+        Ok(fence)
+    }
+
+    /// Reclaims fenced commands until there are at least the given number of free descriptors in
+    /// the control queue.
+    pub(crate) fn wait_for_descriptors(&mut self, count: usize) -> Result {
+        while self.control_queue.available_desc() < count {
+            if self.in_flight.is_empty() {
+                return Err(Error::QueueFull);
+            }
+            match self.control_queue.peek_used() {
+                Some(token) => self.reclaim_fenced(token)?,
+                None => spin_loop(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reclaims all fenced commands which the device has completed.
+    fn reclaim_all_fenced(&mut self) -> Result {
+        while let Some(token) = self.control_queue.peek_used() {
+            self.reclaim_fenced(token)?;
+        }
+        Ok(())
+    }
+
+    /// Pops the fenced command with the given token from the used ring, and records that its
+    /// fence has been signalled.
+    pub(crate) fn reclaim_fenced(&mut self, token: u16) -> Result {
+        let command = self.in_flight.get_mut(&token).ok_or(Error::WrongToken)?;
+        // Safe because these are the same buffers as we passed to `add` in `request_fenced`.
+        unsafe {
+            self.control_queue
+                .pop_used(token, &[&command.req], &mut [&mut command.rsp])
+        }?;
+        // The buffers must only be dropped once the command has been popped.
+        let command = self.in_flight.remove(&token).unwrap();
+        let rsp = CtrlHeader::read_from_prefix(&*command.rsp).unwrap();
+        if rsp.check_type(Command::OK_NODATA).is_err() {
+            warn!(
+                "Fenced command {:?} failed: {:?}",
+                command.fence, rsp.hdr_type
+            );
+            if self.failed_fences.len() == MAX_SIGNALLED_FENCES {
+                let oldest = *self.failed_fences.iter().next().unwrap();
+                self.failed_fences.remove(&oldest);
+            }
+            self.failed_fences.insert(command.fence);
+        }
+        if self.signalled_fences.len() == MAX_SIGNALLED_FENCES {
+            self.signalled_fences.pop_front();
+        }
+        self.signalled_fences.push_back(command.fence);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{Config, Features, Format, CONTROL_QUEUE_SIZE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::{
+        sync::{mpsc, Mutex},
+        thread,
+    };
+
+    #[test]
+    fn fenced_commands() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let (go_tx, go_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // Simulate the device, which accepts every command and echoes back any fence.
+        let handle = thread::spawn(move || {
+            let handle_request = |expected: Command| {
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let request = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        assert_eq!(request.hdr_type, expected);
+                        let mut response = CtrlHeader::with_type(Command::OK_NODATA);
+                        response.flags = request.flags;
+                        response.fence_id = request.fence_id;
+                        response.as_bytes().to_vec()
+                    });
+            };
+            for expected in [
+                Command::RESOURCE_CREATE_2D,
+                Command::RESOURCE_ATTACH_BACKING,
+            ] {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                handle_request(expected);
+            }
+
+            // Complete the first fenced command only once asked to.
+            go_rx.recv().unwrap();
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            handle_request(Command::TRANSFER_TO_HOST_2D);
+            done_tx.send(()).unwrap();
+
+            // Then the second fenced command, followed by a blocking command.
+            go_rx.recv().unwrap();
+            handle_request(Command::RESOURCE_FLUSH);
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            handle_request(Command::RESOURCE_FLUSH);
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let resource = gpu
+            .create_resource_2d_with_backing(Format::B8G8R8A8UNORM, 4, 4)
+            .unwrap();
+        let rect = Rect::new(0, 0, 4, 4);
+        let transfer_fence = gpu.transfer_to_host_fenced(resource, rect).unwrap();
+        let flush_fence = gpu.flush_resource_fenced(resource, rect).unwrap();
+        assert_ne!(transfer_fence, flush_fence);
+        assert_eq!(gpu.is_fence_signalled(transfer_fence), Ok(false));
+        assert_eq!(gpu.poll_fences(), Ok(vec![]));
+
+        go_tx.send(()).unwrap();
+        done_rx.recv().unwrap();
+        assert_eq!(gpu.poll_fences(), Ok(vec![transfer_fence]));
+        assert_eq!(gpu.is_fence_signalled(transfer_fence), Ok(true));
+        assert_eq!(gpu.is_fence_signalled(flush_fence), Ok(false));
+
+        // A blocking command reclaims the fenced command which completes before it.
+        go_tx.send(()).unwrap();
+        gpu.flush_resource(resource, rect).unwrap();
+        assert_eq!(gpu.poll_fences(), Ok(vec![flush_fence]));
+        assert_eq!(gpu.wait_fence(flush_fence), Ok(()));
+
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+//! Implementation of the `embedded-graphics` drawing traits for the framebuffers of VirtIO GPU
+//! scanouts.
+
+use super::{Format, Rect, Scanout};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use embedded_graphics_core::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, OriginDimensions, Point, Size},
+    pixelcolor::{Rgb888, RgbColor},
+    primitives::Rectangle,
+    Pixel,
+};
+
+/// The framebuffer of a scanout, borrowed for drawing.
+struct Canvas<'a> {
+    buffer: &'a mut [u8],
+    format: Format,
+    bounds: Rectangle,
+}
+
+impl Canvas<'_> {
+    /// Returns the offset in the buffer of the given point, or `None` if it is outside the
+    /// framebuffer.
+    fn offset(&self, point: Point) -> Option<usize> {
+        if self.bounds.contains(point) {
+            Some((point.y as usize * self.bounds.size.width as usize + point.x as usize) * 4)
+        } else {
+            None
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Scanout<'_, H, T> {
+    /// Borrows the framebuffer of the scanout for drawing, or returns `Error::NotReady` if there
+    /// isn't one, or its backing has been detached.
+    fn canvas(&mut self) -> Result<Canvas<'_>> {
+        let framebuffer = self.gpu.framebuffers[self.id as usize]
+            .as_ref()
+            .ok_or(Error::NotReady)?;
+        let (resource_id, rect) = (framebuffer.resource_id, framebuffer.rect);
+        // Framebuffers are created as 2D resources with driver-allocated backing, but the caller
+        // may have detached it through the public resource API since.
+        let format = self
+            .gpu
+            .resources
+            .get(&resource_id)
+            .and_then(|resource| resource.format)
+            .ok_or(Error::NotReady)?;
+        let buffer = self
+            .gpu
+            .resource_buffer(resource_id)
+            .ok_or(Error::NotReady)?;
+        Ok(Canvas {
+            buffer,
+            format,
+            bounds: Rectangle::new(Point::zero(), Size::new(rect.width, rect.height)),
+        })
+    }
+
+    /// Records the given area, which must lie within the framebuffer, as damaged.
+    fn add_area_damage(&mut self, area: &Rectangle) -> Result {
+        self.add_damage(Rect::new(
+            area.top_left.x as u32,
+            area.top_left.y as u32,
+            area.size.width,
+            area.size.height,
+        ))
+    }
+}
+
+/// The scanout can be drawn to with `embedded-graphics` once a framebuffer has been set up for it.
+///
+/// Drawing returns `Error::NotReady` if there is no framebuffer, or its backing has been detached
+/// with [`VirtIOGpu::detach_backing`](super::VirtIOGpu::detach_backing). The areas drawn to are recorded
+/// as damage, so [`Scanout::flush_damage`] shows them on the screen.
+impl<H: Hal, T: Transport> DrawTarget for Scanout<'_, H, T> {
+    type Color = Rgb888;
+    type Error = Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result
+    where
+        I: IntoIterator<Item = Pixel<Rgb888>>,
+    {
+        let canvas = self.canvas()?;
+        let mut dirty: Option<(Point, Point)> = None;
+        for Pixel(point, color) in pixels {
+            if let Some(offset) = canvas.offset(point) {
+                canvas.buffer[offset..offset + 4].copy_from_slice(&encode(canvas.format, color));
+                dirty = Some(match dirty {
+                    Some((min, max)) => (min.component_min(point), max.component_max(point)),
+                    None => (point, point),
+                });
+            }
+        }
+        if let Some((min, max)) = dirty {
+            self.add_area_damage(&Rectangle::with_corners(min, max))?;
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result
+    where
+        I: IntoIterator<Item = Rgb888>,
+    {
+        let canvas = self.canvas()?;
+        let drawable = area.intersection(&canvas.bounds);
+        if drawable.is_zero_sized() {
+            return Ok(());
+        }
+        let mut colors = colors.into_iter();
+        for y in area.rows() {
+            let row = canvas.offset(Point::new(drawable.top_left.x, y));
+            for x in area.columns() {
+                let Some(color) = colors.next() else {
+                    break;
+                };
+                if let (Some(row), true) = (row, drawable.columns().contains(&x)) {
+                    let offset = row + (x - drawable.top_left.x) as usize * 4;
+                    canvas.buffer[offset..offset + 4]
+                        .copy_from_slice(&encode(canvas.format, color));
+                }
+            }
+        }
+        self.add_area_damage(&drawable)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb888) -> Result {
+        let canvas = self.canvas()?;
+        let area = area.intersection(&canvas.bounds);
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+        let pixel = encode(canvas.format, color);
+        let row_len = area.size.width as usize * 4;
+        for y in area.rows() {
+            let start = canvas.offset(Point::new(area.top_left.x, y)).unwrap();
+            for dst in canvas.buffer[start..start + row_len].chunks_exact_mut(4) {
+                dst.copy_from_slice(&pixel);
+            }
+        }
+        self.add_area_damage(&area)
+    }
+
+    fn clear(&mut self, color: Rgb888) -> Result {
+        self.fill_solid(&self.bounding_box(), color)
+    }
+}
+
+/// The size of the scanout's framebuffer, or zero if it doesn't have one.
+impl<H: Hal, T: Transport> OriginDimensions for Scanout<'_, H, T> {
+    fn size(&self) -> Size {
+        let (width, height) = self.framebuffer_size().unwrap_or((0, 0));
+        Size::new(width, height)
+    }
+}
+
+/// Converts the given colour to a fully opaque pixel of the given format.
+fn encode(format: Format, color: Rgb888) -> [u8; 4] {
+    let (r, g, b, a) = (color.r(), color.g(), color.b(), 0xff);
+    match format {
+        Format::B8G8R8A8UNORM | Format::B8G8R8X8UNORM => [b, g, r, a],
+        Format::A8R8G8B8UNORM | Format::X8R8G8B8UNORM => [a, r, g, b],
+        Format::R8G8B8A8UNORM | Format::R8G8B8X8UNORM => [r, g, b, a],
+        Format::A8B8G8R8UNORM | Format::X8B8G8R8UNORM => [a, b, g, r],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{
+            Command, Config, CtrlHeader, Features, RespDisplayInfo, VirtIOGpu, CONTROL_QUEUE_SIZE,
+            QUEUE_TRANSMIT,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+    use zerocopy::{AsBytes, FromBytes};
+
+    #[test]
+    fn draw_to_framebuffer() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate the device setting up a 4x2 framebuffer.
+        let handle = thread::spawn(move || {
+            for _ in 0..4 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                            CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .write_to_prefix(&mut response[..])
+                                .unwrap();
+                            let pmode = &mut response[size_of::<CtrlHeader>()..];
+                            Rect::new(0, 0, 4, 2).write_to_prefix(&mut *pmode).unwrap();
+                            // Enabled.
+                            pmode[size_of::<Rect>()] = 1;
+                            response
+                        } else {
+                            CtrlHeader::with_type(Command::OK_NODATA)
+                                .as_bytes()
+                                .to_vec()
+                        }
+                    });
+            }
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let mut scanout = gpu.scanout(0).unwrap();
+        assert_eq!(
+            scanout.fill_solid(&Rectangle::zero(), Rgb888::RED),
+            Err(Error::NotReady)
+        );
+        scanout.setup_framebuffer(Format::B8G8R8A8UNORM).unwrap();
+        handle.join().unwrap();
+        assert_eq!(scanout.size(), Size::new(4, 2));
+
+        // Partly outside the framebuffer.
+        scanout
+            .fill_solid(
+                &Rectangle::new(Point::new(2, -1), Size::new(5, 2)),
+                Rgb888::new(1, 2, 3),
+            )
+            .unwrap();
+        scanout
+            .draw_iter([
+                Pixel(Point::new(0, 1), Rgb888::RED),
+                Pixel(Point::new(-1, 0), Rgb888::RED),
+            ])
+            .unwrap();
+        scanout
+            .fill_contiguous(
+                &Rectangle::new(Point::new(2, 1), Size::new(3, 1)),
+                [Rgb888::GREEN, Rgb888::BLUE, Rgb888::WHITE],
+            )
+            .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            scanout.framebuffer().unwrap(),
+            &[
+                0, 0, 0, 0, 0, 0, 0, 0, 3, 2, 1, 0xff, 3, 2, 1, 0xff,
+                0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0xff, 0, 0xff, 0xff, 0, 0, 0xff,
+            ]
+        );
+        assert_eq!(
+            gpu.framebuffers[0].as_ref().unwrap().damage.rects(),
+            &[Rect::new(0, 1, 1, 1), Rect::new(2, 0, 2, 2)]
+        );
+    }
+
+    #[test]
+    fn draw_after_detaching_backing() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate the device setting up a 4x2 framebuffer and then detaching its backing.
+        let handle = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..5 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        commands.push(header.hdr_type);
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                            CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .write_to_prefix(&mut response[..])
+                                .unwrap();
+                            let pmode = &mut response[size_of::<CtrlHeader>()..];
+                            Rect::new(0, 0, 4, 2).write_to_prefix(&mut *pmode).unwrap();
+                            // Enabled.
+                            pmode[size_of::<Rect>()] = 1;
+                            response
+                        } else {
+                            CtrlHeader::with_type(Command::OK_NODATA)
+                                .as_bytes()
+                                .to_vec()
+                        }
+                    });
+            }
+            commands
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let mut scanout = gpu.scanout(0).unwrap();
+        scanout.setup_framebuffer(Format::B8G8R8A8UNORM).unwrap();
+        let resource_id = scanout.framebuffer_resource().unwrap();
+        gpu.detach_backing(resource_id).unwrap();
+        assert_eq!(
+            handle.join().unwrap().last(),
+            Some(&Command::RESOURCE_DETACH_BACKING)
+        );
+
+        let mut scanout = gpu.scanout(0).unwrap();
+        assert_eq!(
+            scanout.fill_solid(&Rectangle::new(Point::zero(), Size::new(1, 1)), Rgb888::RED),
+            Err(Error::NotReady)
+        );
+        assert_eq!(
+            scanout.draw_iter([Pixel(Point::zero(), Rgb888::RED)]),
+            Err(Error::NotReady)
+        );
+    }
+}
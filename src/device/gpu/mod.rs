@@ -0,0 +1,1061 @@
+//! Driver for VirtIO GPU devices.
+
+mod blob;
+mod cursor;
+mod damage;
+mod edid;
+mod fence;
+#[cfg(feature = "embedded-graphics-core")]
+mod graphics;
+mod resource;
+mod scanout;
+mod virgl;
+
+pub use self::blob::{BlobFlags, MapCaching};
+pub use self::cursor::Cursor;
+pub use self::damage::DamageTracker;
+pub use self::edid::{DetailedTiming, Edid, Mode};
+pub use self::fence::FenceId;
+pub use self::resource::{MemEntry, ResourceId};
+pub use self::scanout::{DisplayEvent, DisplayInfo, FramebufferInfo, Scanout};
+pub use self::virgl::{Box3D, CapsetInfo, ContextId, Resource3DParams, Transfer3D};
+
+use self::fence::InFlight;
+use self::resource::Resource;
+use self::scanout::Framebuffer;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{SharedMemoryRegion, Transport};
+use crate::volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly};
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    vec,
+    vec::Vec,
+};
+use bitflags::bitflags;
+use core::{
+    cmp::min,
+    convert::TryFrom,
+    hint::spin_loop,
+    mem::{self, size_of},
+    ptr::NonNull,
+};
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes};
+
+const QUEUE_SIZE: u16 = 2;
+
+/// The size of the control queue, which must be big enough for several fenced commands to be in
+/// flight at once.
+const CONTROL_QUEUE_SIZE: usize = 16;
+
+/// The maximum number of scanouts which a device may have.
+const MAX_SCANOUTS: usize = 16;
+
+/// A virtio based graphics adapter.
+///
+/// It can operate in 2D mode and in 3D (virgl) mode.
+/// 3D mode will offload rendering ops to the host gpu and therefore requires
+/// a gpu with 3D support on the host machine.
+/// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
+/// and multiple scanouts (aka heads).
+pub struct VirtIOGpu<H: Hal, T: Transport> {
+    transport: T,
+    config_space: NonNull<Config>,
+    negotiated_features: Features,
+    /// The shared memory region into which the device maps host-visible blob resources, if it
+    /// has one.
+    host_visible_region: Option<SharedMemoryRegion>,
+    /// The configuration of each scanout which the device last reported.
+    displays: Vec<DisplayInfo>,
+    /// Whether to recreate framebuffers automatically when their scanouts are resized.
+    auto_resize: bool,
+    /// The framebuffer attached to each scanout, if any, indexed by scanout ID.
+    framebuffers: Vec<Option<Framebuffer>>,
+    /// The resource currently shown as the cursor, if any.
+    cursor_resource: Option<ResourceId>,
+    /// The scanout on which the cursor is shown.
+    cursor_scanout: u32,
+    /// The cursor image created by [`VirtIOGpu::setup_cursor`], which is freed when it is
+    /// replaced.
+    owned_cursor: Option<ResourceId>,
+    /// All resources which the driver has created on the device.
+    resources: BTreeMap<ResourceId, Resource<H>>,
+    /// The resource ID to try next when creating a resource.
+    next_resource_id: u32,
+    /// The number of capability sets which the device supports.
+    num_capsets: u32,
+    /// All 3D contexts which the driver has created on the device.
+    contexts: BTreeSet<ContextId>,
+    /// The context ID to try next when creating a context.
+    next_context_id: u32,
+    /// Fenced commands which have been submitted to the device but not yet completed, by the
+    /// token of their descriptor chain.
+    in_flight: BTreeMap<u16, InFlight>,
+    /// Fences which have completed since they were last reported by
+    /// [`VirtIOGpu::poll_fences`], up to `MAX_SIGNALLED_FENCES` of the most recent.
+    signalled_fences: VecDeque<FenceId>,
+    /// Fences whose commands were rejected by the device, and which have not yet been waited
+    /// for, up to `MAX_SIGNALLED_FENCES` of the most recent.
+    failed_fences: BTreeSet<FenceId>,
+    /// The ID to use for the next fence.
+    next_fence_id: u64,
+    /// Queue for sending control commands.
+    control_queue: VirtQueue<H, CONTROL_QUEUE_SIZE>,
+    /// Queue for sending cursor commands.
+    cursor_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// Send buffer for queue.
+    queue_buf_send: Box<[u8]>,
+    /// Recv buffer for queue.
+    queue_buf_recv: Box<[u8]>,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Create a new VirtIO-Gpu driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let mut negotiated_features = Features::empty();
+        transport.begin_init(|features| {
+            let features = Features::from_bits_truncate(features);
+            info!("Device features {:?}", features);
+            let supported_features =
+                Features::EDID | Features::VIRGL | Features::RESOURCE_BLOB | Features::CONTEXT_INIT;
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
+        });
+
+        // read configuration space
+        let config_space = transport.config_space::<Config>()?;
+        let (num_scanouts, num_capsets) = unsafe {
+            let events_read = volread!(config_space, events_read);
+            let num_scanouts = volread!(config_space, num_scanouts);
+            let num_capsets = volread!(config_space, num_capsets);
+            info!(
+                "events_read: {:#x}, num_scanouts: {:#x}, num_capsets: {:#x}",
+                events_read, num_scanouts, num_capsets
+            );
+            (num_scanouts, num_capsets)
+        };
+        let num_scanouts = min(num_scanouts as usize, MAX_SCANOUTS);
+        let host_visible_region = if negotiated_features.contains(Features::RESOURCE_BLOB) {
+            transport.shared_memory_region(SHM_ID_HOST_VISIBLE)
+        } else {
+            None
+        };
+
+        let control_queue = VirtQueue::new(&mut transport, QUEUE_TRANSMIT)?;
+        let cursor_queue = VirtQueue::new(&mut transport, QUEUE_CURSOR)?;
+
+        let queue_buf_send = FromBytes::new_box_slice_zeroed(PAGE_SIZE);
+        let queue_buf_recv = FromBytes::new_box_slice_zeroed(PAGE_SIZE);
+
+        transport.finish_init();
+
+        Ok(VirtIOGpu {
+            transport,
+            config_space,
+            negotiated_features,
+            host_visible_region,
+            displays: vec![DisplayInfo::default(); num_scanouts],
+            auto_resize: false,
+            framebuffers: (0..num_scanouts).map(|_| None).collect(),
+            cursor_resource: None,
+            cursor_scanout: SCANOUT_ID,
+            owned_cursor: None,
+            resources: BTreeMap::new(),
+            next_resource_id: 1,
+            num_capsets,
+            contexts: BTreeSet::new(),
+            next_context_id: 1,
+            in_flight: BTreeMap::new(),
+            signalled_fences: VecDeque::new(),
+            failed_fences: BTreeSet::new(),
+            next_fence_id: 1,
+            control_queue,
+            cursor_queue,
+            queue_buf_send,
+            queue_buf_recv,
+        })
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Returns the number of scanouts which the device supports.
+    pub fn num_scanouts(&self) -> u32 {
+        self.framebuffers.len() as u32
+    }
+
+    /// Queries the current configuration of every scanout from the device, indexed by scanout ID.
+    pub fn display_info(&mut self) -> Result<Vec<DisplayInfo>> {
+        let info = self.get_display_info()?;
+        Ok(info.pmodes[..self.framebuffers.len()]
+            .iter()
+            .map(DisplayInfo::from)
+            .collect())
+    }
+
+    /// Checks whether the device has signalled that the display configuration has changed, and if
+    /// so acknowledges it and returns the scanouts which were added, removed or resized since
+    /// the configuration was last queried.
+    ///
+    /// Until the configuration has been queried, e.g. by [`VirtIOGpu::display_info`] or
+    /// [`VirtIOGpu::setup_framebuffer`], every scanout is assumed to be disabled, so the first
+    /// call reports any scanouts which were already enabled as added.
+    ///
+    /// This should be called after [`VirtIOGpu::ack_interrupt`] reports an interrupt, as a
+    /// configuration change interrupt is not otherwise distinguished from a used buffer
+    /// notification, or periodically.
+    ///
+    /// If automatic resizing has been enabled with [`VirtIOGpu::set_auto_resize`], the framebuffer
+    /// of each resized scanout is replaced with one of the new size, and the framebuffer of each
+    /// removed scanout is released. The new framebuffer can be fetched with
+    /// [`Scanout::framebuffer`], and must be redrawn.
+    pub fn poll_display_events(&mut self) -> Result<Vec<DisplayEvent>> {
+        // Safe because config_space is a valid pointer to the device configuration space.
+        let events_read = unsafe { volread!(self.config_space, events_read) };
+        if events_read & EVENT_DISPLAY == 0 {
+            return Ok(Vec::new());
+        }
+        // Safe because config_space is a valid pointer to the device configuration space.
+        unsafe { volwrite!(self.config_space, events_clear, EVENT_DISPLAY) };
+
+        let old_displays = self.displays.clone();
+        self.get_display_info()?;
+        let mut events = Vec::new();
+        for (id, (old, new)) in old_displays.iter().zip(self.displays.clone()).enumerate() {
+            let id = id as u32;
+            let event = match (old.enabled, new.enabled) {
+                (false, true) => DisplayEvent::Added(id),
+                (true, false) => DisplayEvent::Removed(id),
+                (true, true)
+                    if (old.rect.width, old.rect.height) != (new.rect.width, new.rect.height) =>
+                {
+                    DisplayEvent::Resized {
+                        id,
+                        width: new.rect.width,
+                        height: new.rect.height,
+                    }
+                }
+                _ => continue,
+            };
+            if self.auto_resize && self.framebuffers[id as usize].is_some() {
+                match event {
+                    DisplayEvent::Resized { .. } => {
+                        let format = self.framebuffer_info(id).unwrap().format;
+                        self.setup_scanout_framebuffer(id, format)?;
+                    }
+                    DisplayEvent::Removed(_) => self.release_scanout_framebuffer(id)?,
+                    DisplayEvent::Added(_) => {}
+                }
+            }
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    /// Sets whether [`VirtIOGpu::poll_display_events`] should recreate framebuffers automatically
+    /// when their scanouts are resized.
+    pub fn set_auto_resize(&mut self, auto_resize: bool) {
+        self.auto_resize = auto_resize;
+    }
+
+    /// Returns a handle to the scanout with the given ID, or `None` if the device doesn't have
+    /// such a scanout.
+    pub fn scanout(&mut self, id: u32) -> Option<Scanout<'_, H, T>> {
+        if (id as usize) < self.framebuffers.len() {
+            Some(Scanout { gpu: self, id })
+        } else {
+            None
+        }
+    }
+
+    /// Fetches the raw EDID blob of the display attached to the given scanout.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_GPU_F_EDID`. Use
+    /// [`Edid::parse`] to extract the display's modes and other information from the blob.
+    pub fn edid(&mut self, scanout_id: u32) -> Result<Vec<u8>> {
+        if !self.negotiated_features.contains(Features::EDID) {
+            return Err(Error::Unsupported);
+        }
+        if scanout_id as usize >= self.framebuffers.len() {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespEdid = self.request(GetEdid {
+            header: CtrlHeader::with_type(Command::GET_EDID),
+            scanout: scanout_id,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_EDID)?;
+        let size = min(rsp.size as usize, rsp.edid.len());
+        Ok(rsp.edid[..size].to_vec())
+    }
+
+    /// Get the resolution (width, height) of the first scanout.
+    pub fn resolution(&mut self) -> Result<(u32, u32)> {
+        let display_info = self.get_display_info()?;
+        let rect = display_info.pmodes[SCANOUT_ID as usize].rect;
+        Ok((rect.width, rect.height))
+    }
+
+    /// Setup framebuffer with the given pixel format for the first scanout.
+    ///
+    /// Returns the framebuffer along with its layout. Pixels are stored line by line, with
+    /// `stride` bytes from the start of one line to the start of the next.
+    pub fn setup_framebuffer(&mut self, format: Format) -> Result<(&mut [u8], FramebufferInfo)> {
+        self.setup_scanout_framebuffer(SCANOUT_ID, format)
+    }
+
+    /// Flush framebuffer of the first scanout to screen.
+    pub fn flush(&mut self) -> Result {
+        self.flush_scanout(SCANOUT_ID)
+    }
+
+    /// Flushes the given rectangle of the framebuffer of the first scanout to the screen.
+    pub fn flush_rect(&mut self, rect: Rect) -> Result {
+        self.flush_scanout_rect(SCANOUT_ID, rect)
+    }
+
+    /// Records that the given rectangle of the framebuffer of the first scanout has been drawn
+    /// to, so that it is flushed by the next call to [`VirtIOGpu::flush_damage`].
+    pub fn add_damage(&mut self, rect: Rect) -> Result {
+        self.add_scanout_damage(SCANOUT_ID, rect)
+    }
+
+    /// Flushes all the regions of the framebuffer of the first scanout which have been recorded
+    /// with [`VirtIOGpu::add_damage`] to the screen.
+    pub fn flush_damage(&mut self) -> Result {
+        self.flush_scanout_damage(SCANOUT_ID)
+    }
+
+    /// Creates a framebuffer of the given format matching the current resolution of the given
+    /// scanout and attaches it to the scanout, replacing any framebuffer previously attached to
+    /// it.
+    fn setup_scanout_framebuffer(
+        &mut self,
+        scanout_id: u32,
+        format: Format,
+    ) -> Result<(&mut [u8], FramebufferInfo)> {
+        // get display info
+        let display_info = self.get_display_info()?;
+        let display = display_info
+            .pmodes
+            .get(scanout_id as usize)
+            .ok_or(Error::InvalidParam)?;
+        info!("scanout {} => {:?}", scanout_id, display);
+        if display.enabled == 0 || display.rect.width == 0 || display.rect.height == 0 {
+            return Err(Error::NotReady);
+        }
+        // The framebuffer covers the whole scanout, but its own coordinates start from 0.
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: display.rect.width,
+            height: display.rect.height,
+        };
+
+        self.release_scanout_framebuffer(scanout_id)?;
+
+        // Create the resource along with continuous pages for the frame buffer. If possible, share
+        // the pages with the host so that they don't need to be copied on every flush.
+        let use_blob = self.negotiated_features.contains(Features::RESOURCE_BLOB);
+        let resource_id = if use_blob {
+            self.create_blob_resource_2d(format, rect.width, rect.height)?
+        } else {
+            self.create_resource_2d_with_backing(format, rect.width, rect.height)?
+        };
+
+        // map frame buffer to screen
+        let result = if use_blob {
+            self.set_scanout_blob(scanout_id, resource_id, rect)
+        } else {
+            self.set_scanout(rect, scanout_id, resource_id.0)
+        };
+        if let Err(e) = result {
+            self.unref_resource(resource_id)?;
+            return Err(e);
+        }
+
+        self.framebuffers[scanout_id as usize] = Some(Framebuffer {
+            resource_id,
+            rect,
+            damage: DamageTracker::default(),
+        });
+        let info = self.framebuffer_info(scanout_id).unwrap();
+        Ok((self.resource_buffer(resource_id).unwrap(), info))
+    }
+
+    /// Returns the layout of the framebuffer attached to the given scanout, if any.
+    fn framebuffer_info(&self, scanout_id: u32) -> Option<FramebufferInfo> {
+        let framebuffer = self.framebuffers.get(scanout_id as usize)?.as_ref()?;
+        let resource = &self.resources[&framebuffer.resource_id];
+        Some(FramebufferInfo {
+            format: resource.format?,
+            width: resource.width,
+            height: resource.height,
+            stride: resource.stride()?,
+        })
+    }
+
+    /// Detaches the framebuffer from the given scanout, if it has one, and frees it.
+    fn release_scanout_framebuffer(&mut self, scanout_id: u32) -> Result {
+        if let Some(framebuffer) = self.framebuffers[scanout_id as usize].take() {
+            self.set_scanout(Rect::default(), scanout_id, 0)?;
+            self.unref_resource(framebuffer.resource_id)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the whole framebuffer of the given scanout to the host and flushes it to the screen.
+    fn flush_scanout(&mut self, scanout_id: u32) -> Result {
+        let framebuffer = self.framebuffer_mut(scanout_id)?;
+        // Everything is about to be flushed, so there is no need to flush the damage separately.
+        framebuffer.damage.clear();
+        let (rect, resource_id) = (framebuffer.rect, framebuffer.resource_id);
+        // copy data from guest to host, unless the host already shares it
+        if !self.resources[&resource_id].is_blob() {
+            self.transfer_to_host_2d(rect, 0, resource_id.0)?;
+        }
+        // flush data to screen
+        self.resource_flush(rect, resource_id.0)?;
+        Ok(())
+    }
+
+    /// Copies the given rectangle of the framebuffer of the given scanout to the host and flushes
+    /// it to the screen. The rectangle is clipped to the framebuffer.
+    fn flush_scanout_rect(&mut self, scanout_id: u32, rect: Rect) -> Result {
+        let framebuffer = self.framebuffer_mut(scanout_id)?;
+        let resource_id = framebuffer.resource_id;
+        if let Some(rect) = rect.intersection(&framebuffer.rect) {
+            if !self.resources[&resource_id].is_blob() {
+                self.transfer_to_host(resource_id, rect)?;
+            }
+            self.flush_resource(resource_id, rect)?;
+        }
+        Ok(())
+    }
+
+    /// Records damage to the framebuffer of the given scanout.
+    fn add_scanout_damage(&mut self, scanout_id: u32, rect: Rect) -> Result {
+        let framebuffer = self.framebuffer_mut(scanout_id)?;
+        if let Some(rect) = rect.intersection(&framebuffer.rect) {
+            framebuffer.damage.add(rect);
+        }
+        Ok(())
+    }
+
+    /// Flushes the damaged regions of the framebuffer of the given scanout, with one transfer and
+    /// one flush for each separate region.
+    fn flush_scanout_damage(&mut self, scanout_id: u32) -> Result {
+        for rect in self.framebuffer_mut(scanout_id)?.damage.take() {
+            self.flush_scanout_rect(scanout_id, rect)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the framebuffer attached to the given scanout, or `Error::NotReady` if there isn't
+    /// one.
+    fn framebuffer_mut(&mut self, scanout_id: u32) -> Result<&mut Framebuffer> {
+        self.framebuffers
+            .get_mut(scanout_id as usize)
+            .and_then(Option::as_mut)
+            .ok_or(Error::NotReady)
+    }
+
+    /// Send a request to the device and block for a response.
+    fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
+        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
+        // Move the buffers out temporarily so they can be borrowed separately from `self`.
+        let send = mem::take(&mut self.queue_buf_send);
+        let mut recv = mem::take(&mut self.queue_buf_recv);
+        let result = self.request_raw(&send, &mut recv);
+        self.queue_buf_send = send;
+        self.queue_buf_recv = recv;
+        result?;
+        Ok(Rsp::read_from_prefix(&*self.queue_buf_recv).unwrap())
+    }
+
+    /// Send a request followed by some additional data to the device and block for a response.
+    fn request_with_data<Req: AsBytes, Rsp: FromBytes>(
+        &mut self,
+        req: Req,
+        data: &[u8],
+    ) -> Result<Rsp> {
+        let data_start = size_of::<Req>();
+        self.queue_buf_send
+            .get_mut(data_start..data_start + data.len())
+            .ok_or(Error::InvalidParam)?
+            .copy_from_slice(data);
+        self.request(req)
+    }
+
+    /// Send a request which may be bigger than the send buffer to the device, and block for a
+    /// response which fits in the given buffer.
+    ///
+    /// Any fenced commands which complete in the meantime are reclaimed.
+    fn request_raw(&mut self, req: &[u8], rsp: &mut [u8]) -> Result {
+        self.wait_for_descriptors(2)?;
+        // Safe because the buffers remain valid and unaccessed until the request is popped
+        // below.
+        let token = unsafe { self.control_queue.add(&[req], &mut [rsp]) }?;
+        if self.control_queue.should_notify() {
+            self.transport.notify(QUEUE_TRANSMIT);
+        }
+        // This mustn't return until the request has been popped, as the device may otherwise write
+        // to the buffers after they are no longer borrowed. If a used entry can't be reclaimed it
+        // is skipped, and the error reported once this request has been popped.
+        let mut result = Ok(());
+        loop {
+            match self.control_queue.peek_used() {
+                Some(used) if used == token => {
+                    // Safe because these are the same buffers as we passed to `add` above.
+                    unsafe { self.control_queue.pop_used(token, &[req], &mut [rsp]) }?;
+                    return result;
+                }
+                Some(used) => {
+                    if let Err(e) = self.reclaim_fenced(used) {
+                        warn!("Skipping used control queue entry {}: {}", used, e);
+                        self.control_queue.skip_used();
+                        result = Err(e);
+                    }
+                }
+                None => spin_loop(),
+            }
+        }
+    }
+
+    /// Send a mouse cursor operation request to the device and block for a response.
+    fn cursor_request<Req: AsBytes>(&mut self, req: Req) -> Result {
+        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
+        self.cursor_queue.add_notify_wait_pop(
+            &[&self.queue_buf_send],
+            &mut [],
+            &mut self.transport,
+        )?;
+        Ok(())
+    }
+
+    /// Queries the display configuration from the device, and remembers it so that
+    /// [`VirtIOGpu::poll_display_events`] only reports later changes.
+    fn get_display_info(&mut self) -> Result<RespDisplayInfo> {
+        let info: RespDisplayInfo =
+            self.request(CtrlHeader::with_type(Command::GET_DISPLAY_INFO))?;
+        info.header.check_type(Command::OK_DISPLAY_INFO)?;
+        for (display, pmode) in self.displays.iter_mut().zip(info.pmodes.iter()) {
+            *display = DisplayInfo::from(pmode);
+        }
+        Ok(info)
+    }
+
+    fn resource_create_2d(
+        &mut self,
+        resource_id: u32,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceCreate2D {
+            header: CtrlHeader::with_type(Command::RESOURCE_CREATE_2D),
+            resource_id,
+            format,
+            width,
+            height,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_unref(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceUnref {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNREF),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(SetScanout {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT),
+            rect,
+            scanout_id,
+            resource_id,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_flush(&mut self, rect: Rect, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceFlush {
+            header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
+            rect,
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn transfer_to_host_2d(&mut self, rect: Rect, offset: u64, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(TransferToHost2D {
+            header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_attach_backing(&mut self, resource_id: u32, entries: &[MemEntry]) -> Result {
+        let rsp: CtrlHeader = self.request_with_data(
+            ResourceAttachBacking {
+                header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
+                resource_id,
+                nr_entries: entries.len() as u32,
+            },
+            entries.as_bytes(),
+        )?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_detach_backing(&mut self, resource_id: u32) -> Result {
+        let rsp: CtrlHeader = self.request(ResourceDetachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_DETACH_BACKING),
+            resource_id,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn update_cursor(
+        &mut self,
+        resource_id: u32,
+        scanout_id: u32,
+        pos_x: u32,
+        pos_y: u32,
+        hot_x: u32,
+        hot_y: u32,
+        is_move: bool,
+    ) -> Result {
+        self.cursor_request(UpdateCursor {
+            header: if is_move {
+                CtrlHeader::with_type(Command::MOVE_CURSOR)
+            } else {
+                CtrlHeader::with_type(Command::UPDATE_CURSOR)
+            },
+            pos: CursorPos {
+                scanout_id,
+                x: pos_x,
+                y: pos_y,
+                _padding: 0,
+            },
+            resource_id,
+            hot_x,
+            hot_y,
+            _padding: 0,
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        self.transport.queue_unset(QUEUE_CURSOR);
+    }
+}
+
+#[repr(C)]
+struct Config {
+    /// Signals pending events to the driver。
+    events_read: ReadOnly<u32>,
+
+    /// Clears pending events in the device.
+    events_clear: WriteOnly<u32>,
+
+    /// Specifies the maximum number of scanouts supported by the device.
+    ///
+    /// Minimum value is 1, maximum value is 16.
+    num_scanouts: Volatile<u32>,
+
+    /// Specifies the maximum number of capability sets supported by the device.
+    num_capsets: ReadOnly<u32>,
+}
+
+/// Display configuration has changed.
+const EVENT_DISPLAY: u32 = 1 << 0;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Features: u64 {
+        /// virgl 3D mode is supported.
+        const VIRGL                 = 1 << 0;
+        /// EDID is supported.
+        const EDID                  = 1 << 1;
+        /// Assigning resources UUIDs for export to other virtio devices is supported.
+        const RESOURCE_UUID         = 1 << 2;
+        /// Creating and using size-based blob resources is supported.
+        const RESOURCE_BLOB         = 1 << 3;
+        /// Multiple context types and synchronization timelines are supported.
+        const CONTEXT_INIT          = 1 << 4;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq, FromBytes)]
+struct Command(u32);
+
+impl Command {
+    const GET_DISPLAY_INFO: Command = Command(0x100);
+    const RESOURCE_CREATE_2D: Command = Command(0x101);
+    const RESOURCE_UNREF: Command = Command(0x102);
+    const SET_SCANOUT: Command = Command(0x103);
+    const RESOURCE_FLUSH: Command = Command(0x104);
+    const TRANSFER_TO_HOST_2D: Command = Command(0x105);
+    const RESOURCE_ATTACH_BACKING: Command = Command(0x106);
+    const RESOURCE_DETACH_BACKING: Command = Command(0x107);
+    const GET_CAPSET_INFO: Command = Command(0x108);
+    const GET_CAPSET: Command = Command(0x109);
+    const GET_EDID: Command = Command(0x10a);
+    const RESOURCE_ASSIGN_UUID: Command = Command(0x10b);
+    const RESOURCE_CREATE_BLOB: Command = Command(0x10c);
+    const SET_SCANOUT_BLOB: Command = Command(0x10d);
+
+    const CTX_CREATE: Command = Command(0x200);
+    const CTX_DESTROY: Command = Command(0x201);
+    const CTX_ATTACH_RESOURCE: Command = Command(0x202);
+    const CTX_DETACH_RESOURCE: Command = Command(0x203);
+    const RESOURCE_CREATE_3D: Command = Command(0x204);
+    const TRANSFER_TO_HOST_3D: Command = Command(0x205);
+    const TRANSFER_FROM_HOST_3D: Command = Command(0x206);
+    const SUBMIT_3D: Command = Command(0x207);
+    const RESOURCE_MAP_BLOB: Command = Command(0x208);
+    const RESOURCE_UNMAP_BLOB: Command = Command(0x209);
+
+    const UPDATE_CURSOR: Command = Command(0x300);
+    const MOVE_CURSOR: Command = Command(0x301);
+
+    const OK_NODATA: Command = Command(0x1100);
+    const OK_DISPLAY_INFO: Command = Command(0x1101);
+    const OK_CAPSET_INFO: Command = Command(0x1102);
+    const OK_CAPSET: Command = Command(0x1103);
+    const OK_EDID: Command = Command(0x1104);
+    const OK_RESOURCE_UUID: Command = Command(0x1105);
+    const OK_MAP_INFO: Command = Command(0x1106);
+
+    const ERR_UNSPEC: Command = Command(0x1200);
+    const ERR_OUT_OF_MEMORY: Command = Command(0x1201);
+    const ERR_INVALID_SCANOUT_ID: Command = Command(0x1202);
+}
+
+const GPU_FLAG_FENCE: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy, FromBytes)]
+struct CtrlHeader {
+    hdr_type: Command,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    _padding: u32,
+}
+
+impl CtrlHeader {
+    fn with_type(hdr_type: Command) -> CtrlHeader {
+        Self::with_context(hdr_type, 0)
+    }
+
+    /// Creates a header for a command in the given 3D context.
+    fn with_context(hdr_type: Command, ctx_id: u32) -> CtrlHeader {
+        CtrlHeader {
+            hdr_type,
+            flags: 0,
+            fence_id: 0,
+            ctx_id,
+            _padding: 0,
+        }
+    }
+
+    /// Return error if the type is not same as expected.
+    fn check_type(&self, expected: Command) -> Result {
+        if self.hdr_type == expected {
+            Ok(())
+        } else {
+            Err(Error::IoError)
+        }
+    }
+}
+
+/// A rectangle in the coordinate space of a scanout or resource.
+#[repr(C)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, PartialEq)]
+pub struct Rect {
+    /// The X coordinate of the left edge.
+    pub x: u32,
+    /// The Y coordinate of the top edge.
+    pub y: u32,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+}
+
+impl Rect {
+    /// Creates a rectangle with the given top-left corner and size.
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Returns whether the rectangle doesn't contain any pixels.
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns the smallest rectangle containing both this rectangle and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Returns the part of this rectangle which is also in `other`, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        let rect = Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y));
+        if rect.is_empty() {
+            None
+        } else {
+            Some(rect)
+        }
+    }
+
+    /// Returns whether this rectangle overlaps or shares an edge with `other`.
+    fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+
+    fn area(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    fn right(&self) -> u32 {
+        self.x.saturating_add(self.width)
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y.saturating_add(self.height)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, FromBytes)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct GetEdid {
+    header: CtrlHeader,
+    scanout: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes)]
+struct RespEdid {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+    edid: [u8; 1024],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceCreate2D {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: Format,
+    width: u32,
+    height: u32,
+}
+
+/// The pixel format of a resource.
+///
+/// The names give the order of the components in memory, from the lowest address.
+#[repr(u32)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Blue, green, red, alpha.
+    B8G8R8A8UNORM = 1,
+    /// Blue, green, red, unused.
+    B8G8R8X8UNORM = 2,
+    /// Alpha, red, green, blue.
+    A8R8G8B8UNORM = 3,
+    /// Unused, red, green, blue.
+    X8R8G8B8UNORM = 4,
+    /// Red, green, blue, alpha.
+    R8G8B8A8UNORM = 67,
+    /// Unused, blue, green, red.
+    X8B8G8R8UNORM = 68,
+    /// Alpha, blue, green, red.
+    A8B8G8R8UNORM = 121,
+    /// Red, green, blue, unused.
+    R8G8B8X8UNORM = 134,
+}
+
+impl TryFrom<u32> for Format {
+    type Error = Error;
+
+    /// Converts a `VIRTIO_GPU_FORMAT_*` value to the corresponding format, or returns
+    /// [`Error::InvalidParam`] if it isn't one of the formats defined by the VirtIO spec.
+    fn try_from(format: u32) -> Result<Self> {
+        match format {
+            1 => Ok(Self::B8G8R8A8UNORM),
+            2 => Ok(Self::B8G8R8X8UNORM),
+            3 => Ok(Self::A8R8G8B8UNORM),
+            4 => Ok(Self::X8R8G8B8UNORM),
+            67 => Ok(Self::R8G8B8A8UNORM),
+            68 => Ok(Self::X8B8G8R8UNORM),
+            121 => Ok(Self::A8B8G8R8UNORM),
+            134 => Ok(Self::R8G8B8X8UNORM),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+}
+
+impl Format {
+    /// Returns the number of bytes used to store each pixel.
+    pub fn bytes_per_pixel(self) -> u32 {
+        4
+    }
+}
+
+/// The header of a `RESOURCE_ATTACH_BACKING` command, which is followed by `nr_entries`
+/// [`MemEntry`]s.
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceAttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceDetachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceUnref {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct TransferToHost2D {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy, FromBytes)]
+struct CursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Clone, Copy, FromBytes)]
+struct UpdateCursor {
+    header: CtrlHeader,
+    pos: CursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    _padding: u32,
+}
+
+/// The ID of the shared memory region into which host-visible blob resources are mapped.
+const SHM_ID_HOST_VISIBLE: u8 = 1;
+
+const QUEUE_TRANSMIT: u16 = 0;
+const QUEUE_CURSOR: u16 = 1;
+
+const SCANOUT_ID: u32 = 0;
+
+const CURSOR_RECT: Rect = Rect {
+    x: 0,
+    y: 0,
+    width: 64,
+    height: 64,
+};
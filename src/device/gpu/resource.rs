@@ -0,0 +1,550 @@
+//! Management of the resources from which a VirtIO GPU device displays images.
+
+use super::blob::Blob;
+use super::{Format, Rect, VirtIOGpu};
+use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
+use crate::transport::Transport;
+use crate::{pages, Error, Result};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The ID of a resource which the driver has created on the device.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ResourceId(pub(crate) u32);
+
+impl From<ResourceId> for u32 {
+    fn from(id: ResourceId) -> u32 {
+        id.0
+    }
+}
+
+/// One entry of the scatter-gather list of guest memory backing a resource.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, FromBytes, PartialEq)]
+pub struct MemEntry {
+    addr: u64,
+    length: u32,
+    _padding: u32,
+}
+
+impl MemEntry {
+    /// Creates an entry for the given region of guest physical memory.
+    pub fn new(addr: PhysAddr, length: u32) -> Self {
+        Self {
+            addr: addr as u64,
+            length,
+            _padding: 0,
+        }
+    }
+}
+
+/// What the driver knows about a resource which it has created.
+pub(crate) struct Resource<H: Hal> {
+    /// The format of a 2D resource, or `None` for other kinds of resource.
+    pub(crate) format: Option<Format>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) backing: Backing<H>,
+    /// Details of a blob resource, or `None` if it was created in some other way.
+    pub(crate) blob: Option<Blob>,
+}
+
+impl<H: Hal> Resource<H> {
+    /// Returns the number of bytes in each line of a 2D resource.
+    pub(crate) fn stride(&self) -> Option<u32> {
+        Some(self.width * self.format?.bytes_per_pixel())
+    }
+
+    /// Returns whether the resource is a blob resource, whose memory is shared with the host so
+    /// doesn't need to be transferred.
+    pub(crate) fn is_blob(&self) -> bool {
+        self.blob.is_some()
+    }
+
+    /// Returns whether the given rectangle lies entirely within the resource.
+    pub(crate) fn contains(&self, rect: &Rect) -> bool {
+        u64::from(rect.x) + u64::from(rect.width) <= u64::from(self.width)
+            && u64::from(rect.y) + u64::from(rect.height) <= u64::from(self.height)
+    }
+}
+
+/// The guest memory attached to a resource.
+pub(crate) enum Backing<H: Hal> {
+    /// No memory is attached.
+    Detached,
+    /// The memory was allocated by the driver, and will be freed when it is detached.
+    Owned {
+        dma: Dma<H>,
+        /// The number of bytes attached, which may be less than the size of the DMA region.
+        len: usize,
+    },
+    /// The memory was provided by the caller of [`VirtIOGpu::attach_backing`].
+    External,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Creates a 2D resource of the given format and size on the device, without any backing
+    /// memory.
+    pub fn create_resource_2d(
+        &mut self,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<ResourceId> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let id = self.alloc_resource_id();
+        self.resource_create_2d(id.0, format, width, height)?;
+        self.resources.insert(
+            id,
+            Resource {
+                format: Some(format),
+                width,
+                height,
+                backing: Backing::Detached,
+                blob: None,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Creates a 2D resource of the given format and size on the device, and attaches a buffer
+    /// allocated by the driver to it.
+    ///
+    /// The buffer can be accessed with [`VirtIOGpu::resource_buffer`], and is freed when the
+    /// backing is detached or the resource is unreferenced.
+    pub fn create_resource_2d_with_backing(
+        &mut self,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<ResourceId> {
+        let id = self.create_resource_2d(format, width, height)?;
+        let len = format.bytes_per_pixel() as usize * width as usize * height as usize;
+        if let Err(e) = self.alloc_backing(id, len) {
+            self.unref_resource(id)?;
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Allocates a buffer of the given length, and attaches it to the resource as its backing.
+    ///
+    /// The buffer can be accessed with [`VirtIOGpu::resource_buffer`], and is freed when the
+    /// backing is detached or the resource is unreferenced.
+    pub fn alloc_backing(&mut self, id: ResourceId, len: usize) -> Result {
+        if len == 0 || len > u32::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        let dma = Dma::new(pages(len), BufferDirection::Both)?;
+        let entry = MemEntry::new(dma.paddr(), len as u32);
+        // Safe because the DMA buffer will be kept in the resource until it is detached.
+        unsafe { self.attach_backing(id, &[entry]) }?;
+        self.resources.get_mut(&id).unwrap().backing = Backing::Owned { dma, len };
+        Ok(())
+    }
+
+    /// Returns the buffer which the driver allocated for the given resource, if it was allocated
+    /// by [`VirtIOGpu::alloc_backing`] or [`VirtIOGpu::create_resource_2d_with_backing`] and is
+    /// still attached.
+    ///
+    /// For 2D resources, pixels are stored line by line, with no padding between lines.
+    pub fn resource_buffer(&mut self, id: ResourceId) -> Option<&mut [u8]> {
+        match &mut self.resources.get_mut(&id)?.backing {
+            // Safe because the DMA region is owned by the resource, and we have a unique reference
+            // to it.
+            Backing::Owned { dma, len } => Some(unsafe { &mut dma.raw_slice().as_mut()[..*len] }),
+            _ => None,
+        }
+    }
+
+    /// Attaches the given scatter-gather list of guest memory to the resource as its backing.
+    ///
+    /// Returns [`Error::InvalidParam`] if the resource doesn't exist or already has backing
+    /// memory attached.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for DMA, must not be otherwise accessed while the device may be
+    /// transferring to or from it, and must remain valid until the backing is detached with
+    /// [`VirtIOGpu::detach_backing`] or the resource is unreferenced.
+    pub unsafe fn attach_backing(&mut self, id: ResourceId, entries: &[MemEntry]) -> Result {
+        let resource = self.resources.get_mut(&id).ok_or(Error::InvalidParam)?;
+        if !matches!(resource.backing, Backing::Detached) || entries.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        self.resource_attach_backing(id.0, entries)?;
+        self.resources.get_mut(&id).unwrap().backing = Backing::External;
+        Ok(())
+    }
+
+    /// Detaches the backing memory from the given resource, freeing it if it was allocated by the
+    /// driver.
+    pub fn detach_backing(&mut self, id: ResourceId) -> Result {
+        let resource = self.resources.get(&id).ok_or(Error::InvalidParam)?;
+        if matches!(resource.backing, Backing::Detached) {
+            return Err(Error::InvalidParam);
+        }
+        self.resource_detach_backing(id.0)?;
+        self.resources.get_mut(&id).unwrap().backing = Backing::Detached;
+        Ok(())
+    }
+
+    /// Destroys the given resource on the device, freeing its backing memory if it was allocated
+    /// by the driver.
+    ///
+    /// Any scanout displaying the resource is disabled by the device.
+    pub fn unref_resource(&mut self, id: ResourceId) -> Result {
+        let resource = self.resources.get(&id).ok_or(Error::InvalidParam)?;
+        if matches!(&resource.blob, Some(blob) if blob.map_offset.is_some()) {
+            self.unmap_blob(id)?;
+        }
+        self.resource_unref(id.0)?;
+        self.resources.remove(&id);
+        for framebuffer in &mut self.framebuffers {
+            if matches!(framebuffer, Some(framebuffer) if framebuffer.resource_id == id) {
+                *framebuffer = None;
+            }
+        }
+        if self.cursor_resource == Some(id) {
+            self.cursor_resource = None;
+        }
+        if self.owned_cursor == Some(id) {
+            self.owned_cursor = None;
+        }
+        Ok(())
+    }
+
+    /// Copies the given rectangle of the 2D resource from its backing memory to the host.
+    pub fn transfer_to_host(&mut self, id: ResourceId, rect: Rect) -> Result {
+        let offset = self.transfer_offset(id, &rect)?;
+        self.transfer_to_host_2d(rect, offset, id.0)
+    }
+
+    /// Flushes the given rectangle of the resource to any scanouts which are displaying it.
+    pub fn flush_resource(&mut self, id: ResourceId, rect: Rect) -> Result {
+        self.check_rect(id, &rect)?;
+        self.resource_flush(rect, id.0)
+    }
+
+    /// Checks that the given rectangle lies within the given 2D resource, and returns the offset
+    /// of its top-left corner within the backing memory.
+    pub(crate) fn transfer_offset(&self, id: ResourceId, rect: &Rect) -> Result<u64> {
+        self.check_rect(id, rect)?;
+        let resource = &self.resources[&id];
+        let (Some(format), Some(stride)) = (resource.format, resource.stride()) else {
+            return Err(Error::InvalidParam);
+        };
+        Ok(u64::from(rect.y) * u64::from(stride)
+            + u64::from(rect.x) * u64::from(format.bytes_per_pixel()))
+    }
+
+    /// Returns [`Error::InvalidParam`] unless the given resource exists and contains the given
+    /// rectangle.
+    pub(crate) fn check_rect(&self, id: ResourceId, rect: &Rect) -> Result {
+        match self.resources.get(&id) {
+            Some(resource) if resource.contains(rect) => Ok(()),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Displays the given rectangle of the resource on the given scanout, or disables the scanout
+    /// if `id` is `None`.
+    ///
+    /// This can be used to flip between several resources. It doesn't change which framebuffer
+    /// [`VirtIOGpu::flush`] or [`Scanout::flush`](super::Scanout::flush) use.
+    pub fn set_scanout_resource(
+        &mut self,
+        scanout_id: u32,
+        id: Option<ResourceId>,
+        rect: Rect,
+    ) -> Result {
+        if scanout_id as usize >= self.framebuffers.len() {
+            return Err(Error::InvalidParam);
+        }
+        match id {
+            Some(id) => {
+                let resource = self.resources.get(&id).ok_or(Error::InvalidParam)?;
+                if !resource.contains(&rect) {
+                    return Err(Error::InvalidParam);
+                }
+                self.set_scanout(rect, scanout_id, id.0)
+            }
+            None => self.set_scanout(Rect::default(), scanout_id, 0),
+        }
+    }
+
+    /// Returns a resource ID which is not currently in use.
+    pub(crate) fn alloc_resource_id(&mut self) -> ResourceId {
+        loop {
+            let id = ResourceId(self.next_resource_id);
+            // Resource ID 0 is used to disable scanouts and the cursor.
+            self.next_resource_id = self.next_resource_id.checked_add(1).unwrap_or(1);
+            if !self.resources.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{
+            Command, Config, CtrlHeader, Features, ResourceAttachBacking, ResourceCreate2D,
+            ResourceDetachBacking, ResourceUnref, RespDisplayInfo, TransferToHost2D,
+            CONTROL_QUEUE_SIZE, QUEUE_CURSOR, QUEUE_SIZE, QUEUE_TRANSMIT,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+
+    /// Simulates the device handling the given number of control commands, returning the
+    /// requests which it received.
+    ///
+    /// `GET_DISPLAY_INFO` reports a single enabled 4x2 scanout, and all other commands succeed.
+    fn handle_control_commands(state: Arc<Mutex<State>>, count: usize) -> Vec<Vec<u8>> {
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                    let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                    requests.push(request);
+                    if header.hdr_type == Command::GET_DISPLAY_INFO {
+                        let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                        CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                            .write_to_prefix(&mut response[..])
+                            .unwrap();
+                        let pmode = &mut response[size_of::<CtrlHeader>()..];
+                        Rect::new(0, 0, 4, 2).write_to_prefix(&mut *pmode).unwrap();
+                        // Enabled.
+                        pmode[size_of::<Rect>()] = 1;
+                        response
+                    } else {
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    }
+                });
+        }
+        requests
+    }
+
+    #[test]
+    fn resource_lifecycle() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let handle = thread::spawn(move || handle_control_commands(state, 6));
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(
+            gpu.create_resource_2d(Format::B8G8R8A8UNORM, 0, 2),
+            Err(Error::InvalidParam)
+        );
+        let id = gpu.create_resource_2d(Format::B8G8R8A8UNORM, 4, 2).unwrap();
+
+        let entries = [MemEntry::new(0x1000, 16), MemEntry::new(0x3000, 16)];
+        // Safe because the fake device never accesses the backing memory.
+        unsafe {
+            assert_eq!(gpu.attach_backing(id, &[]), Err(Error::InvalidParam));
+            gpu.attach_backing(id, &entries).unwrap();
+            assert_eq!(gpu.attach_backing(id, &entries), Err(Error::InvalidParam));
+            assert_eq!(
+                gpu.attach_backing(ResourceId(42), &entries),
+                Err(Error::InvalidParam)
+            );
+        }
+        assert_eq!(gpu.resource_buffer(id), None);
+        gpu.detach_backing(id).unwrap();
+        assert_eq!(gpu.detach_backing(id), Err(Error::InvalidParam));
+
+        assert_eq!(gpu.alloc_backing(id, 0), Err(Error::InvalidParam));
+        gpu.alloc_backing(id, 32).unwrap();
+        assert_eq!(gpu.resource_buffer(id).unwrap().len(), 32);
+
+        assert_eq!(gpu.transfer_offset(id, &Rect::new(1, 1, 2, 1)), Ok(20));
+        assert_eq!(
+            gpu.transfer_to_host(id, Rect::new(3, 0, 2, 1)),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            gpu.flush_resource(id, Rect::new(0, 1, 1, 2)),
+            Err(Error::InvalidParam)
+        );
+        gpu.transfer_to_host(id, Rect::new(1, 1, 2, 1)).unwrap();
+
+        gpu.unref_resource(id).unwrap();
+        assert_eq!(gpu.unref_resource(id), Err(Error::InvalidParam));
+        assert_eq!(gpu.resource_buffer(id), None);
+
+        let requests = handle.join().unwrap();
+        assert_eq!(requests.len(), 6);
+        let expected_create = ResourceCreate2D {
+            header: CtrlHeader::with_type(Command::RESOURCE_CREATE_2D),
+            resource_id: id.0,
+            format: Format::B8G8R8A8UNORM,
+            width: 4,
+            height: 2,
+        };
+        assert!(requests[0].starts_with(expected_create.as_bytes()));
+        let expected_attach = ResourceAttachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
+            resource_id: id.0,
+            nr_entries: 2,
+        };
+        assert!(requests[1].starts_with(&[expected_attach.as_bytes(), entries.as_bytes()].concat()));
+        let expected_detach = ResourceDetachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_DETACH_BACKING),
+            resource_id: id.0,
+            _padding: 0,
+        };
+        assert!(requests[2].starts_with(expected_detach.as_bytes()));
+        let expected_attach = ResourceAttachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
+            resource_id: id.0,
+            nr_entries: 1,
+        };
+        assert!(requests[3].starts_with(expected_attach.as_bytes()));
+        let entry =
+            MemEntry::read_from_prefix(&requests[3][size_of::<ResourceAttachBacking>()..]).unwrap();
+        assert_eq!(entry.length, 32);
+        let expected_transfer = TransferToHost2D {
+            header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
+            rect: Rect::new(1, 1, 2, 1),
+            offset: 20,
+            resource_id: id.0,
+            _padding: 0,
+        };
+        assert!(requests[4].starts_with(expected_transfer.as_bytes()));
+        let expected_unref = ResourceUnref {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNREF),
+            resource_id: id.0,
+            _padding: 0,
+        };
+        assert!(requests[5].starts_with(expected_unref.as_bytes()));
+    }
+
+    #[test]
+    fn unref_clears_framebuffer_and_cursor() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        // Set up the framebuffer, create the cursor, then unref both.
+        let control_state = state.clone();
+        let control = thread::spawn(move || handle_control_commands(control_state, 9));
+        let cursor = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CURSOR);
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<{ QUEUE_SIZE as usize }>(QUEUE_CURSOR);
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        gpu.setup_framebuffer(Format::B8G8R8A8UNORM).unwrap();
+        let framebuffer_id = gpu.framebuffers[0].as_ref().unwrap().resource_id;
+        let image = [0xff; 8];
+        let cursor_image = gpu
+            .create_cursor(&image, Format::R8G8B8A8UNORM, 2, 1, 0, 0)
+            .unwrap();
+        gpu.show_cursor(0, &cursor_image, 1, 1).unwrap();
+        cursor.join().unwrap();
+        assert_eq!(gpu.cursor_resource, Some(cursor_image.resource_id()));
+
+        gpu.unref_resource(framebuffer_id).unwrap();
+        assert!(gpu.framebuffers[0].is_none());
+        gpu.unref_resource(cursor_image.resource_id()).unwrap();
+        assert_eq!(gpu.cursor_resource, None);
+
+        let requests = control.join().unwrap();
+        let unref_header = CtrlHeader::with_type(Command::RESOURCE_UNREF);
+        assert!(requests[7].starts_with(unref_header.as_bytes()));
+        assert!(requests[8].starts_with(unref_header.as_bytes()));
+    }
+
+    #[test]
+    fn resource_id_wraps() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        gpu.next_resource_id = u32::MAX;
+        gpu.resources.insert(
+            ResourceId(1),
+            Resource {
+                format: None,
+                width: 1,
+                height: 1,
+                backing: Backing::Detached,
+                blob: None,
+            },
+        );
+        assert_eq!(gpu.alloc_resource_id(), ResourceId(u32::MAX));
+        // 0 is reserved and 1 is in use.
+        assert_eq!(gpu.alloc_resource_id(), ResourceId(2));
+    }
+}
@@ -0,0 +1,339 @@
+//! Per-scanout state and handles for VirtIO GPU devices.
+
+use super::{DamageTracker, DisplayOne, Edid, Format, Rect, ResourceId, VirtIOGpu};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Result;
+use alloc::vec::Vec;
+
+/// The configuration of a single scanout, as reported by the device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DisplayInfo {
+    /// The position and size of the display. The position is relative to the other displays, and
+    /// is only a hint of how the host has arranged them.
+    pub rect: Rect,
+    /// Whether the display is enabled, i.e. the user has opened a window for it on the host.
+    pub enabled: bool,
+    /// Flags reported by the device. No flags are currently defined.
+    pub flags: u32,
+}
+
+impl From<&DisplayOne> for DisplayInfo {
+    fn from(display: &DisplayOne) -> Self {
+        Self {
+            rect: display.rect,
+            enabled: display.enabled != 0,
+            flags: display.flags,
+        }
+    }
+}
+
+/// A change to the display configuration reported by [`VirtIOGpu::poll_display_events`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisplayEvent {
+    /// The scanout with the given ID has been enabled.
+    Added(u32),
+    /// The scanout with the given ID has been disabled.
+    Removed(u32),
+    /// The scanout has remained enabled but its resolution has changed.
+    Resized {
+        /// The ID of the scanout.
+        id: u32,
+        /// The new width in pixels.
+        width: u32,
+        /// The new height in pixels.
+        height: u32,
+    },
+}
+
+/// The layout of a framebuffer which is attached to a scanout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FramebufferInfo {
+    /// The pixel format.
+    pub format: Format,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The number of bytes from the start of one line to the start of the next.
+    pub stride: u32,
+}
+
+/// A framebuffer which is attached to a scanout.
+#[derive(Clone, Debug)]
+pub(crate) struct Framebuffer {
+    /// The resource which holds the framebuffer, with backing allocated by the driver.
+    pub(crate) resource_id: ResourceId,
+    /// The size of the framebuffer, with the origin at 0.
+    pub(crate) rect: Rect,
+    /// The regions which have been drawn to but not yet flushed.
+    pub(crate) damage: DamageTracker,
+}
+
+/// A handle to a single scanout (aka head or display) of a [`VirtIOGpu`].
+///
+/// This is obtained from [`VirtIOGpu::scanout`], and borrows the device for as long as it is used.
+pub struct Scanout<'a, H: Hal, T: Transport> {
+    pub(crate) gpu: &'a mut VirtIOGpu<H, T>,
+    pub(crate) id: u32,
+}
+
+impl<H: Hal, T: Transport> Scanout<'_, H, T> {
+    /// Returns the ID of the scanout.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Queries the current configuration of the scanout from the device.
+    pub fn display_info(&mut self) -> Result<DisplayInfo> {
+        let info = self.gpu.get_display_info()?;
+        Ok(DisplayInfo::from(&info.pmodes[self.id as usize]))
+    }
+
+    /// Fetches the raw EDID blob of the display attached to the scanout.
+    pub fn edid(&mut self) -> Result<Vec<u8>> {
+        self.gpu.edid(self.id)
+    }
+
+    /// Fetches and parses the EDID of the display attached to the scanout.
+    pub fn parsed_edid(&mut self) -> Result<Edid> {
+        Edid::parse(&self.edid()?)
+    }
+
+    /// Creates a framebuffer of the given format matching the current resolution of the scanout
+    /// and attaches it to the scanout, replacing any framebuffer previously attached.
+    ///
+    /// Returns the framebuffer along with its layout, or `Error::NotReady` if the scanout is not
+    /// enabled.
+    pub fn setup_framebuffer(&mut self, format: Format) -> Result<(&mut [u8], FramebufferInfo)> {
+        self.gpu.setup_scanout_framebuffer(self.id, format)
+    }
+
+    /// Returns the layout of the framebuffer attached to the scanout, if one has been set up.
+    pub fn framebuffer_info(&self) -> Option<FramebufferInfo> {
+        self.gpu.framebuffer_info(self.id)
+    }
+
+    /// Returns the framebuffer attached to the scanout, if one has been set up.
+    pub fn framebuffer(&mut self) -> Option<&mut [u8]> {
+        let resource_id = self.framebuffer_resource()?;
+        self.gpu.resource_buffer(resource_id)
+    }
+
+    /// Returns the resource holding the framebuffer attached to the scanout, if one has been set
+    /// up.
+    pub fn framebuffer_resource(&self) -> Option<ResourceId> {
+        self.gpu.framebuffers[self.id as usize]
+            .as_ref()
+            .map(|framebuffer| framebuffer.resource_id)
+    }
+
+    /// Returns the size (width, height) of the framebuffer attached to the scanout, if one has
+    /// been set up.
+    pub fn framebuffer_size(&self) -> Option<(u32, u32)> {
+        self.gpu.framebuffers[self.id as usize]
+            .as_ref()
+            .map(|framebuffer| (framebuffer.rect.width, framebuffer.rect.height))
+    }
+
+    /// Detaches the framebuffer from the scanout, which disables the display, and frees it.
+    pub fn release_framebuffer(&mut self) -> Result {
+        self.gpu.release_scanout_framebuffer(self.id)
+    }
+
+    /// Flushes the framebuffer of the scanout to the screen.
+    pub fn flush(&mut self) -> Result {
+        self.gpu.flush_scanout(self.id)
+    }
+
+    /// Flushes the given rectangle of the framebuffer of the scanout to the screen.
+    pub fn flush_rect(&mut self, rect: Rect) -> Result {
+        self.gpu.flush_scanout_rect(self.id, rect)
+    }
+
+    /// Records that the given rectangle of the framebuffer has been drawn to, so that it is
+    /// flushed by the next call to [`Scanout::flush_damage`].
+    pub fn add_damage(&mut self, rect: Rect) -> Result {
+        self.gpu.add_scanout_damage(self.id, rect)
+    }
+
+    /// Flushes all the regions of the framebuffer which have been recorded with
+    /// [`Scanout::add_damage`] to the screen.
+    pub fn flush_damage(&mut self) -> Result {
+        self.gpu.flush_scanout_damage(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{
+            Command, Config, CtrlHeader, Features, RespDisplayInfo, SetScanout, CONTROL_QUEUE_SIZE,
+            EVENT_DISPLAY, QUEUE_TRANSMIT,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+    use zerocopy::{AsBytes, FromBytes};
+
+    #[test]
+    fn display_events() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(EVENT_DISPLAY),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(2),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate a device with scanout 0 enabled at 4x2, which is later resized to 8x4.
+        let handle = thread::spawn(move || {
+            for (width, height) in [(4, 2), (4, 2), (8, 4)] {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        assert_eq!(header.hdr_type, Command::GET_DISPLAY_INFO);
+                        let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                        CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                            .write_to_prefix(&mut response[..])
+                            .unwrap();
+                        let pmode = &mut response[size_of::<CtrlHeader>()..];
+                        Rect::new(0, 0, width, height)
+                            .write_to_prefix(&mut *pmode)
+                            .unwrap();
+                        // Enabled.
+                        pmode[size_of::<Rect>()] = 1;
+                        response
+                    });
+            }
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        // The configuration hasn't been queried yet, so the enabled scanout counts as added.
+        assert_eq!(gpu.poll_display_events(), Ok(vec![DisplayEvent::Added(0)]));
+        // Nothing has changed since then.
+        assert_eq!(gpu.poll_display_events(), Ok(vec![]));
+        assert_eq!(
+            gpu.poll_display_events(),
+            Ok(vec![DisplayEvent::Resized {
+                id: 0,
+                width: 8,
+                height: 4
+            }])
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn framebuffer_per_scanout() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(2),
+            num_capsets: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::empty().bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate a device with scanout 0 enabled at 4x2 and scanout 1 enabled at 8x4 to its
+        // right, recording the requests for setting up a framebuffer on each.
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..8 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(request.as_slice()).unwrap();
+                        requests.push(request);
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = vec![0; size_of::<RespDisplayInfo>()];
+                            CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .write_to_prefix(&mut response[..])
+                                .unwrap();
+                            let pmodes = &mut response[size_of::<CtrlHeader>()..];
+                            for (pmode, rect) in pmodes
+                                .chunks_mut(size_of::<DisplayOne>())
+                                .zip([Rect::new(0, 0, 4, 2), Rect::new(4, 0, 8, 4)])
+                            {
+                                rect.write_to_prefix(&mut *pmode).unwrap();
+                                // Enabled.
+                                pmode[size_of::<Rect>()] = 1;
+                            }
+                            response
+                        } else {
+                            CtrlHeader::with_type(Command::OK_NODATA)
+                                .as_bytes()
+                                .to_vec()
+                        }
+                    });
+            }
+            requests
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let mut scanout = gpu.scanout(0).unwrap();
+        let (framebuffer, _) = scanout.setup_framebuffer(Format::B8G8R8A8UNORM).unwrap();
+        assert_eq!(framebuffer.len(), 4 * 2 * 4);
+        let mut scanout = gpu.scanout(1).unwrap();
+        let (framebuffer, _) = scanout.setup_framebuffer(Format::B8G8R8A8UNORM).unwrap();
+        assert_eq!(framebuffer.len(), 8 * 4 * 4);
+        let requests = handle.join().unwrap();
+
+        let resource_0 = gpu.framebuffers[0].as_ref().unwrap().resource_id;
+        let resource_1 = gpu.framebuffers[1].as_ref().unwrap().resource_id;
+        assert_ne!(resource_0, resource_1);
+        let expected_set_scanout_0 = SetScanout {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT),
+            rect: Rect::new(0, 0, 4, 2),
+            scanout_id: 0,
+            resource_id: resource_0.0,
+        };
+        assert!(requests[3].starts_with(expected_set_scanout_0.as_bytes()));
+        // The framebuffer's own coordinates start from 0, wherever the scanout is.
+        let expected_set_scanout_1 = SetScanout {
+            header: CtrlHeader::with_type(Command::SET_SCANOUT),
+            rect: Rect::new(0, 0, 8, 4),
+            scanout_id: 1,
+            resource_id: resource_1.0,
+        };
+        assert!(requests[7].starts_with(expected_set_scanout_1.as_bytes()));
+    }
+}
@@ -0,0 +1,550 @@
+//! 3D (virgl) contexts and command submission for VirtIO GPU devices.
+
+use super::resource::{Backing, Resource};
+use super::{Command, CtrlHeader, Features, ResourceId, VirtIOGpu};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{vec, vec::Vec};
+use core::{cmp::min, mem::size_of};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The maximum length of the debug name of a context.
+const CONTEXT_NAME_LEN: usize = 64;
+
+/// The bits of the `context_init` field of `CTX_CREATE` which hold the capability set ID.
+const CONTEXT_INIT_CAPSET_ID_MASK: u32 = 0xff;
+
+/// The ID of a 3D rendering context which the driver has created on the device.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ContextId(pub(crate) u32);
+
+impl From<ContextId> for u32 {
+    fn from(id: ContextId) -> u32 {
+        id.0
+    }
+}
+
+/// Information about a capability set supported by the device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CapsetInfo {
+    /// The ID of the capability set, such as 1 for virgl or 2 for virgl2.
+    pub id: u32,
+    /// The highest version of the capability set which the device supports.
+    pub max_version: u32,
+    /// The maximum size of the capability set in bytes.
+    pub max_size: u32,
+}
+
+/// The parameters of a 3D resource, as defined by virglrenderer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Resource3DParams {
+    /// The texture target, e.g. `PIPE_TEXTURE_2D`.
+    pub target: u32,
+    /// The virgl format.
+    pub format: u32,
+    /// The ways in which the resource may be bound, e.g. `VIRGL_BIND_RENDER_TARGET`.
+    pub bind: u32,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The depth in pixels.
+    pub depth: u32,
+    /// The number of array layers.
+    pub array_size: u32,
+    /// The index of the last mipmap level.
+    pub last_level: u32,
+    /// The number of samples per pixel.
+    pub nr_samples: u32,
+    /// Resource flags.
+    pub flags: u32,
+}
+
+/// A box within a 3D resource.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, PartialEq)]
+pub struct Box3D {
+    /// The X coordinate of the left edge.
+    pub x: u32,
+    /// The Y coordinate of the top edge.
+    pub y: u32,
+    /// The Z coordinate of the front face.
+    pub z: u32,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The depth in pixels.
+    pub depth: u32,
+}
+
+/// The region and layout of a transfer between a 3D resource and its backing memory.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Transfer3D {
+    /// The region of the resource to transfer.
+    pub region: Box3D,
+    /// The offset in bytes of the start of the region in the backing memory.
+    pub offset: u64,
+    /// The mipmap level to transfer.
+    pub level: u32,
+    /// The number of bytes between lines in the backing memory, or 0 for the default.
+    pub stride: u32,
+    /// The number of bytes between layers in the backing memory, or 0 for the default.
+    pub layer_stride: u32,
+}
+
+impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
+    /// Returns the number of capability sets which the device supports.
+    pub fn num_capsets(&self) -> u32 {
+        self.num_capsets
+    }
+
+    /// Queries information about the capability set with the given index, which must be less
+    /// than [`VirtIOGpu::num_capsets`].
+    pub fn capset_info(&mut self, index: u32) -> Result<CapsetInfo> {
+        self.check_virgl()?;
+        if index >= self.num_capsets {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespCapsetInfo = self.request(GetCapsetInfo {
+            header: CtrlHeader::with_type(Command::GET_CAPSET_INFO),
+            capset_index: index,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_CAPSET_INFO)?;
+        Ok(CapsetInfo {
+            id: rsp.capset_id,
+            max_version: rsp.capset_max_version,
+            max_size: rsp.capset_max_size,
+        })
+    }
+
+    /// Fetches the given version of a capability set from the device.
+    pub fn capset(&mut self, info: &CapsetInfo, version: u32) -> Result<Vec<u8>> {
+        self.check_virgl()?;
+        if version > info.max_version {
+            return Err(Error::InvalidParam);
+        }
+        let req = GetCapset {
+            header: CtrlHeader::with_type(Command::GET_CAPSET),
+            capset_id: info.id,
+            capset_version: version,
+        };
+        let mut rsp = vec![0; size_of::<CtrlHeader>() + info.max_size as usize];
+        self.request_raw(req.as_bytes(), &mut rsp)?;
+        let (header, data) = rsp.split_at(size_of::<CtrlHeader>());
+        CtrlHeader::read_from(header)
+            .ok_or(Error::IoError)?
+            .check_type(Command::OK_CAPSET)?;
+        Ok(data.to_vec())
+    }
+
+    /// Creates a 3D rendering context on the device, with the given name for debugging.
+    pub fn create_context(&mut self, debug_name: &str) -> Result<ContextId> {
+        self.check_virgl()?;
+        self.ctx_create(debug_name, 0)
+    }
+
+    /// Creates a rendering context on the device which uses the capability set with the given
+    /// ID, such as venus or cross-domain, rather than the default virgl.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_GPU_F_CONTEXT_INIT`.
+    pub fn create_context_with_capset(
+        &mut self,
+        debug_name: &str,
+        capset_id: u32,
+    ) -> Result<ContextId> {
+        if !self.negotiated_features.contains(Features::CONTEXT_INIT) {
+            return Err(Error::Unsupported);
+        }
+        if capset_id & !CONTEXT_INIT_CAPSET_ID_MASK != 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.ctx_create(debug_name, capset_id)
+    }
+
+    fn ctx_create(&mut self, debug_name: &str, context_init: u32) -> Result<ContextId> {
+        let id = self.alloc_context_id();
+        let mut req = CtxCreate {
+            header: CtrlHeader::with_context(Command::CTX_CREATE, id.0),
+            nlen: 0,
+            context_init,
+            debug_name: [0; CONTEXT_NAME_LEN],
+        };
+        let name_len = min(debug_name.len(), CONTEXT_NAME_LEN);
+        req.debug_name[..name_len].copy_from_slice(&debug_name.as_bytes()[..name_len]);
+        req.nlen = name_len as u32;
+        let rsp: CtrlHeader = self.request(req)?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.contexts.insert(id);
+        Ok(id)
+    }
+
+    /// Destroys the given 3D rendering context.
+    pub fn destroy_context(&mut self, ctx: ContextId) -> Result {
+        self.check_context(ctx)?;
+        let rsp: CtrlHeader =
+            self.request(CtrlHeader::with_context(Command::CTX_DESTROY, ctx.0))?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.contexts.remove(&ctx);
+        Ok(())
+    }
+
+    /// Makes the given resource available to commands submitted in the given context.
+    pub fn attach_resource_to_context(&mut self, ctx: ContextId, id: ResourceId) -> Result {
+        self.ctx_resource(Command::CTX_ATTACH_RESOURCE, ctx, id)
+    }
+
+    /// Stops the given resource being available to commands submitted in the given context.
+    pub fn detach_resource_from_context(&mut self, ctx: ContextId, id: ResourceId) -> Result {
+        self.ctx_resource(Command::CTX_DETACH_RESOURCE, ctx, id)
+    }
+
+    fn ctx_resource(&mut self, command: Command, ctx: ContextId, id: ResourceId) -> Result {
+        self.check_context(ctx)?;
+        if !self.resources.contains_key(&id) {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: CtrlHeader = self.request(CtxResource {
+            header: CtrlHeader::with_context(command, ctx.0),
+            resource_id: id.0,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Creates a 3D resource with the given parameters on the device, without any backing memory.
+    ///
+    /// Backing memory may be attached with [`VirtIOGpu::alloc_backing`] or
+    /// [`VirtIOGpu::attach_backing`], and the resource must be attached to a context before it
+    /// can be used there.
+    pub fn create_resource_3d(&mut self, params: &Resource3DParams) -> Result<ResourceId> {
+        self.check_virgl()?;
+        let id = self.alloc_resource_id();
+        let rsp: CtrlHeader = self.request(ResourceCreate3D {
+            header: CtrlHeader::with_type(Command::RESOURCE_CREATE_3D),
+            resource_id: id.0,
+            target: params.target,
+            format: params.format,
+            bind: params.bind,
+            width: params.width,
+            height: params.height,
+            depth: params.depth,
+            array_size: params.array_size,
+            last_level: params.last_level,
+            nr_samples: params.nr_samples,
+            flags: params.flags,
+            _padding: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)?;
+        self.resources.insert(
+            id,
+            Resource {
+                format: None,
+                width: params.width,
+                height: params.height,
+                backing: Backing::Detached,
+                blob: None,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Copies the given region of a 3D resource from its backing memory to the host.
+    pub fn transfer_to_host_3d(
+        &mut self,
+        ctx: ContextId,
+        id: ResourceId,
+        transfer: &Transfer3D,
+    ) -> Result {
+        self.transfer_host_3d(Command::TRANSFER_TO_HOST_3D, ctx, id, transfer)
+    }
+
+    /// Copies the given region of a 3D resource from the host to its backing memory.
+    pub fn transfer_from_host_3d(
+        &mut self,
+        ctx: ContextId,
+        id: ResourceId,
+        transfer: &Transfer3D,
+    ) -> Result {
+        self.transfer_host_3d(Command::TRANSFER_FROM_HOST_3D, ctx, id, transfer)
+    }
+
+    fn transfer_host_3d(
+        &mut self,
+        command: Command,
+        ctx: ContextId,
+        id: ResourceId,
+        transfer: &Transfer3D,
+    ) -> Result {
+        self.check_context(ctx)?;
+        let resource = self.resources.get(&id).ok_or(Error::InvalidParam)?;
+        if matches!(resource.backing, Backing::Detached) {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: CtrlHeader = self.request(TransferHost3D {
+            header: CtrlHeader::with_context(command, ctx.0),
+            region: transfer.region,
+            offset: transfer.offset,
+            resource_id: id.0,
+            level: transfer.level,
+            stride: transfer.stride,
+            layer_stride: transfer.layer_stride,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Submits a buffer of commands to be executed in the given context.
+    ///
+    /// The format of the commands depends on the capability set which the context uses, e.g. the
+    /// virgl command stream. Its length must be a multiple of 4 bytes.
+    pub fn submit_3d(&mut self, ctx: ContextId, commands: &[u8]) -> Result {
+        let req = self.submit_3d_request(ctx, commands)?;
+        let mut rsp = CtrlHeader::new_zeroed();
+        self.request_raw(&req, rsp.as_bytes_mut())?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    /// Checks the context and commands, and builds a `SUBMIT_3D` request for them.
+    pub(crate) fn submit_3d_request(&self, ctx: ContextId, commands: &[u8]) -> Result<Vec<u8>> {
+        self.check_context(ctx)?;
+        if commands.len() & 0b11 != 0 || commands.len() > u32::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        let mut req = Submit3D {
+            header: CtrlHeader::with_context(Command::SUBMIT_3D, ctx.0),
+            size: commands.len() as u32,
+            _padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        req.extend_from_slice(commands);
+        Ok(req)
+    }
+
+    /// Returns [`Error::Unsupported`] if 3D mode was not negotiated.
+    fn check_virgl(&self) -> Result {
+        if self.negotiated_features.contains(Features::VIRGL) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Returns [`Error::InvalidParam`] if the given context doesn't exist.
+    pub(crate) fn check_context(&self, ctx: ContextId) -> Result {
+        if self.contexts.contains(&ctx) {
+            Ok(())
+        } else {
+            Err(Error::InvalidParam)
+        }
+    }
+
+    /// Returns a context ID which is not currently in use.
+    fn alloc_context_id(&mut self) -> ContextId {
+        loop {
+            let id = ContextId(self.next_context_id);
+            // Context ID 0 is used for commands which aren't in any context.
+            self.next_context_id = self.next_context_id.checked_add(1).unwrap_or(1);
+            if !self.contexts.contains(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct GetCapsetInfo {
+    header: CtrlHeader,
+    capset_index: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+struct RespCapsetInfo {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_max_version: u32,
+    capset_max_size: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct GetCapset {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_version: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct CtxCreate {
+    header: CtrlHeader,
+    nlen: u32,
+    context_init: u32,
+    debug_name: [u8; CONTEXT_NAME_LEN],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct CtxResource {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct ResourceCreate3D {
+    header: CtrlHeader,
+    resource_id: u32,
+    target: u32,
+    format: u32,
+    bind: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+    array_size: u32,
+    last_level: u32,
+    nr_samples: u32,
+    flags: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct TransferHost3D {
+    header: CtrlHeader,
+    region: Box3D,
+    offset: u64,
+    resource_id: u32,
+    level: u32,
+    stride: u32,
+    layer_stride: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct Submit3D {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::gpu::{Config, CONTROL_QUEUE_SIZE, QUEUE_TRANSMIT},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::{ReadOnly, Volatile, WriteOnly},
+    };
+    use alloc::sync::Arc;
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn submit_3d() {
+        let mut config_space = Config {
+            events_read: ReadOnly::new(0),
+            events_clear: WriteOnly::default(),
+            num_scanouts: Volatile::new(1),
+            num_capsets: ReadOnly::new(1),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: CONTROL_QUEUE_SIZE as u32,
+            device_features: Features::VIRGL.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Start a thread to simulate the device, which records the command stream and accepts
+        // every command.
+        let handle = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..5 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_TRANSMIT, |request| {
+                        commands.push(request);
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            commands
+        });
+
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(gpu.num_capsets(), 1);
+        let ctx = gpu.create_context("test").unwrap();
+        let params = Resource3DParams {
+            target: 2,
+            format: 1,
+            width: 64,
+            height: 32,
+            depth: 1,
+            array_size: 1,
+            ..Default::default()
+        };
+        let resource = gpu.create_resource_3d(&params).unwrap();
+        gpu.attach_resource_to_context(ctx, resource).unwrap();
+        assert_eq!(
+            gpu.submit_3d(ctx, &[1, 2, 3]),
+            Err(Error::InvalidParam),
+            "Command buffers must be a whole number of words"
+        );
+        gpu.submit_3d(ctx, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        gpu.destroy_context(ctx).unwrap();
+        assert_eq!(
+            gpu.submit_3d(ctx, &[1, 2, 3, 4]),
+            Err(Error::InvalidParam),
+            "The context no longer exists"
+        );
+
+        let commands = handle.join().unwrap();
+        let headers: Vec<CtrlHeader> = commands
+            .iter()
+            .map(|command| CtrlHeader::read_from_prefix(command.as_slice()).unwrap())
+            .collect();
+        assert_eq!(
+            headers
+                .iter()
+                .map(|header| (header.hdr_type, header.ctx_id))
+                .collect::<Vec<_>>(),
+            vec![
+                (Command::CTX_CREATE, ctx.0),
+                (Command::RESOURCE_CREATE_3D, 0),
+                (Command::CTX_ATTACH_RESOURCE, ctx.0),
+                (Command::SUBMIT_3D, ctx.0),
+                (Command::CTX_DESTROY, ctx.0),
+            ]
+        );
+        let create = &commands[0][size_of::<CtrlHeader>()..];
+        assert_eq!(&create[..4], &4u32.to_le_bytes());
+        assert_eq!(&create[8..12], b"test");
+        let submit = &commands[3][size_of::<CtrlHeader>()..];
+        assert_eq!(submit, &[8, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let create_3d = &commands[1][size_of::<CtrlHeader>()..];
+        assert_eq!(&create_3d[..4], &u32::from(resource).to_le_bytes());
+        assert_eq!(&create_3d[16..24], &[64, 0, 0, 0, 32, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,409 @@
+//! Decoding of the evdev events reported by VirtIO input devices.
+
+use super::InputEvent;
+use crate::{Error, Result};
+use core::convert::TryFrom;
+
+/// An evdev event type, i.e. the `EV_*` value in [`InputEvent::event_type`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct EventType(pub u16);
+
+impl EventType {
+    /// `EV_SYN`: markers separating events.
+    pub const SYN: Self = Self(0x00);
+    /// `EV_KEY`: state changes of keys and buttons.
+    pub const KEY: Self = Self(0x01);
+    /// `EV_REL`: relative axis changes.
+    pub const REL: Self = Self(0x02);
+    /// `EV_ABS`: absolute axis changes.
+    pub const ABS: Self = Self(0x03);
+    /// `EV_MSC`: miscellaneous input data.
+    pub const MSC: Self = Self(0x04);
+    /// `EV_SW`: binary state switches.
+    pub const SW: Self = Self(0x05);
+    /// `EV_LED`: LEDs on the device.
+    pub const LED: Self = Self(0x11);
+    /// `EV_SND`: sound output.
+    pub const SND: Self = Self(0x12);
+    /// `EV_REP`: key repeat settings.
+    pub const REP: Self = Self(0x14);
+    /// `EV_FF`: force feedback.
+    pub const FF: Self = Self(0x15);
+}
+
+/// A relative axis, i.e. the `REL_*` code of an `EV_REL` event.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RelAxis(pub u16);
+
+impl RelAxis {
+    /// `REL_X`: horizontal motion.
+    pub const X: Self = Self(0x00);
+    /// `REL_Y`: vertical motion.
+    pub const Y: Self = Self(0x01);
+    /// `REL_Z`
+    pub const Z: Self = Self(0x02);
+    /// `REL_RX`
+    pub const RX: Self = Self(0x03);
+    /// `REL_RY`
+    pub const RY: Self = Self(0x04);
+    /// `REL_RZ`
+    pub const RZ: Self = Self(0x05);
+    /// `REL_HWHEEL`: horizontal scroll wheel, in notches.
+    pub const HWHEEL: Self = Self(0x06);
+    /// `REL_DIAL`
+    pub const DIAL: Self = Self(0x07);
+    /// `REL_WHEEL`: vertical scroll wheel, in notches.
+    pub const WHEEL: Self = Self(0x08);
+    /// `REL_MISC`
+    pub const MISC: Self = Self(0x09);
+    /// `REL_WHEEL_HI_RES`: vertical scroll wheel, in 1/120ths of a notch.
+    pub const WHEEL_HI_RES: Self = Self(0x0b);
+    /// `REL_HWHEEL_HI_RES`: horizontal scroll wheel, in 1/120ths of a notch.
+    pub const HWHEEL_HI_RES: Self = Self(0x0c);
+}
+
+/// An absolute axis, i.e. the `ABS_*` code of an `EV_ABS` event.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AbsAxis(pub u16);
+
+impl AbsAxis {
+    /// `ABS_X`: horizontal position.
+    pub const X: Self = Self(0x00);
+    /// `ABS_Y`: vertical position.
+    pub const Y: Self = Self(0x01);
+    /// `ABS_Z`
+    pub const Z: Self = Self(0x02);
+    /// `ABS_RX`
+    pub const RX: Self = Self(0x03);
+    /// `ABS_RY`
+    pub const RY: Self = Self(0x04);
+    /// `ABS_RZ`
+    pub const RZ: Self = Self(0x05);
+    /// `ABS_WHEEL`
+    pub const WHEEL: Self = Self(0x08);
+    /// `ABS_PRESSURE`: pressure of a single touch or stylus.
+    pub const PRESSURE: Self = Self(0x18);
+    /// `ABS_DISTANCE`: hover distance of a stylus.
+    pub const DISTANCE: Self = Self(0x19);
+    /// `ABS_TILT_X`
+    pub const TILT_X: Self = Self(0x1a);
+    /// `ABS_TILT_Y`
+    pub const TILT_Y: Self = Self(0x1b);
+    /// `ABS_MISC`
+    pub const MISC: Self = Self(0x28);
+    /// `ABS_MT_SLOT`: selects the multi-touch slot which following events refer to.
+    pub const MT_SLOT: Self = Self(0x2f);
+    /// `ABS_MT_TOUCH_MAJOR`
+    pub const MT_TOUCH_MAJOR: Self = Self(0x30);
+    /// `ABS_MT_TOUCH_MINOR`
+    pub const MT_TOUCH_MINOR: Self = Self(0x31);
+    /// `ABS_MT_ORIENTATION`
+    pub const MT_ORIENTATION: Self = Self(0x34);
+    /// `ABS_MT_POSITION_X`: horizontal position of a contact.
+    pub const MT_POSITION_X: Self = Self(0x35);
+    /// `ABS_MT_POSITION_Y`: vertical position of a contact.
+    pub const MT_POSITION_Y: Self = Self(0x36);
+    /// `ABS_MT_TOOL_TYPE`
+    pub const MT_TOOL_TYPE: Self = Self(0x37);
+    /// `ABS_MT_TRACKING_ID`: identifies a contact, or -1 when it is lifted.
+    pub const MT_TRACKING_ID: Self = Self(0x39);
+    /// `ABS_MT_PRESSURE`: pressure of a contact.
+    pub const MT_PRESSURE: Self = Self(0x3a);
+    /// `ABS_MT_DISTANCE`
+    pub const MT_DISTANCE: Self = Self(0x3b);
+}
+
+/// `SYN_REPORT`: the end of a frame of events which happened at the same time.
+pub(crate) const SYN_REPORT: u16 = 0x00;
+/// `SYN_DROPPED`: events were dropped because the event queue was full.
+pub(crate) const SYN_DROPPED: u16 = 0x03;
+
+/// Defines the `KeyCode` enum along with its conversion from evdev key codes.
+macro_rules! key_codes {
+    ($($name:ident = $value:literal => $evdev:ident,)*) => {
+        /// A key or button, i.e. the `KEY_*` or `BTN_*` code of an `EV_KEY` event.
+        ///
+        /// Key codes identify physical key positions, named after the US layout. Use a
+        /// [`Keymap`](super::Keymap) to find out which character a key produces.
+        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        #[repr(u16)]
+        pub enum KeyCode {
+            $(
+                #[doc = concat!("`", stringify!($evdev), "`")]
+                $name = $value,
+            )*
+        }
+
+        impl TryFrom<u16> for KeyCode {
+            type Error = Error;
+
+            /// Converts an evdev key code to the corresponding key, or returns
+            /// [`Error::InvalidParam`] if it isn't one which we know about.
+            fn try_from(code: u16) -> Result<Self> {
+                match code {
+                    $($value => Ok(Self::$name),)*
+                    _ => Err(Error::InvalidParam),
+                }
+            }
+        }
+    };
+}
+
+key_codes! {
+    Esc = 1 => KEY_ESC,
+    Digit1 = 2 => KEY_1,
+    Digit2 = 3 => KEY_2,
+    Digit3 = 4 => KEY_3,
+    Digit4 = 5 => KEY_4,
+    Digit5 = 6 => KEY_5,
+    Digit6 = 7 => KEY_6,
+    Digit7 = 8 => KEY_7,
+    Digit8 = 9 => KEY_8,
+    Digit9 = 10 => KEY_9,
+    Digit0 = 11 => KEY_0,
+    Minus = 12 => KEY_MINUS,
+    Equal = 13 => KEY_EQUAL,
+    Backspace = 14 => KEY_BACKSPACE,
+    Tab = 15 => KEY_TAB,
+    Q = 16 => KEY_Q,
+    W = 17 => KEY_W,
+    E = 18 => KEY_E,
+    R = 19 => KEY_R,
+    T = 20 => KEY_T,
+    Y = 21 => KEY_Y,
+    U = 22 => KEY_U,
+    I = 23 => KEY_I,
+    O = 24 => KEY_O,
+    P = 25 => KEY_P,
+    LeftBrace = 26 => KEY_LEFTBRACE,
+    RightBrace = 27 => KEY_RIGHTBRACE,
+    Enter = 28 => KEY_ENTER,
+    LeftCtrl = 29 => KEY_LEFTCTRL,
+    A = 30 => KEY_A,
+    S = 31 => KEY_S,
+    D = 32 => KEY_D,
+    F = 33 => KEY_F,
+    G = 34 => KEY_G,
+    H = 35 => KEY_H,
+    J = 36 => KEY_J,
+    K = 37 => KEY_K,
+    L = 38 => KEY_L,
+    Semicolon = 39 => KEY_SEMICOLON,
+    Apostrophe = 40 => KEY_APOSTROPHE,
+    Grave = 41 => KEY_GRAVE,
+    LeftShift = 42 => KEY_LEFTSHIFT,
+    Backslash = 43 => KEY_BACKSLASH,
+    Z = 44 => KEY_Z,
+    X = 45 => KEY_X,
+    C = 46 => KEY_C,
+    V = 47 => KEY_V,
+    B = 48 => KEY_B,
+    N = 49 => KEY_N,
+    M = 50 => KEY_M,
+    Comma = 51 => KEY_COMMA,
+    Dot = 52 => KEY_DOT,
+    Slash = 53 => KEY_SLASH,
+    RightShift = 54 => KEY_RIGHTSHIFT,
+    KpAsterisk = 55 => KEY_KPASTERISK,
+    LeftAlt = 56 => KEY_LEFTALT,
+    Space = 57 => KEY_SPACE,
+    CapsLock = 58 => KEY_CAPSLOCK,
+    F1 = 59 => KEY_F1,
+    F2 = 60 => KEY_F2,
+    F3 = 61 => KEY_F3,
+    F4 = 62 => KEY_F4,
+    F5 = 63 => KEY_F5,
+    F6 = 64 => KEY_F6,
+    F7 = 65 => KEY_F7,
+    F8 = 66 => KEY_F8,
+    F9 = 67 => KEY_F9,
+    F10 = 68 => KEY_F10,
+    NumLock = 69 => KEY_NUMLOCK,
+    ScrollLock = 70 => KEY_SCROLLLOCK,
+    Kp7 = 71 => KEY_KP7,
+    Kp8 = 72 => KEY_KP8,
+    Kp9 = 73 => KEY_KP9,
+    KpMinus = 74 => KEY_KPMINUS,
+    Kp4 = 75 => KEY_KP4,
+    Kp5 = 76 => KEY_KP5,
+    Kp6 = 77 => KEY_KP6,
+    KpPlus = 78 => KEY_KPPLUS,
+    Kp1 = 79 => KEY_KP1,
+    Kp2 = 80 => KEY_KP2,
+    Kp3 = 81 => KEY_KP3,
+    Kp0 = 82 => KEY_KP0,
+    KpDot = 83 => KEY_KPDOT,
+    Key102nd = 86 => KEY_102ND,
+    F11 = 87 => KEY_F11,
+    F12 = 88 => KEY_F12,
+    KpEnter = 96 => KEY_KPENTER,
+    RightCtrl = 97 => KEY_RIGHTCTRL,
+    KpSlash = 98 => KEY_KPSLASH,
+    SysRq = 99 => KEY_SYSRQ,
+    RightAlt = 100 => KEY_RIGHTALT,
+    Home = 102 => KEY_HOME,
+    Up = 103 => KEY_UP,
+    PageUp = 104 => KEY_PAGEUP,
+    Left = 105 => KEY_LEFT,
+    Right = 106 => KEY_RIGHT,
+    End = 107 => KEY_END,
+    Down = 108 => KEY_DOWN,
+    PageDown = 109 => KEY_PAGEDOWN,
+    Insert = 110 => KEY_INSERT,
+    Delete = 111 => KEY_DELETE,
+    Mute = 113 => KEY_MUTE,
+    VolumeDown = 114 => KEY_VOLUMEDOWN,
+    VolumeUp = 115 => KEY_VOLUMEUP,
+    Power = 116 => KEY_POWER,
+    KpEqual = 117 => KEY_KPEQUAL,
+    Pause = 119 => KEY_PAUSE,
+    KpComma = 121 => KEY_KPCOMMA,
+    LeftMeta = 125 => KEY_LEFTMETA,
+    RightMeta = 126 => KEY_RIGHTMETA,
+    Compose = 127 => KEY_COMPOSE,
+    BtnLeft = 0x110 => BTN_LEFT,
+    BtnRight = 0x111 => BTN_RIGHT,
+    BtnMiddle = 0x112 => BTN_MIDDLE,
+    BtnSide = 0x113 => BTN_SIDE,
+    BtnExtra = 0x114 => BTN_EXTRA,
+    BtnForward = 0x115 => BTN_FORWARD,
+    BtnBack = 0x116 => BTN_BACK,
+    BtnTask = 0x117 => BTN_TASK,
+    BtnToolPen = 0x140 => BTN_TOOL_PEN,
+    BtnToolRubber = 0x141 => BTN_TOOL_RUBBER,
+    BtnToolFinger = 0x145 => BTN_TOOL_FINGER,
+    BtnToolMouse = 0x146 => BTN_TOOL_MOUSE,
+    BtnTouch = 0x14a => BTN_TOUCH,
+    BtnStylus = 0x14b => BTN_STYLUS,
+    BtnStylus2 = 0x14c => BTN_STYLUS2,
+    BtnToolDoubleTap = 0x14d => BTN_TOOL_DOUBLETAP,
+    BtnToolTripleTap = 0x14e => BTN_TOOL_TRIPLETAP,
+    BtnToolQuadTap = 0x14f => BTN_TOOL_QUADTAP,
+}
+
+impl From<KeyCode> for u16 {
+    fn from(key: KeyCode) -> u16 {
+        key as u16
+    }
+}
+
+/// The state reported by an `EV_KEY` event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyState {
+    /// The key was released.
+    Released,
+    /// The key was pressed.
+    Pressed,
+    /// The key is being held down, and has repeated.
+    Repeated,
+}
+
+/// An [`InputEvent`] decoded according to the evdev protocol, as returned by
+/// [`InputEvent::decode`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodedEvent {
+    /// A key or button changed state.
+    Key {
+        /// The key or button.
+        key: KeyCode,
+        /// Its new state.
+        state: KeyState,
+    },
+    /// A relative axis moved.
+    Relative {
+        /// The axis.
+        axis: RelAxis,
+        /// The distance which it moved.
+        delta: i32,
+    },
+    /// An absolute axis changed.
+    Absolute {
+        /// The axis.
+        axis: AbsAxis,
+        /// Its new value.
+        value: i32,
+    },
+    /// `SYN_REPORT`: the end of a frame of events which happened at the same time.
+    SynReport,
+    /// `SYN_DROPPED`: some events were lost, so the events up to and including the next
+    /// `SYN_REPORT` should be ignored.
+    SynDropped,
+    /// Any other event, including keys which aren't covered by [`KeyCode`].
+    Other(InputEvent),
+}
+
+impl InputEvent {
+    /// Decodes the event according to the evdev protocol.
+    pub fn decode(&self) -> DecodedEvent {
+        let value = self.value as i32;
+        match EventType(self.event_type) {
+            EventType::SYN if self.code == SYN_REPORT => DecodedEvent::SynReport,
+            EventType::SYN if self.code == SYN_DROPPED => DecodedEvent::SynDropped,
+            EventType::KEY => {
+                let state = match value {
+                    0 => KeyState::Released,
+                    1 => KeyState::Pressed,
+                    2 => KeyState::Repeated,
+                    _ => return DecodedEvent::Other(*self),
+                };
+                match KeyCode::try_from(self.code) {
+                    Ok(key) => DecodedEvent::Key { key, state },
+                    Err(_) => DecodedEvent::Other(*self),
+                }
+            }
+            EventType::REL => DecodedEvent::Relative {
+                axis: RelAxis(self.code),
+                delta: value,
+            },
+            EventType::ABS => DecodedEvent::Absolute {
+                axis: AbsAxis(self.code),
+                value,
+            },
+            _ => DecodedEvent::Other(*self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_events() {
+        assert_eq!(
+            InputEvent::new(EventType::KEY.0, 30, 1).decode(),
+            DecodedEvent::Key {
+                key: KeyCode::A,
+                state: KeyState::Pressed
+            }
+        );
+        assert_eq!(
+            InputEvent::new(EventType::KEY.0, 0x110, 2).decode(),
+            DecodedEvent::Key {
+                key: KeyCode::BtnLeft,
+                state: KeyState::Repeated
+            }
+        );
+        assert_eq!(
+            InputEvent::new(EventType::REL.0, 0, -3i32 as u32).decode(),
+            DecodedEvent::Relative {
+                axis: RelAxis::X,
+                delta: -3
+            }
+        );
+        assert_eq!(
+            InputEvent::new(EventType::ABS.0, 0x39, u32::MAX).decode(),
+            DecodedEvent::Absolute {
+                axis: AbsAxis::MT_TRACKING_ID,
+                value: -1
+            }
+        );
+        assert_eq!(
+            InputEvent::new(EventType::SYN.0, SYN_REPORT, 0).decode(),
+            DecodedEvent::SynReport
+        );
+        // An unknown key code.
+        let event = InputEvent::new(EventType::KEY.0, 0x2ff, 1);
+        assert_eq!(event.decode(), DecodedEvent::Other(event));
+    }
+}
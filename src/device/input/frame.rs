@@ -0,0 +1,203 @@
+//! Accumulation of pointer events into complete frames.
+
+use super::{AbsAxis, DecodedEvent, KeyCode, KeyState, RelAxis};
+use bitflags::bitflags;
+
+bitflags! {
+    /// Pointer buttons, as reported in [`PointerState::buttons`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Buttons: u8 {
+        /// `BTN_LEFT`
+        const LEFT = 1 << 0;
+        /// `BTN_RIGHT`
+        const RIGHT = 1 << 1;
+        /// `BTN_MIDDLE`
+        const MIDDLE = 1 << 2;
+        /// `BTN_SIDE`
+        const SIDE = 1 << 3;
+        /// `BTN_EXTRA`
+        const EXTRA = 1 << 4;
+        /// `BTN_FORWARD`
+        const FORWARD = 1 << 5;
+        /// `BTN_BACK`
+        const BACK = 1 << 6;
+        /// `BTN_TASK`
+        const TASK = 1 << 7;
+    }
+}
+
+impl Buttons {
+    /// Returns the button corresponding to the given key code, if it is a pointer button.
+    fn from_key(key: KeyCode) -> Option<Self> {
+        match key {
+            KeyCode::BtnLeft => Some(Self::LEFT),
+            KeyCode::BtnRight => Some(Self::RIGHT),
+            KeyCode::BtnMiddle => Some(Self::MIDDLE),
+            KeyCode::BtnSide => Some(Self::SIDE),
+            KeyCode::BtnExtra => Some(Self::EXTRA),
+            KeyCode::BtnForward => Some(Self::FORWARD),
+            KeyCode::BtnBack => Some(Self::BACK),
+            KeyCode::BtnTask => Some(Self::TASK),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a pointing device at the end of a frame, as returned by
+/// [`FrameAccumulator::push`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PointerState {
+    /// The absolute position reported by `ABS_X` and `ABS_Y`, in the units of the device, or
+    /// `None` if the device hasn't reported one.
+    ///
+    /// Use [`AbsInfo::scale`](super::AbsInfo::scale) to convert it to screen pixels.
+    pub position: Option<(i32, i32)>,
+    /// The relative motion reported by `REL_X` and `REL_Y` during the frame.
+    pub motion: (i32, i32),
+    /// The vertical scroll reported by `REL_WHEEL` during the frame, in notches.
+    pub wheel: i32,
+    /// The horizontal scroll reported by `REL_HWHEEL` during the frame, in notches.
+    pub hwheel: i32,
+    /// The buttons which are held down.
+    pub buttons: Buttons,
+    /// Whether a finger or stylus is touching the surface, as reported by `BTN_TOUCH`.
+    pub touching: bool,
+}
+
+/// Accumulates pointer events until the `SYN_REPORT` which ends each frame, so that
+/// simultaneous changes, e.g. of both coordinates of a position, are seen together.
+///
+/// Keyboard events don't belong to any frame, so are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct FrameAccumulator {
+    state: PointerState,
+    changed: bool,
+    dropping: bool,
+}
+
+impl FrameAccumulator {
+    /// Creates a new accumulator for a pointing device with no buttons held.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given event to the current frame.
+    ///
+    /// Returns the new state of the pointing device if the event ends a frame which changed it.
+    /// Relative motion and scrolling are reset at the start of each frame. If the device dropped
+    /// events, the rest of the frame is ignored.
+    pub fn push(&mut self, event: &DecodedEvent) -> Option<PointerState> {
+        match *event {
+            DecodedEvent::SynReport => {
+                let state = self.state;
+                let changed = self.changed && !self.dropping;
+                self.state.motion = (0, 0);
+                self.state.wheel = 0;
+                self.state.hwheel = 0;
+                self.changed = false;
+                self.dropping = false;
+                return changed.then_some(state);
+            }
+            DecodedEvent::SynDropped => {
+                self.dropping = true;
+            }
+            _ if self.dropping => {}
+            DecodedEvent::Key { key, state } => {
+                let pressed = state != KeyState::Released;
+                if key == KeyCode::BtnTouch {
+                    self.state.touching = pressed;
+                } else if let Some(button) = Buttons::from_key(key) {
+                    self.state.buttons.set(button, pressed);
+                } else {
+                    return None;
+                }
+                self.changed = true;
+            }
+            DecodedEvent::Relative { axis, delta } => {
+                match axis {
+                    RelAxis::X => self.state.motion.0 += delta,
+                    RelAxis::Y => self.state.motion.1 += delta,
+                    RelAxis::WHEEL => self.state.wheel += delta,
+                    RelAxis::HWHEEL => self.state.hwheel += delta,
+                    _ => return None,
+                }
+                self.changed = true;
+            }
+            DecodedEvent::Absolute { axis, value } => {
+                let (x, y) = self.state.position.unwrap_or_default();
+                match axis {
+                    AbsAxis::X => self.state.position = Some((value, y)),
+                    AbsAxis::Y => self.state.position = Some((x, value)),
+                    _ => return None,
+                }
+                self.changed = true;
+            }
+            DecodedEvent::Other(_) => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulate_frames() {
+        let mut frames = FrameAccumulator::new();
+        let events = [
+            DecodedEvent::Absolute {
+                axis: AbsAxis::X,
+                value: 100,
+            },
+            DecodedEvent::Absolute {
+                axis: AbsAxis::Y,
+                value: 200,
+            },
+            DecodedEvent::Key {
+                key: KeyCode::BtnLeft,
+                state: KeyState::Pressed,
+            },
+            DecodedEvent::Relative {
+                axis: RelAxis::WHEEL,
+                delta: -1,
+            },
+        ];
+        for event in &events {
+            assert_eq!(frames.push(event), None);
+        }
+        let state = PointerState {
+            position: Some((100, 200)),
+            wheel: -1,
+            buttons: Buttons::LEFT,
+            ..Default::default()
+        };
+        assert_eq!(frames.push(&DecodedEvent::SynReport), Some(state));
+
+        // Keyboard events don't change the pointer state, and scrolling is reset.
+        let key = DecodedEvent::Key {
+            key: KeyCode::A,
+            state: KeyState::Pressed,
+        };
+        assert_eq!(frames.push(&key), None);
+        assert_eq!(frames.push(&DecodedEvent::SynReport), None);
+        let release = DecodedEvent::Key {
+            key: KeyCode::BtnLeft,
+            state: KeyState::Released,
+        };
+        frames.push(&release);
+        assert_eq!(
+            frames.push(&DecodedEvent::SynReport),
+            Some(PointerState {
+                position: Some((100, 200)),
+                ..Default::default()
+            })
+        );
+
+        // A frame with dropped events is ignored.
+        frames.push(&events[2]);
+        frames.push(&DecodedEvent::SynDropped);
+        frames.push(&events[3]);
+        assert_eq!(frames.push(&DecodedEvent::SynReport), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Translation of key codes to characters according to keyboard layouts.
+
+use super::{KeyCode, KeyState, Leds};
+use bitflags::bitflags;
+
+bitflags! {
+    /// The state of the modifier and lock keys of a keyboard.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Modifiers: u16 {
+        /// The left Shift key is held.
+        const LEFT_SHIFT = 1 << 0;
+        /// The right Shift key is held.
+        const RIGHT_SHIFT = 1 << 1;
+        /// The left Ctrl key is held.
+        const LEFT_CTRL = 1 << 2;
+        /// The right Ctrl key is held.
+        const RIGHT_CTRL = 1 << 3;
+        /// The left Alt key is held.
+        const LEFT_ALT = 1 << 4;
+        /// The right Alt key, which is Alt Gr on many layouts, is held.
+        const ALT_GR = 1 << 5;
+        /// The left Meta key is held.
+        const LEFT_META = 1 << 6;
+        /// The right Meta key is held.
+        const RIGHT_META = 1 << 7;
+        /// Caps Lock is on.
+        const CAPS_LOCK = 1 << 8;
+        /// Num Lock is on.
+        const NUM_LOCK = 1 << 9;
+        /// Scroll Lock is on.
+        const SCROLL_LOCK = 1 << 10;
+    }
+}
+
+impl Modifiers {
+    /// Updates the modifier state for the given key event.
+    ///
+    /// Modifier keys are tracked while they are held, and lock keys toggle when they are pressed.
+    /// Returns whether a lock was toggled, in which case the keyboard LEDs should be updated to
+    /// match [`Modifiers::leds`].
+    pub fn update(&mut self, key: KeyCode, state: KeyState) -> bool {
+        let held = match key {
+            KeyCode::LeftShift => Self::LEFT_SHIFT,
+            KeyCode::RightShift => Self::RIGHT_SHIFT,
+            KeyCode::LeftCtrl => Self::LEFT_CTRL,
+            KeyCode::RightCtrl => Self::RIGHT_CTRL,
+            KeyCode::LeftAlt => Self::LEFT_ALT,
+            KeyCode::RightAlt => Self::ALT_GR,
+            KeyCode::LeftMeta => Self::LEFT_META,
+            KeyCode::RightMeta => Self::RIGHT_META,
+            _ => {
+                let lock = match key {
+                    KeyCode::CapsLock => Self::CAPS_LOCK,
+                    KeyCode::NumLock => Self::NUM_LOCK,
+                    KeyCode::ScrollLock => Self::SCROLL_LOCK,
+                    _ => return false,
+                };
+                if state == KeyState::Pressed {
+                    self.toggle(lock);
+                    return true;
+                }
+                return false;
+            }
+        };
+        self.set(held, state != KeyState::Released);
+        false
+    }
+
+    /// Returns whether either Shift key is held.
+    pub fn shift(&self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    /// Returns whether either Ctrl key is held.
+    pub fn ctrl(&self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    /// Returns whether either Meta key is held.
+    pub fn meta(&self) -> bool {
+        self.intersects(Self::LEFT_META | Self::RIGHT_META)
+    }
+
+    /// Returns the keyboard LEDs which should be on for the current lock state, to pass to
+    /// [`VirtIOInput::set_leds`](super::VirtIOInput::set_leds).
+    pub fn leds(&self) -> Leds {
+        let mut leds = Leds::empty();
+        leds.set(Leds::CAPS_LOCK, self.contains(Self::CAPS_LOCK));
+        leds.set(Leds::NUM_LOCK, self.contains(Self::NUM_LOCK));
+        leds.set(Leds::SCROLL_LOCK, self.contains(Self::SCROLL_LOCK));
+        leds
+    }
+}
+
+/// The characters produced by a key in a keyboard layout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeymapEntry {
+    /// The key.
+    pub key: KeyCode,
+    /// The character produced with no modifiers.
+    pub normal: char,
+    /// The character produced with Shift.
+    pub shifted: char,
+    /// The character produced with Alt Gr, if any.
+    pub alt_gr: Option<char>,
+}
+
+impl KeymapEntry {
+    /// Creates an entry for a key without an Alt Gr character.
+    pub const fn new(key: KeyCode, normal: char, shifted: char) -> Self {
+        Self {
+            key,
+            normal,
+            shifted,
+            alt_gr: None,
+        }
+    }
+
+    /// Creates an entry for a key with an Alt Gr character.
+    pub const fn with_alt_gr(key: KeyCode, normal: char, shifted: char, alt_gr: char) -> Self {
+        Self {
+            key,
+            normal,
+            shifted,
+            alt_gr: Some(alt_gr),
+        }
+    }
+}
+
+/// A keyboard layout, which translates key codes to characters.
+///
+/// The layout covers the keys which differ between layouts. Keys like Enter, Tab, Space and most
+/// of the numeric keypad are translated the same way for all layouts. Dead keys are treated as
+/// producing their character directly.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Keymap {
+    entries: &'static [KeymapEntry],
+    decimal: char,
+}
+
+impl Keymap {
+    /// The US QWERTY layout.
+    pub const US: Self = Self::new(US_ENTRIES, '.');
+
+    /// The German QWERTZ layout.
+    pub const GERMAN: Self = Self::new(GERMAN_ENTRIES, ',');
+
+    /// Creates a custom layout from the given entries, with the given character for the decimal
+    /// point on the numeric keypad.
+    pub const fn new(entries: &'static [KeymapEntry], decimal: char) -> Self {
+        Self { entries, decimal }
+    }
+
+    /// Returns the character which the given key produces with the given modifiers, or `None` if
+    /// it doesn't produce one.
+    ///
+    /// Caps Lock only affects letters. With Ctrl held, letters and a few symbols produce the
+    /// corresponding ASCII control characters. Alt and Meta are ignored, so callers wanting to
+    /// treat them as shortcuts should check for them first.
+    pub fn translate(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        if let Some(c) = self.translate_common(key, modifiers) {
+            return Some(c);
+        }
+        let entry = self.entries.iter().find(|entry| entry.key == key)?;
+        if modifiers.contains(Modifiers::ALT_GR) {
+            return entry.alt_gr;
+        }
+        let is_letter = entry.normal.is_lowercase() && entry.shifted.is_uppercase();
+        let shift = modifiers.shift() != (is_letter && modifiers.contains(Modifiers::CAPS_LOCK));
+        let c = if shift { entry.shifted } else { entry.normal };
+        if modifiers.ctrl() {
+            let upper = c.to_ascii_uppercase();
+            if ('@'..='_').contains(&upper) {
+                return Some(char::from(upper as u8 & 0x1f));
+            }
+            return None;
+        }
+        Some(c)
+    }
+
+    /// Translates the keys which are the same for all layouts.
+    fn translate_common(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        let num_lock = modifiers.contains(Modifiers::NUM_LOCK);
+        match key {
+            KeyCode::Esc => Some('\x1b'),
+            KeyCode::Backspace => Some('\x08'),
+            KeyCode::Tab => Some('\t'),
+            KeyCode::Enter | KeyCode::KpEnter => Some('\n'),
+            KeyCode::Space => Some(' '),
+            KeyCode::Delete => Some('\x7f'),
+            KeyCode::KpSlash => Some('/'),
+            KeyCode::KpAsterisk => Some('*'),
+            KeyCode::KpMinus => Some('-'),
+            KeyCode::KpPlus => Some('+'),
+            KeyCode::KpEqual => Some('='),
+            KeyCode::Kp0 if num_lock => Some('0'),
+            KeyCode::Kp1 if num_lock => Some('1'),
+            KeyCode::Kp2 if num_lock => Some('2'),
+            KeyCode::Kp3 if num_lock => Some('3'),
+            KeyCode::Kp4 if num_lock => Some('4'),
+            KeyCode::Kp5 if num_lock => Some('5'),
+            KeyCode::Kp6 if num_lock => Some('6'),
+            KeyCode::Kp7 if num_lock => Some('7'),
+            KeyCode::Kp8 if num_lock => Some('8'),
+            KeyCode::Kp9 if num_lock => Some('9'),
+            KeyCode::KpDot if num_lock => Some(self.decimal),
+            _ => None,
+        }
+    }
+}
+
+const US_ENTRIES: &[KeymapEntry] = &[
+    KeymapEntry::new(KeyCode::Grave, '`', '~'),
+    KeymapEntry::new(KeyCode::Digit1, '1', '!'),
+    KeymapEntry::new(KeyCode::Digit2, '2', '@'),
+    KeymapEntry::new(KeyCode::Digit3, '3', '#'),
+    KeymapEntry::new(KeyCode::Digit4, '4', '$'),
+    KeymapEntry::new(KeyCode::Digit5, '5', '%'),
+    KeymapEntry::new(KeyCode::Digit6, '6', '^'),
+    KeymapEntry::new(KeyCode::Digit7, '7', '&'),
+    KeymapEntry::new(KeyCode::Digit8, '8', '*'),
+    KeymapEntry::new(KeyCode::Digit9, '9', '('),
+    KeymapEntry::new(KeyCode::Digit0, '0', ')'),
+    KeymapEntry::new(KeyCode::Minus, '-', '_'),
+    KeymapEntry::new(KeyCode::Equal, '=', '+'),
+    KeymapEntry::new(KeyCode::Q, 'q', 'Q'),
+    KeymapEntry::new(KeyCode::W, 'w', 'W'),
+    KeymapEntry::new(KeyCode::E, 'e', 'E'),
+    KeymapEntry::new(KeyCode::R, 'r', 'R'),
+    KeymapEntry::new(KeyCode::T, 't', 'T'),
+    KeymapEntry::new(KeyCode::Y, 'y', 'Y'),
+    KeymapEntry::new(KeyCode::U, 'u', 'U'),
+    KeymapEntry::new(KeyCode::I, 'i', 'I'),
+    KeymapEntry::new(KeyCode::O, 'o', 'O'),
+    KeymapEntry::new(KeyCode::P, 'p', 'P'),
+    KeymapEntry::new(KeyCode::LeftBrace, '[', '{'),
+    KeymapEntry::new(KeyCode::RightBrace, ']', '}'),
+    KeymapEntry::new(KeyCode::Backslash, '\\', '|'),
+    KeymapEntry::new(KeyCode::A, 'a', 'A'),
+    KeymapEntry::new(KeyCode::S, 's', 'S'),
+    KeymapEntry::new(KeyCode::D, 'd', 'D'),
+    KeymapEntry::new(KeyCode::F, 'f', 'F'),
+    KeymapEntry::new(KeyCode::G, 'g', 'G'),
+    KeymapEntry::new(KeyCode::H, 'h', 'H'),
+    KeymapEntry::new(KeyCode::J, 'j', 'J'),
+    KeymapEntry::new(KeyCode::K, 'k', 'K'),
+    KeymapEntry::new(KeyCode::L, 'l', 'L'),
+    KeymapEntry::new(KeyCode::Semicolon, ';', ':'),
+    KeymapEntry::new(KeyCode::Apostrophe, '\'', '"'),
+    KeymapEntry::new(KeyCode::Key102nd, '\\', '|'),
+    KeymapEntry::new(KeyCode::Z, 'z', 'Z'),
+    KeymapEntry::new(KeyCode::X, 'x', 'X'),
+    KeymapEntry::new(KeyCode::C, 'c', 'C'),
+    KeymapEntry::new(KeyCode::V, 'v', 'V'),
+    KeymapEntry::new(KeyCode::B, 'b', 'B'),
+    KeymapEntry::new(KeyCode::N, 'n', 'N'),
+    KeymapEntry::new(KeyCode::M, 'm', 'M'),
+    KeymapEntry::new(KeyCode::Comma, ',', '<'),
+    KeymapEntry::new(KeyCode::Dot, '.', '>'),
+    KeymapEntry::new(KeyCode::Slash, '/', '?'),
+];
+
+const GERMAN_ENTRIES: &[KeymapEntry] = &[
+    KeymapEntry::new(KeyCode::Grave, '^', '°'),
+    KeymapEntry::new(KeyCode::Digit1, '1', '!'),
+    KeymapEntry::with_alt_gr(KeyCode::Digit2, '2', '"', '²'),
+    KeymapEntry::with_alt_gr(KeyCode::Digit3, '3', '§', '³'),
+    KeymapEntry::new(KeyCode::Digit4, '4', '$'),
+    KeymapEntry::new(KeyCode::Digit5, '5', '%'),
+    KeymapEntry::new(KeyCode::Digit6, '6', '&'),
+    KeymapEntry::with_alt_gr(KeyCode::Digit7, '7', '/', '{'),
+    KeymapEntry::with_alt_gr(KeyCode::Digit8, '8', '(', '['),
+    KeymapEntry::with_alt_gr(KeyCode::Digit9, '9', ')', ']'),
+    KeymapEntry::with_alt_gr(KeyCode::Digit0, '0', '=', '}'),
+    KeymapEntry::with_alt_gr(KeyCode::Minus, 'ß', '?', '\\'),
+    KeymapEntry::new(KeyCode::Equal, '´', '`'),
+    KeymapEntry::with_alt_gr(KeyCode::Q, 'q', 'Q', '@'),
+    KeymapEntry::new(KeyCode::W, 'w', 'W'),
+    KeymapEntry::with_alt_gr(KeyCode::E, 'e', 'E', '€'),
+    KeymapEntry::new(KeyCode::R, 'r', 'R'),
+    KeymapEntry::new(KeyCode::T, 't', 'T'),
+    KeymapEntry::new(KeyCode::Y, 'z', 'Z'),
+    KeymapEntry::new(KeyCode::U, 'u', 'U'),
+    KeymapEntry::new(KeyCode::I, 'i', 'I'),
+    KeymapEntry::new(KeyCode::O, 'o', 'O'),
+    KeymapEntry::new(KeyCode::P, 'p', 'P'),
+    KeymapEntry::new(KeyCode::LeftBrace, 'ü', 'Ü'),
+    KeymapEntry::with_alt_gr(KeyCode::RightBrace, '+', '*', '~'),
+    KeymapEntry::new(KeyCode::Backslash, '#', '\''),
+    KeymapEntry::new(KeyCode::A, 'a', 'A'),
+    KeymapEntry::new(KeyCode::S, 's', 'S'),
+    KeymapEntry::new(KeyCode::D, 'd', 'D'),
+    KeymapEntry::new(KeyCode::F, 'f', 'F'),
+    KeymapEntry::new(KeyCode::G, 'g', 'G'),
+    KeymapEntry::new(KeyCode::H, 'h', 'H'),
+    KeymapEntry::new(KeyCode::J, 'j', 'J'),
+    KeymapEntry::new(KeyCode::K, 'k', 'K'),
+    KeymapEntry::new(KeyCode::L, 'l', 'L'),
+    KeymapEntry::new(KeyCode::Semicolon, 'ö', 'Ö'),
+    KeymapEntry::new(KeyCode::Apostrophe, 'ä', 'Ä'),
+    KeymapEntry::with_alt_gr(KeyCode::Key102nd, '<', '>', '|'),
+    KeymapEntry::new(KeyCode::Z, 'y', 'Y'),
+    KeymapEntry::new(KeyCode::X, 'x', 'X'),
+    KeymapEntry::new(KeyCode::C, 'c', 'C'),
+    KeymapEntry::new(KeyCode::V, 'v', 'V'),
+    KeymapEntry::new(KeyCode::B, 'b', 'B'),
+    KeymapEntry::new(KeyCode::N, 'n', 'N'),
+    KeymapEntry::with_alt_gr(KeyCode::M, 'm', 'M', 'µ'),
+    KeymapEntry::new(KeyCode::Comma, ',', ';'),
+    KeymapEntry::new(KeyCode::Dot, '.', ':'),
+    KeymapEntry::new(KeyCode::Slash, '-', '_'),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_keys() {
+        let mut modifiers = Modifiers::empty();
+        assert_eq!(Keymap::US.translate(KeyCode::Y, modifiers), Some('y'));
+        assert_eq!(Keymap::GERMAN.translate(KeyCode::Y, modifiers), Some('z'));
+        assert_eq!(
+            Keymap::GERMAN.translate(KeyCode::Semicolon, modifiers),
+            Some('ö')
+        );
+        assert_eq!(Keymap::US.translate(KeyCode::Enter, modifiers), Some('\n'));
+        assert_eq!(Keymap::US.translate(KeyCode::Kp1, modifiers), None);
+        assert_eq!(Keymap::US.translate(KeyCode::LeftShift, modifiers), None);
+
+        assert!(!modifiers.update(KeyCode::RightShift, KeyState::Pressed));
+        assert_eq!(Keymap::US.translate(KeyCode::Digit2, modifiers), Some('@'));
+        assert_eq!(
+            Keymap::GERMAN.translate(KeyCode::Digit2, modifiers),
+            Some('"')
+        );
+        modifiers.update(KeyCode::RightShift, KeyState::Released);
+
+        // Caps Lock only affects letters, and is cancelled out by Shift.
+        assert!(modifiers.update(KeyCode::CapsLock, KeyState::Pressed));
+        assert!(!modifiers.update(KeyCode::CapsLock, KeyState::Released));
+        assert_eq!(modifiers.leds(), Leds::CAPS_LOCK);
+        assert_eq!(
+            Keymap::GERMAN.translate(KeyCode::Apostrophe, modifiers),
+            Some('Ä')
+        );
+        assert_eq!(
+            Keymap::GERMAN.translate(KeyCode::Minus, modifiers),
+            Some('ß')
+        );
+        modifiers.update(KeyCode::LeftShift, KeyState::Pressed);
+        assert_eq!(Keymap::US.translate(KeyCode::A, modifiers), Some('a'));
+        modifiers = Modifiers::empty();
+
+        modifiers.update(KeyCode::RightAlt, KeyState::Pressed);
+        assert_eq!(Keymap::GERMAN.translate(KeyCode::Q, modifiers), Some('@'));
+        assert_eq!(Keymap::GERMAN.translate(KeyCode::W, modifiers), None);
+        modifiers.update(KeyCode::RightAlt, KeyState::Released);
+
+        modifiers.update(KeyCode::LeftCtrl, KeyState::Pressed);
+        assert_eq!(Keymap::US.translate(KeyCode::C, modifiers), Some('\x03'));
+        assert_eq!(Keymap::US.translate(KeyCode::Digit1, modifiers), None);
+    }
+}
@@ -0,0 +1,663 @@
+//! Driver for VirtIO input devices.
+
+mod event;
+mod frame;
+mod keymap;
+mod multitouch;
+
+pub use self::event::{AbsAxis, DecodedEvent, EventType, KeyCode, KeyState, RelAxis};
+pub use self::frame::{Buttons, FrameAccumulator, PointerState};
+pub use self::keymap::{Keymap, KeymapEntry, Modifiers};
+pub use self::multitouch::{MultiTouch, TouchEvent, TouchPhase};
+
+use self::event::SYN_REPORT;
+use super::common::Feature;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
+use crate::Result;
+use alloc::{boxed::Box, string::String};
+use bitflags::bitflags;
+use core::{cmp::min, convert::TryFrom, ptr::NonNull};
+use log::info;
+use zerocopy::{AsBytes, FromBytes};
+
+/// Virtual human interface devices such as keyboards, mice and tablets.
+///
+/// An instance of the virtio device represents one such input device.
+/// Device behavior mirrors that of the evdev layer in Linux,
+/// making pass-through implementations on top of evdev easy.
+pub struct VirtIOInput<H: Hal, T: Transport> {
+    transport: T,
+    event_queue: VirtQueue<H, QUEUE_SIZE>,
+    status_queue: VirtQueue<H, QUEUE_SIZE>,
+    event_buf: Box<[InputEvent; 32]>,
+    config: NonNull<Config>,
+}
+
+impl<H: Hal, T: Transport> VirtIOInput<H, T> {
+    /// Create a new VirtIO-Input driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let mut event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
+        transport.begin_init(|features| {
+            let features = Feature::from_bits_truncate(features);
+            info!("Device features: {:?}", features);
+            // negotiate these flags only
+            let supported_features = Feature::empty();
+            (features & supported_features).bits()
+        });
+
+        let config = transport.config_space::<Config>()?;
+
+        let mut event_queue = VirtQueue::new(&mut transport, QUEUE_EVENT)?;
+        let status_queue = VirtQueue::new(&mut transport, QUEUE_STATUS)?;
+        for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
+            // Safe because the buffer lasts as long as the queue.
+            let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
+            assert_eq!(token, i as u16);
+        }
+        if event_queue.should_notify() {
+            transport.notify(QUEUE_EVENT);
+        }
+
+        transport.finish_init();
+
+        Ok(VirtIOInput {
+            transport,
+            event_queue,
+            status_queue,
+            event_buf,
+            config,
+        })
+    }
+
+    /// Acknowledge interrupt and process events.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Pop the pending event.
+    pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
+        if let Some(token) = self.event_queue.peek_used() {
+            let event = &mut self.event_buf[token as usize];
+            // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
+            // is still valid.
+            unsafe {
+                self.event_queue
+                    .pop_used(token, &[], &mut [event.as_bytes_mut()])
+                    .ok()?;
+            }
+            let event_saved = *event;
+            // requeue
+            // Safe because buffer lasts as long as the queue.
+            if let Ok(new_token) = unsafe { self.event_queue.add(&[], &mut [event.as_bytes_mut()]) }
+            {
+                // This only works because nothing happen between `pop_used` and `add` that affects
+                // the list of free descriptors in the queue, so `add` reuses the descriptor which
+                // was just freed by `pop_used`.
+                assert_eq!(new_token, token);
+                if self.event_queue.should_notify() {
+                    self.transport.notify(QUEUE_EVENT);
+                }
+                return Some(event_saved);
+            }
+        }
+        None
+    }
+
+    /// Sends the given event to the device through the status queue, and waits for the device to
+    /// process it.
+    ///
+    /// Status events are used to update the state of the device, e.g. the keyboard LEDs. Like the
+    /// events reported by the device, changes should be followed by an `EV_SYN` / `SYN_REPORT`
+    /// event, which the convenience methods below take care of.
+    pub fn send_status(&mut self, event: InputEvent) -> Result {
+        self.status_queue
+            .add_notify_wait_pop(&[event.as_bytes()], &mut [], &mut self.transport)?;
+        Ok(())
+    }
+
+    /// Sends the given events followed by a `SYN_REPORT` through the status queue.
+    fn send_status_frame(&mut self, events: &[InputEvent]) -> Result {
+        for &event in events {
+            self.send_status(event)?;
+        }
+        self.send_status(InputEvent::new(EventType::SYN.0, SYN_REPORT, 0))
+    }
+
+    /// Turns the keyboard LEDs on or off to match the given lock state.
+    pub fn set_leds(&mut self, leds: Leds) -> Result {
+        self.send_status_frame(&[
+            InputEvent::new(
+                EventType::LED.0,
+                LED_NUML,
+                leds.contains(Leds::NUM_LOCK).into(),
+            ),
+            InputEvent::new(
+                EventType::LED.0,
+                LED_CAPSL,
+                leds.contains(Leds::CAPS_LOCK).into(),
+            ),
+            InputEvent::new(
+                EventType::LED.0,
+                LED_SCROLLL,
+                leds.contains(Leds::SCROLL_LOCK).into(),
+            ),
+        ])
+    }
+
+    /// Sets the delay in milliseconds before a held key starts repeating, and the period in
+    /// milliseconds between repeats, for devices which repeat keys themselves.
+    pub fn set_repeat(&mut self, delay_ms: u32, period_ms: u32) -> Result {
+        self.send_status_frame(&[
+            InputEvent::new(EventType::REP.0, REP_DELAY, delay_ms),
+            InputEvent::new(EventType::REP.0, REP_PERIOD, period_ms),
+        ])
+    }
+
+    /// Query a specific piece of information by `select` and `subsel`, and write
+    /// result to `out`, return the result size.
+    ///
+    /// The size is limited to the size of the configuration space's data field, even if the device
+    /// reports a larger one.
+    pub fn query_config_select(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+        out: &mut [u8],
+    ) -> u8 {
+        let size;
+        let data;
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe {
+            volwrite!(self.config, select, select as u8);
+            volwrite!(self.config, subsel, subsel);
+            size = min(volread!(self.config, size), CONFIG_DATA_SIZE as u8);
+            data = volread!(self.config, data);
+        }
+        out[..size as usize].copy_from_slice(&data[..size as usize]);
+        size
+    }
+
+    /// Queries the given piece of information, returning it padded with zeroes along with its
+    /// size.
+    fn query_config(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+    ) -> ([u8; CONFIG_DATA_SIZE], u8) {
+        let mut data = [0; CONFIG_DATA_SIZE];
+        let size = self.query_config_select(select, subsel, &mut data);
+        (data, size)
+    }
+
+    /// Queries the given string, replacing any invalid UTF-8 sequences.
+    fn query_config_string(&mut self, select: InputConfigSelect) -> String {
+        let (data, size) = self.query_config(select, 0);
+        String::from_utf8_lossy(&data[..size as usize]).into_owned()
+    }
+
+    /// Returns the name of the device.
+    pub fn name(&mut self) -> String {
+        self.query_config_string(InputConfigSelect::IdName)
+    }
+
+    /// Returns the serial number of the device, which is empty if it doesn't have one.
+    pub fn serial(&mut self) -> String {
+        self.query_config_string(InputConfigSelect::IdSerial)
+    }
+
+    /// Returns the bus, vendor, product and version IDs of the device, if it provides them.
+    pub fn ids(&mut self) -> Option<DevIDs> {
+        let (data, size) = self.query_config(InputConfigSelect::IdDevids, 0);
+        DevIDs::read_from_prefix(&data[..size as usize])
+    }
+
+    /// Returns the input properties of the device, as a bitmap of `INPUT_PROP_*` values.
+    pub fn prop_bits(&mut self) -> InputBitmap {
+        let (bytes, _) = self.query_config(InputConfigSelect::PropBits, 0);
+        InputBitmap { bytes }
+    }
+
+    /// Returns the event codes of the given event type which the device supports.
+    ///
+    /// The bitmap is empty if the device doesn't support the event type at all, including if the
+    /// type can't be queried because it doesn't fit in a byte.
+    pub fn ev_bits(&mut self, ev_type: EventType) -> InputBitmap {
+        let bytes = match u8::try_from(ev_type.0) {
+            Ok(subsel) => self.query_config(InputConfigSelect::EvBits, subsel).0,
+            Err(_) => [0; CONFIG_DATA_SIZE],
+        };
+        InputBitmap { bytes }
+    }
+
+    /// Returns information about the given absolute axis, or `None` if the device doesn't have
+    /// it.
+    pub fn abs_info(&mut self, axis: AbsAxis) -> Option<AbsInfo> {
+        let subsel = u8::try_from(axis.0).ok()?;
+        let (data, size) = self.query_config(InputConfigSelect::AbsInfo, subsel);
+        AbsInfo::read_from_prefix(&data[..size as usize])
+    }
+
+    /// Creates a tracker for the contacts of the device, which scales their positions to a
+    /// screen of the given size, or returns `None` if the device doesn't support multi-touch.
+    pub fn multi_touch(&mut self, width: u32, height: u32) -> Option<MultiTouch> {
+        let slots = self.abs_info(AbsAxis::MT_SLOT)?;
+        let x_info = self.abs_info(AbsAxis::MT_POSITION_X)?;
+        let y_info = self.abs_info(AbsAxis::MT_POSITION_Y)?;
+        let num_slots = usize::try_from(slots.max).ok()?.checked_add(1)?;
+        Some(MultiTouch::new(num_slots, x_info, y_info, width, height))
+    }
+
+    /// Works out what kind of device this is from the events and properties which it supports,
+    /// or returns `None` if it isn't any of the kinds we know about.
+    pub fn kind(&mut self) -> Option<DeviceKind> {
+        let props = self.prop_bits();
+        let keys = self.ev_bits(EventType::KEY);
+        let rel = self.ev_bits(EventType::REL);
+        let abs = self.ev_bits(EventType::ABS);
+        DeviceKind::classify(&props, &keys, &rel, &abs)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_EVENT);
+        self.transport.queue_unset(QUEUE_STATUS);
+    }
+}
+
+/// Select value used for [`VirtIOInput::query_config_select()`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum InputConfigSelect {
+    /// Returns the name of the device, in u.string. subsel is zero.
+    IdName = 0x01,
+    /// Returns the serial number of the device, in u.string. subsel is zero.
+    IdSerial = 0x02,
+    /// Returns ID information of the device, in u.ids. subsel is zero.
+    IdDevids = 0x03,
+    /// Returns input properties of the device, in u.bitmap. subsel is zero.
+    /// Individual bits in the bitmap correspond to INPUT_PROP_* constants used
+    /// by the underlying evdev implementation.
+    PropBits = 0x10,
+    /// subsel specifies the event type using EV_* constants in the underlying
+    /// evdev implementation. If size is non-zero the event type is supported
+    /// and a bitmap of supported event codes is returned in u.bitmap. Individual
+    /// bits in the bitmap correspond to implementation-defined input event codes,
+    /// for example keys or pointing device axes.
+    EvBits = 0x11,
+    /// subsel specifies the absolute axis using ABS_* constants in the underlying
+    /// evdev implementation. Information about the axis will be returned in u.abs.
+    AbsInfo = 0x12,
+}
+
+#[repr(C)]
+struct Config {
+    select: WriteOnly<u8>,
+    subsel: WriteOnly<u8>,
+    size: ReadOnly<u8>,
+    _reversed: [ReadOnly<u8>; 5],
+    data: ReadOnly<[u8; CONFIG_DATA_SIZE]>,
+}
+
+/// The size of the data field of the configuration space.
+const CONFIG_DATA_SIZE: usize = 128;
+
+/// Information about an absolute axis, as returned by [`VirtIOInput::abs_info`].
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, PartialEq)]
+pub struct AbsInfo {
+    /// The minimum value of the axis.
+    pub min: u32,
+    /// The maximum value of the axis.
+    pub max: u32,
+    /// The amount of noise which is filtered out of events.
+    pub fuzz: u32,
+    /// The size of the dead zone around the centre of the axis.
+    pub flat: u32,
+    /// The resolution of the axis, in units per millimetre (or per radian for rotational axes).
+    pub res: u32,
+}
+
+impl AbsInfo {
+    /// Scales the given value of the axis from its range to `0..size`, e.g. to map an absolute
+    /// tablet coordinate to a screen pixel. Values outside the range of the axis are clamped to
+    /// it.
+    ///
+    /// As in evdev, the value and the limits of the range are interpreted as signed.
+    pub fn scale(&self, value: u32, size: u32) -> u32 {
+        let min = i64::from(self.min as i32);
+        let max = i64::from(self.max as i32);
+        if size == 0 || max <= min {
+            return 0;
+        }
+        let value = i64::from(value as i32).clamp(min, max);
+        ((value - min) * (i64::from(size) - 1) / (max - min)) as u32
+    }
+}
+
+/// The IDs of an input device, as returned by [`VirtIOInput::ids`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, FromBytes, PartialEq)]
+pub struct DevIDs {
+    /// The `BUS_*` type of the bus which the device is attached to.
+    pub bustype: u16,
+    /// The vendor ID.
+    pub vendor: u16,
+    /// The product ID.
+    pub product: u16,
+    /// The version of the product.
+    pub version: u16,
+}
+
+/// A bitmap of input properties or event codes which a device supports, as returned by
+/// [`VirtIOInput::prop_bits`] and [`VirtIOInput::ev_bits`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InputBitmap {
+    bytes: [u8; CONFIG_DATA_SIZE],
+}
+
+impl InputBitmap {
+    /// Returns whether the given bit is set.
+    pub fn contains(&self, bit: u16) -> bool {
+        matches!(self.bytes.get(usize::from(bit / 8)), Some(byte) if byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Returns whether no bits are set.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|&byte| byte == 0)
+    }
+
+    /// Returns an iterator over the bits which are set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..(CONFIG_DATA_SIZE * 8) as u16).filter(move |&bit| self.contains(bit))
+    }
+
+    /// Returns the raw bitmap, in which bit `n` is bit `n % 8` of byte `n / 8`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The kind of an input device, as worked out by [`VirtIOInput::kind`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceKind {
+    /// A keyboard with at least the usual letter keys.
+    Keyboard,
+    /// A pointing device which reports relative motion.
+    Mouse,
+    /// A pointing device which reports absolute positions, not tied to a display.
+    Tablet,
+    /// A touch device mounted on a display, which reports absolute positions.
+    Touchscreen,
+}
+
+impl DeviceKind {
+    /// Classifies a device from its input properties and the `EV_KEY`, `EV_REL` and `EV_ABS`
+    /// event codes which it supports.
+    fn classify(
+        props: &InputBitmap,
+        keys: &InputBitmap,
+        rel: &InputBitmap,
+        abs: &InputBitmap,
+    ) -> Option<Self> {
+        let has_abs_position = (abs.contains(AbsAxis::X.0) && abs.contains(AbsAxis::Y.0))
+            || (abs.contains(AbsAxis::MT_POSITION_X.0) && abs.contains(AbsAxis::MT_POSITION_Y.0));
+        if has_abs_position {
+            if props.contains(INPUT_PROP_DIRECT)
+                || (keys.contains(KeyCode::BtnTouch.into())
+                    && !keys.contains(KeyCode::BtnLeft.into()))
+            {
+                Some(Self::Touchscreen)
+            } else {
+                Some(Self::Tablet)
+            }
+        } else if rel.contains(RelAxis::X.0) && rel.contains(RelAxis::Y.0) {
+            Some(Self::Mouse)
+        } else if [KeyCode::Enter, KeyCode::A, KeyCode::Z, KeyCode::Space]
+            .iter()
+            .all(|&key| keys.contains(key.into()))
+        {
+            Some(Self::Keyboard)
+        } else {
+            None
+        }
+    }
+}
+
+bitflags! {
+    /// Keyboard LEDs, as set by [`VirtIOInput::set_leds`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Leds: u8 {
+        /// Num Lock.
+        const NUM_LOCK = 1 << 0;
+        /// Caps Lock.
+        const CAPS_LOCK = 1 << 1;
+        /// Scroll Lock.
+        const SCROLL_LOCK = 1 << 2;
+    }
+}
+
+/// Both queues use the same `virtio_input_event` struct. `type`, `code` and `value`
+/// are filled according to the Linux input layer (evdev) interface.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, PartialEq)]
+pub struct InputEvent {
+    /// Event type.
+    pub event_type: u16,
+    /// Event code.
+    pub code: u16,
+    /// Event value.
+    pub value: u32,
+}
+
+impl InputEvent {
+    /// Creates an event with the given type, code and value.
+    pub fn new(event_type: u16, code: u16, value: u32) -> Self {
+        Self {
+            event_type,
+            code,
+            value,
+        }
+    }
+}
+
+const LED_NUML: u16 = 0x00;
+const LED_CAPSL: u16 = 0x01;
+const LED_SCROLLL: u16 = 0x02;
+
+const REP_DELAY: u16 = 0x00;
+const REP_PERIOD: u16 = 0x01;
+
+const INPUT_PROP_DIRECT: u16 = 0x01;
+
+const QUEUE_EVENT: u16 = 0;
+const QUEUE_STATUS: u16 = 1;
+
+// a parameter that can change
+const QUEUE_SIZE: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{convert::TryInto, mem::size_of};
+    use std::{sync::Mutex, thread};
+
+    fn config_space() -> Config {
+        Config {
+            select: WriteOnly::default(),
+            subsel: WriteOnly::default(),
+            size: ReadOnly::new(0),
+            _reversed: Default::default(),
+            data: ReadOnly::new([0; CONFIG_DATA_SIZE]),
+        }
+    }
+
+    fn fake_transport(config_space: &mut Config) -> (FakeTransport<Config>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Input,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    fn bitmap(bits: &[u16]) -> InputBitmap {
+        let mut bytes = [0; CONFIG_DATA_SIZE];
+        for &bit in bits {
+            bytes[usize::from(bit / 8)] |= 1 << (bit % 8);
+        }
+        InputBitmap { bytes }
+    }
+
+    #[test]
+    fn bitmap_bits() {
+        let touch = KeyCode::BtnTouch.into();
+        let bits = bitmap(&[0, 9, touch]);
+        assert!(bits.contains(9));
+        assert!(!bits.contains(8));
+        assert!(!bits.contains(u16::MAX));
+        assert_eq!(bits.iter().collect::<Vec<_>>(), vec![0, 9, touch]);
+        assert!(bitmap(&[]).is_empty());
+    }
+
+    #[test]
+    fn classify_devices() {
+        let none = bitmap(&[]);
+        let keyboard_keys = bitmap(&[
+            KeyCode::Enter.into(),
+            KeyCode::A.into(),
+            KeyCode::Z.into(),
+            KeyCode::Space.into(),
+        ]);
+        let xy = bitmap(&[0, 1]);
+        assert_eq!(
+            DeviceKind::classify(&none, &keyboard_keys, &none, &none),
+            Some(DeviceKind::Keyboard)
+        );
+        assert_eq!(
+            DeviceKind::classify(&none, &bitmap(&[KeyCode::BtnLeft.into()]), &xy, &none),
+            Some(DeviceKind::Mouse)
+        );
+        assert_eq!(
+            DeviceKind::classify(&none, &bitmap(&[KeyCode::BtnLeft.into()]), &none, &xy),
+            Some(DeviceKind::Tablet)
+        );
+        assert_eq!(
+            DeviceKind::classify(
+                &bitmap(&[INPUT_PROP_DIRECT]),
+                &bitmap(&[KeyCode::BtnTouch.into()]),
+                &none,
+                &bitmap(&[AbsAxis::MT_POSITION_X.0, AbsAxis::MT_POSITION_Y.0]),
+            ),
+            Some(DeviceKind::Touchscreen)
+        );
+        assert_eq!(DeviceKind::classify(&none, &none, &none, &none), None);
+    }
+
+    #[test]
+    fn query_abs_info() {
+        let abs_info = AbsInfo {
+            min: 0,
+            max: 32767,
+            fuzz: 0,
+            flat: 0,
+            res: 0,
+        };
+        let mut data = [0; CONFIG_DATA_SIZE];
+        data[..size_of::<AbsInfo>()].copy_from_slice(abs_info.as_bytes());
+        let mut config_space = Config {
+            size: ReadOnly::new(size_of::<AbsInfo>().try_into().unwrap()),
+            data: ReadOnly::new(data),
+            ..config_space()
+        };
+        let (transport, _) = fake_transport(&mut config_space);
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let info = input.abs_info(AbsAxis::X).unwrap();
+        assert_eq!(info, abs_info);
+        // Map the full range of the axis onto a 1024 pixel wide screen.
+        assert_eq!(info.scale(0, 1024), 0);
+        assert_eq!(info.scale(16384, 1024), 511);
+        assert_eq!(info.scale(32767, 1024), 1023);
+        assert_eq!(info.scale(u32::MAX, 1024), 0);
+    }
+
+    #[test]
+    fn query_out_of_range() {
+        let mut config_space = Config {
+            size: ReadOnly::new(200),
+            data: ReadOnly::new([0xff; CONFIG_DATA_SIZE]),
+            ..config_space()
+        };
+        let (transport, _) = fake_transport(&mut config_space);
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // The size reported by the device is limited to the data field.
+        let mut out = [0; CONFIG_DATA_SIZE];
+        assert_eq!(
+            input.query_config_select(InputConfigSelect::EvBits, 1, &mut out),
+            CONFIG_DATA_SIZE as u8
+        );
+        assert!(!input.ev_bits(EventType::KEY).is_empty());
+
+        // Codes which don't fit in a byte aren't queried.
+        assert!(input.ev_bits(EventType(0x100)).is_empty());
+        assert_eq!(input.abs_info(AbsAxis(0x100)), None);
+    }
+
+    #[test]
+    fn set_leds() {
+        let mut config_space = config_space();
+        let (transport, state) = fake_transport(&mut config_space);
+
+        // Simulate the device, recording the status events.
+        let handle = thread::spawn(move || {
+            let mut events = Vec::new();
+            for _ in 0..4 {
+                State::wait_until_queue_notified(&state, QUEUE_STATUS);
+                let request = state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(QUEUE_STATUS);
+                let event = InputEvent::read_from(request.as_slice()).unwrap();
+                events.push((event.event_type, event.code, event.value));
+            }
+            events
+        });
+
+        let mut input = VirtIOInput::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        input.set_leds(Leds::CAPS_LOCK).unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                (EventType::LED.0, LED_NUML, 0),
+                (EventType::LED.0, LED_CAPSL, 1),
+                (EventType::LED.0, LED_SCROLLL, 0),
+                (EventType::SYN.0, SYN_REPORT, 0),
+            ]
+        );
+    }
+}
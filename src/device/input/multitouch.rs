@@ -0,0 +1,249 @@
+//! Tracking of contacts reported with the evdev multi-touch protocol B.
+
+use super::{AbsAxis, AbsInfo, DecodedEvent};
+use alloc::{vec, vec::Vec};
+use core::{cmp::min, convert::TryFrom};
+
+/// The maximum number of slots which are tracked, however many the device claims to have.
+const MAX_SLOTS: usize = 64;
+
+/// What happened to a contact, as reported in [`TouchEvent::phase`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TouchPhase {
+    /// The contact touched the surface.
+    Down,
+    /// The contact moved.
+    Move,
+    /// The contact was lifted from the surface.
+    Up,
+}
+
+/// A change to a contact, as returned by [`MultiTouch::push`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TouchEvent {
+    /// The tracking ID which the device assigned to the contact, which stays the same while it
+    /// touches the surface.
+    pub tracking_id: i32,
+    /// The slot which the device reported the contact in.
+    pub slot: usize,
+    /// What happened to the contact.
+    pub phase: TouchPhase,
+    /// The horizontal position of the contact, scaled to the screen width. For
+    /// [`TouchPhase::Up`] this is the last position reported.
+    pub x: u32,
+    /// The vertical position of the contact, scaled to the screen height. For
+    /// [`TouchPhase::Up`] this is the last position reported.
+    pub y: u32,
+}
+
+/// The state of a multi-touch slot.
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    /// The contact in the slot, as of the current frame.
+    tracking_id: Option<i32>,
+    /// The contact in the slot, as of the last reported frame.
+    reported_id: Option<i32>,
+    x: i32,
+    y: i32,
+    /// Whether the position has changed in the current frame.
+    moved: bool,
+}
+
+/// Tracks the contacts of a touch device using the evdev multi-touch protocol B, in which each
+/// contact is assigned to a slot, and turns the `ABS_MT_*` events of each frame into touch down,
+/// move and up events.
+#[derive(Clone, Debug)]
+pub struct MultiTouch {
+    slots: Vec<Slot>,
+    /// The slot which `ABS_MT_*` events currently refer to.
+    current_slot: usize,
+    x_info: AbsInfo,
+    y_info: AbsInfo,
+    width: u32,
+    height: u32,
+    dropping: bool,
+}
+
+impl MultiTouch {
+    /// Creates a tracker for a device with the given number of slots and the given ranges of
+    /// `ABS_MT_POSITION_X` and `ABS_MT_POSITION_Y`, which scales positions to a screen of the
+    /// given size.
+    ///
+    /// [`VirtIOInput::multi_touch`](super::VirtIOInput::multi_touch) queries the slots and
+    /// ranges from the device.
+    pub fn new(
+        num_slots: usize,
+        x_info: AbsInfo,
+        y_info: AbsInfo,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        Self {
+            slots: vec![Slot::default(); min(num_slots, MAX_SLOTS)],
+            current_slot: 0,
+            x_info,
+            y_info,
+            width,
+            height,
+            dropping: false,
+        }
+    }
+
+    /// Changes the size of the screen which positions are scaled to, e.g. after the display has
+    /// been resized.
+    pub fn set_screen_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Returns the number of contacts currently touching the surface, as of the last frame.
+    pub fn active_contacts(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.reported_id.is_some())
+            .count()
+    }
+
+    /// Adds the given event to the current frame.
+    ///
+    /// When the event ends a frame, returns the changes to the contacts during the frame, in slot
+    /// order. Otherwise, or if nothing changed, returns an empty vector. If the device dropped
+    /// events, the rest of the frame is ignored.
+    pub fn push(&mut self, event: &DecodedEvent) -> Vec<TouchEvent> {
+        match *event {
+            DecodedEvent::SynReport => {
+                if core::mem::take(&mut self.dropping) {
+                    return Vec::new();
+                }
+                return self.report();
+            }
+            DecodedEvent::SynDropped => self.dropping = true,
+            DecodedEvent::Absolute { axis, value } if !self.dropping => {
+                if axis == AbsAxis::MT_SLOT {
+                    // Events for slots which we don't track are ignored.
+                    self.current_slot = usize::try_from(value).unwrap_or(usize::MAX);
+                } else if let Some(slot) = self.slots.get_mut(self.current_slot) {
+                    match axis {
+                        AbsAxis::MT_TRACKING_ID => {
+                            slot.tracking_id = if value < 0 { None } else { Some(value) };
+                        }
+                        AbsAxis::MT_POSITION_X => {
+                            slot.x = value;
+                            slot.moved = true;
+                        }
+                        AbsAxis::MT_POSITION_Y => {
+                            slot.y = value;
+                            slot.moved = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    /// Ends the current frame, returning the changes to the contacts during it.
+    fn report(&mut self) -> Vec<TouchEvent> {
+        let mut events = Vec::new();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let x = self.x_info.scale(slot.x as u32, self.width);
+            let y = self.y_info.scale(slot.y as u32, self.height);
+            let event = |tracking_id, phase| TouchEvent {
+                tracking_id,
+                slot: index,
+                phase,
+                x,
+                y,
+            };
+            match (slot.reported_id, slot.tracking_id) {
+                (Some(old), Some(new)) if old == new => {
+                    if slot.moved {
+                        events.push(event(new, TouchPhase::Move));
+                    }
+                }
+                (old, new) => {
+                    // The contact may have been lifted and replaced within a single frame.
+                    if let Some(old) = old {
+                        events.push(event(old, TouchPhase::Up));
+                    }
+                    if let Some(new) = new {
+                        events.push(event(new, TouchPhase::Down));
+                    }
+                }
+            }
+            slot.reported_id = slot.tracking_id;
+            slot.moved = false;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs(axis: AbsAxis, value: i32) -> DecodedEvent {
+        DecodedEvent::Absolute { axis, value }
+    }
+
+    #[test]
+    fn track_contacts() {
+        let range = AbsInfo {
+            min: 0,
+            max: 1000,
+            ..Default::default()
+        };
+        let mut touch = MultiTouch::new(10, range, range, 101, 201);
+        let touch_event = |tracking_id, slot, phase, x, y| TouchEvent {
+            tracking_id,
+            slot,
+            phase,
+            x,
+            y,
+        };
+
+        // Two fingers touch down in the same frame.
+        for event in [
+            abs(AbsAxis::MT_TRACKING_ID, 7),
+            abs(AbsAxis::MT_POSITION_X, 100),
+            abs(AbsAxis::MT_POSITION_Y, 500),
+            abs(AbsAxis::MT_SLOT, 1),
+            abs(AbsAxis::MT_TRACKING_ID, 8),
+            abs(AbsAxis::MT_POSITION_X, 1000),
+            abs(AbsAxis::MT_POSITION_Y, 0),
+        ] {
+            assert_eq!(touch.push(&event), vec![]);
+        }
+        assert_eq!(
+            touch.push(&DecodedEvent::SynReport),
+            vec![
+                touch_event(7, 0, TouchPhase::Down, 10, 100),
+                touch_event(8, 1, TouchPhase::Down, 100, 0),
+            ]
+        );
+        assert_eq!(touch.active_contacts(), 2);
+
+        // The second finger moves, and the first is lifted. The current slot is still 1.
+        touch.push(&abs(AbsAxis::MT_POSITION_Y, 1000));
+        touch.push(&abs(AbsAxis::MT_SLOT, 0));
+        touch.push(&abs(AbsAxis::MT_TRACKING_ID, -1));
+        assert_eq!(
+            touch.push(&DecodedEvent::SynReport),
+            vec![
+                touch_event(7, 0, TouchPhase::Up, 10, 100),
+                touch_event(8, 1, TouchPhase::Move, 100, 200),
+            ]
+        );
+        assert_eq!(touch.active_contacts(), 1);
+
+        // Nothing changed.
+        assert_eq!(touch.push(&DecodedEvent::SynReport), vec![]);
+
+        // Events for a slot which isn't tracked are ignored.
+        touch.push(&abs(AbsAxis::MT_SLOT, 10));
+        touch.push(&abs(AbsAxis::MT_TRACKING_ID, 9));
+        assert_eq!(touch.push(&DecodedEvent::SynReport), vec![]);
+    }
+}
@@ -0,0 +1,230 @@
+//! Packet buffers for VirtIO network devices.
+
+use super::{VirtioNetHdr, NET_HDR_SIZE};
+use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
+use crate::{nonnull_slice_from_raw_parts, pages, Error, Result};
+use alloc::{rc::Rc, vec::Vec};
+use core::{cell::RefCell, convert::TryFrom, mem::size_of, ptr::NonNull};
+use zerocopy::AsBytes;
+
+/// A pool of equally sized packet buffers, allocated up front in a single DMA region.
+///
+/// The region is permanently shared with the device, so buffers from it can be passed to the
+/// device without being copied or mapped. Each buffer holds a [`VirtioNetHdr`] immediately followed
+/// by the packet, so that both can be described by a single descriptor.
+pub(crate) struct BufferPool<H: Hal> {
+    dma: Dma<H>,
+    /// The size in bytes of each buffer, including the header.
+    buf_len: usize,
+    /// The indices of the buffers which are not currently in use.
+    free: RefCell<Vec<u16>>,
+}
+
+impl<H: Hal> BufferPool<H> {
+    /// Allocates a pool of `count` buffers of `buf_len` bytes each.
+    pub(crate) fn new(
+        count: usize,
+        buf_len: usize,
+        direction: BufferDirection,
+    ) -> Result<Rc<Self>> {
+        let buf_len = aligned_buf_len(buf_len);
+        let count = u16::try_from(count).map_err(|_| Error::InvalidParam)?;
+        let dma = Dma::new(pages(usize::from(count) * buf_len), direction)?;
+        Ok(Rc::new(Self {
+            dma,
+            buf_len,
+            free: RefCell::new((0..count).rev().collect()),
+        }))
+    }
+
+    /// Returns the size of the largest packet which fits in a buffer, not including the header.
+    pub(crate) fn max_packet_len(&self) -> usize {
+        self.buf_len - NET_HDR_SIZE
+    }
+
+    /// Takes an unused buffer out of the pool, if there are any left.
+    fn take(&self) -> Option<u16> {
+        self.free.borrow_mut().pop()
+    }
+
+    /// Returns a buffer to the pool.
+    fn give_back(&self, index: u16) {
+        self.free.borrow_mut().push(index);
+    }
+
+    /// Returns the physical address of the buffer with the given index, as seen by the device.
+    fn paddr(&self, index: u16) -> PhysAddr {
+        self.dma.paddr() + usize::from(index) * self.buf_len
+    }
+
+    /// Returns a pointer to the buffer with the given index.
+    fn vaddr(&self, index: u16) -> NonNull<[u8]> {
+        nonnull_slice_from_raw_parts(
+            self.dma.vaddr(usize::from(index) * self.buf_len),
+            self.buf_len,
+        )
+    }
+}
+
+/// Returns the size which a buffer of the given length is actually allocated with, rounded down to
+/// keep every buffer aligned so that the header can be referenced in place.
+pub(crate) fn aligned_buf_len(buf_len: usize) -> usize {
+    buf_len & !(size_of::<usize>() - 1)
+}
+
+/// A buffer used for transmitting.
+///
+/// This is taken from a pool of DMA memory owned by the device driver, and returned to the pool
+/// when it is dropped.
+pub struct TxBuffer<H: Hal> {
+    pool: Rc<BufferPool<H>>,
+    index: u16,
+    packet_len: usize,
+}
+
+impl<H: Hal> TxBuffer<H> {
+    /// Takes a buffer for a packet of length `packet_len` from the given pool, with an empty
+    /// header.
+    pub(crate) fn new(pool: &Rc<BufferPool<H>>, packet_len: usize) -> Result<Self> {
+        if packet_len > pool.max_packet_len() {
+            return Err(Error::InvalidParam);
+        }
+        let index = pool.take().ok_or(Error::QueueFull)?;
+        let mut tx_buf = Self {
+            pool: pool.clone(),
+            index,
+            packet_len,
+        };
+        VirtioNetHdr::default()
+            .write_to_prefix(tx_buf.as_bytes_mut())
+            .unwrap();
+        Ok(tx_buf)
+    }
+
+    /// Returns the network packet length.
+    pub fn packet_len(&self) -> usize {
+        self.packet_len
+    }
+
+    /// Sets the network packet length, so that the buffer can be reused for a packet of a different
+    /// size.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is too small.
+    pub fn set_packet_len(&mut self, packet_len: usize) -> Result {
+        if packet_len > self.pool.max_packet_len() {
+            return Err(Error::InvalidParam);
+        }
+        self.packet_len = packet_len;
+        Ok(())
+    }
+
+    /// Returns the network packet as a slice.
+    pub fn packet(&self) -> &[u8] {
+        &self.as_bytes()[NET_HDR_SIZE..]
+    }
+
+    /// Returns the network packet as a mutable slice.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.as_bytes_mut()[NET_HDR_SIZE..]
+    }
+
+    /// Returns the physical address of the header, which is immediately followed by the packet.
+    pub(crate) fn paddr(&self) -> PhysAddr {
+        self.pool.paddr(self.index)
+    }
+
+    /// Returns the header and the packet together.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        // Safe because the buffer is only accessed through this handle until it is given back to
+        // the pool, and the device doesn't write to it.
+        unsafe { &self.pool.vaddr(self.index).as_ref()[..NET_HDR_SIZE + self.packet_len] }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // Safe because the buffer is only accessed through this handle until it is given back to
+        // the pool, and the device doesn't access it while the handle is available to the caller.
+        unsafe { &mut self.pool.vaddr(self.index).as_mut()[..NET_HDR_SIZE + self.packet_len] }
+    }
+}
+
+impl<H: Hal> Drop for TxBuffer<H> {
+    fn drop(&mut self) {
+        self.pool.give_back(self.index);
+    }
+}
+
+/// A buffer used for receiving.
+///
+/// This is taken from a pool of DMA memory owned by the device driver, and returned to the pool
+/// when it is dropped, after which the driver will make it available to the device again.
+pub struct RxBuffer<H: Hal> {
+    pool: Rc<BufferPool<H>>,
+    index: u16,
+    packet_len: usize,
+}
+
+impl<H: Hal> RxBuffer<H> {
+    /// Takes an unused buffer from the given pool, if there are any left.
+    pub(crate) fn new(pool: &Rc<BufferPool<H>>) -> Option<Self> {
+        Some(Self {
+            pool: pool.clone(),
+            index: pool.take()?,
+            packet_len: 0,
+        })
+    }
+
+    /// Set the network packet length.
+    pub(crate) fn set_packet_len(&mut self, packet_len: usize) {
+        self.packet_len = packet_len
+    }
+
+    /// Returns the physical address and length of the whole buffer, including space for the
+    /// header.
+    pub(crate) fn shared_buffer(&self) -> (PhysAddr, usize) {
+        (self.pool.paddr(self.index), self.pool.buf_len)
+    }
+
+    /// Returns the network packet length (witout header).
+    pub const fn packet_len(&self) -> usize {
+        self.packet_len
+    }
+
+    /// Returns all data in the buffer, including both the header and the packet.
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safe because the buffer is only accessed through this handle until it is given back to
+        // the pool, and the device doesn't access it while the handle is available to the caller.
+        unsafe { self.pool.vaddr(self.index).as_ref() }
+    }
+
+    /// Returns all data in the buffer with the mutable reference,
+    /// including both the header and the packet.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // Safe because the buffer is only accessed through this handle until it is given back to
+        // the pool, and the device doesn't access it while the handle is available to the caller.
+        unsafe { self.pool.vaddr(self.index).as_mut() }
+    }
+
+    /// Returns the reference of the header.
+    pub fn header(&self) -> &VirtioNetHdr {
+        // Safe because the buffer is aligned and big enough for the header, and any bit pattern is
+        // a valid header.
+        unsafe { &*(self.as_bytes().as_ptr() as *const VirtioNetHdr) }
+    }
+
+    /// Returns the network packet as a slice.
+    pub fn packet(&self) -> &[u8] {
+        &self.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+    }
+
+    /// Returns the network packet as a mutable slice.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        let packet_len = self.packet_len;
+        &mut self.as_bytes_mut()[NET_HDR_SIZE..NET_HDR_SIZE + packet_len]
+    }
+}
+
+impl<H: Hal> Drop for RxBuffer<H> {
+    fn drop(&mut self) {
+        self.pool.give_back(self.index);
+    }
+}
@@ -0,0 +1,1013 @@
+//! Driver for VirtIO network devices.
+
+mod buffer;
+#[cfg(feature = "smoltcp")]
+mod phy;
+
+pub use self::buffer::{RxBuffer, TxBuffer};
+#[cfg(feature = "smoltcp")]
+pub use self::phy::{NetDevice, NetRxToken, NetTxToken};
+
+use self::buffer::{aligned_buf_len, BufferPool};
+use crate::hal::{BufferDirection, Hal};
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::volatile::{volread, ReadOnly};
+use crate::{Error, Result};
+use alloc::rc::Rc;
+use bitflags::bitflags;
+use core::{array, hint::spin_loop, mem::size_of};
+use log::{debug, info, warn};
+use zerocopy::{AsBytes, FromBytes};
+
+const MAX_BUFFER_LEN: usize = 65535;
+const MIN_BUFFER_LEN: usize = 1526;
+const NET_HDR_SIZE: usize = size_of::<VirtioNetHdr>();
+/// The length of an Ethernet header, which is not included in the MTU.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// The virtio network device is a virtual ethernet card.
+///
+/// It has enhanced rapidly and demonstrates clearly how support for new
+/// features are added to an existing device.
+/// Empty buffers are placed in one virtqueue for receiving packets, and
+/// outgoing packets are enqueued into another for transmission in that order.
+/// A third command queue is used to control advanced filtering features.
+///
+/// Packets can either be sent one at a time with [`send`](Self::send), which blocks until the
+/// device has consumed each one, or queued in batches with [`transmit_begin`](Self::transmit_begin)
+/// and [`notify_transmit`](Self::notify_transmit), and then reclaimed for reuse with
+/// [`poll_transmit`](Self::poll_transmit) once the device has finished with them.
+///
+/// Both transmit and receive buffers are taken from pools of DMA memory which are allocated when
+/// the driver is created and stay shared with the device, so packets are never copied or mapped
+/// on the data path. Use [`new_tx_buffer`](Self::new_tx_buffer) to get a buffer to send.
+pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: T,
+    mac: EthernetAddress,
+    mtu: Option<u16>,
+    /// Whether the header and packet may be described by a single descriptor.
+    any_layout: bool,
+    recv_queue: VirtQueue<H, QUEUE_SIZE>,
+    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The control queue, if `VIRTIO_NET_F_CTRL_VQ` was negotiated.
+    ctrl_queue: Option<VirtQueue<H, CTRL_QUEUE_SIZE>>,
+    /// The guest offloads which were negotiated, and so may be enabled.
+    supported_guest_offloads: GuestOffloads,
+    /// The guest offloads which are currently enabled.
+    guest_offloads: GuestOffloads,
+    rx_pool: Rc<BufferPool<H>>,
+    tx_pool: Rc<BufferPool<H>>,
+    /// Buffers which have been added to the receive queue and not yet used, indexed by token.
+    rx_buffers: [Option<RxBuffer<H>>; QUEUE_SIZE],
+    /// Buffers which have been added to the transmit queue and not yet reclaimed, indexed by token.
+    tx_buffers: [Option<TxBuffer<H>>; QUEUE_SIZE],
+    /// Whether any buffers have been added to the transmit queue since the device was last
+    /// notified.
+    tx_notify_pending: bool,
+    stats: NetStats,
+}
+
+/// Counters of the traffic and errors seen by a [`VirtIONet`] driver since it was created.
+///
+/// A snapshot can be taken at any time with [`VirtIONet::stats`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct NetStats {
+    /// The number of packets which the device has finished sending.
+    pub tx_packets: u64,
+    /// The number of bytes in the packets counted by `tx_packets`, not including headers.
+    pub tx_bytes: u64,
+    /// The number of packets received from the device.
+    pub rx_packets: u64,
+    /// The number of bytes in the packets counted by `rx_packets`, not including headers.
+    pub rx_bytes: u64,
+    /// The number of used receive buffers which were dropped because the device returned a token
+    /// which didn't match any buffer added by the driver.
+    pub rx_wrong_token: u64,
+    /// The number of received buffers which were dropped because they were too short to contain a
+    /// header.
+    pub rx_too_short: u64,
+    /// The number of times a packet couldn't be queued for transmission because the transmit
+    /// queue was full.
+    pub tx_queue_full: u64,
+    /// The number of notifications sent to the device, for either queue.
+    pub notifications_sent: u64,
+    /// The number of notifications which weren't sent because the device had suppressed them.
+    pub notifications_suppressed: u64,
+    /// The number of interrupts acknowledged by [`VirtIONet::ack_interrupt`].
+    pub interrupts_acked: u64,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
+    /// Create a new VirtIO-Net driver.
+    ///
+    /// `buf_len` is the size of each receive and transmit buffer, including the
+    /// [`VirtioNetHdr`]. The MTU reported by the device is only used if a packet of that size fits
+    /// in a buffer.
+    pub fn new(mut transport: T, buf_len: usize) -> Result<Self> {
+        let config = transport.config_space::<Config>()?;
+        let mut negotiated_features = Features::empty();
+        transport.begin_init(|features| {
+            let features = Features::from_bits_truncate(features);
+            info!("Device features {:?}", features);
+            let mut supported_features = Features::MAC | Features::STATUS | Features::ANY_LAYOUT;
+            // Accepting the MTU requires receive buffers big enough for a packet of that size.
+            if features.contains(Features::MTU) {
+                // Safe because config points to a valid MMIO region for the config space.
+                let mtu = usize::from(unsafe { volread!(config, mtu) });
+                if mtu + ETHERNET_HEADER_LEN + NET_HDR_SIZE <= aligned_buf_len(buf_len) {
+                    supported_features |= Features::MTU;
+                } else {
+                    warn!(
+                        "Ignoring MTU {} which doesn't fit in buffers of {} bytes",
+                        mtu, buf_len
+                    );
+                }
+            }
+            // Only accept guest offloads if they can be turned off again, as the rest of the
+            // driver doesn't expect partially checksummed or coalesced packets.
+            if features.contains(Features::CTRL_VQ | Features::CTRL_GUEST_OFFLOADS) {
+                supported_features |= Features::CTRL_VQ
+                    | Features::CTRL_GUEST_OFFLOADS
+                    | Features::from_bits_truncate(GuestOffloads::all().bits());
+            }
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
+        });
+        // read configuration space
+        let mac;
+        let mut mtu = None;
+        // Safe because config points to a valid MMIO region for the config space.
+        unsafe {
+            mac = volread!(config, mac);
+            if negotiated_features.contains(Features::MTU) {
+                mtu = Some(volread!(config, mtu));
+            }
+            debug!(
+                "Got MAC={:02x?}, status={:?}, mtu={:?}",
+                mac,
+                volread!(config, status),
+                mtu
+            );
+        }
+
+        if !(MIN_BUFFER_LEN..=MAX_BUFFER_LEN).contains(&buf_len) {
+            warn!(
+                "Receive buffer len {} is not in range [{}, {}]",
+                buf_len, MIN_BUFFER_LEN, MAX_BUFFER_LEN
+            );
+            return Err(Error::InvalidParam);
+        }
+
+        let send_queue = VirtQueue::new(&mut transport, QUEUE_TRANSMIT)?;
+        let recv_queue = VirtQueue::new(&mut transport, QUEUE_RECEIVE)?;
+        let rx_pool = BufferPool::new(QUEUE_SIZE, buf_len, BufferDirection::DeviceToDriver)?;
+        let tx_pool = BufferPool::new(QUEUE_SIZE, buf_len, BufferDirection::DriverToDevice)?;
+        let ctrl_queue = if negotiated_features.contains(Features::CTRL_VQ) {
+            Some(VirtQueue::new(&mut transport, QUEUE_CONTROL)?)
+        } else {
+            None
+        };
+        let supported_guest_offloads =
+            GuestOffloads::from_bits_truncate(negotiated_features.bits());
+
+        transport.finish_init();
+
+        let mut net = VirtIONet {
+            transport,
+            mac,
+            mtu,
+            any_layout: negotiated_features.contains(Features::ANY_LAYOUT),
+            recv_queue,
+            send_queue,
+            ctrl_queue,
+            supported_guest_offloads,
+            guest_offloads: supported_guest_offloads,
+            rx_pool,
+            tx_pool,
+            rx_buffers: array::from_fn(|_| None),
+            tx_buffers: array::from_fn(|_| None),
+            tx_notify_pending: false,
+            stats: NetStats::default(),
+        };
+        // All negotiated offloads start off enabled, so turn them off before any packets can be
+        // received.
+        if !net.supported_guest_offloads.is_empty() {
+            net.set_guest_offloads(GuestOffloads::empty())?;
+        }
+        net.fill_rx_queue()?;
+        Ok(net)
+    }
+
+    /// Acknowledge interrupt.
+    pub fn ack_interrupt(&mut self) -> bool {
+        let acked = self.transport.ack_interrupt();
+        if acked {
+            self.stats.interrupts_acked += 1;
+        }
+        acked
+    }
+
+    /// Returns a snapshot of the traffic and error counters.
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    /// Get MAC address.
+    pub fn mac_address(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    /// Returns the maximum MTU reported by the device, if the `VIRTIO_NET_F_MTU` feature was
+    /// negotiated.
+    ///
+    /// This does not include the Ethernet header.
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    /// Returns the size of the largest packet, including the Ethernet header, which fits in a
+    /// transmit or receive buffer.
+    pub fn max_packet_len(&self) -> usize {
+        self.tx_pool.max_packet_len()
+    }
+
+    /// Returns the guest receive offloads which the device supports, and so can be enabled with
+    /// [`set_guest_offloads`](Self::set_guest_offloads).
+    ///
+    /// This is empty unless the device supports `VIRTIO_NET_F_CTRL_GUEST_OFFLOADS`.
+    pub fn supported_guest_offloads(&self) -> GuestOffloads {
+        self.supported_guest_offloads
+    }
+
+    /// Returns the guest receive offloads which are currently enabled.
+    ///
+    /// All offloads are disabled when the driver is created.
+    pub fn guest_offloads(&self) -> GuestOffloads {
+        self.guest_offloads
+    }
+
+    /// Enables exactly the given guest receive offloads, and disables all others.
+    ///
+    /// While any offloads are enabled the device may pass on packets with partial checksums or
+    /// coalesced segments, as described by the [`VirtioNetHdr`] of each received buffer, so the
+    /// caller must be prepared to handle them. If TSO or UFO are enabled then the receive buffers
+    /// should be large enough for a coalesced packet. Disabling all offloads ensures that the
+    /// original frames are seen.
+    ///
+    /// Returns [`Error::Unsupported`] if any of the offloads are not in
+    /// [`supported_guest_offloads`](Self::supported_guest_offloads), or [`Error::IoError`] if the
+    /// device rejects the combination.
+    pub fn set_guest_offloads(&mut self, offloads: GuestOffloads) -> Result {
+        if !self.supported_guest_offloads.contains(offloads) {
+            return Err(Error::Unsupported);
+        }
+        let ctrl_queue = self.ctrl_queue.as_mut().ok_or(Error::Unsupported)?;
+        let header = CtrlHeader {
+            class: CtrlClass::GUEST_OFFLOADS,
+            command: CTRL_GUEST_OFFLOADS_SET,
+        };
+        let data = u64::to_le_bytes(offloads.bits());
+        let mut ack = CtrlAck::ERR;
+        ctrl_queue.add_notify_wait_pop(
+            &[header.as_bytes(), &data],
+            &mut [ack.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        if ack != CtrlAck::OK {
+            warn!("Device rejected guest offloads {:?}", offloads);
+            return Err(Error::IoError);
+        }
+        self.guest_offloads = offloads;
+        Ok(())
+    }
+
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.send_queue.available_desc() >= self.tx_descriptors()
+    }
+
+    /// Whether can receive packet.
+    pub fn can_recv(&self) -> bool {
+        self.recv_queue.can_pop()
+    }
+
+    /// Returns the number of descriptors needed for each transmitted packet.
+    fn tx_descriptors(&self) -> usize {
+        if self.any_layout {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Adds every receive buffer which isn't currently in use to the receive queue, and notifies
+    /// the device if any were added.
+    fn fill_rx_queue(&mut self) -> Result {
+        let mut added = false;
+        // There are as many descriptors as buffers in the pool, so there is always room for the
+        // unused buffers.
+        while let Some(rx_buf) = RxBuffer::new(&self.rx_pool) {
+            // Safe because the buffer is part of `rx_pool`, which stays shared with the device for
+            // as long as the queue exists, and it is kept in `rx_buffers` until the device has
+            // used it.
+            let token = unsafe { self.recv_queue.add_shared(&[], &[rx_buf.shared_buffer()]) }?;
+            // `rx_buffers[token]` is expected to be `None` since the descriptor starting at
+            // `token` was free.
+            if self.rx_buffers[token as usize].is_some() {
+                return Err(Error::WrongToken);
+            }
+            self.rx_buffers[token as usize] = Some(rx_buf);
+            added = true;
+        }
+        if added {
+            let should_notify = self.recv_queue.should_notify();
+            self.notify_if(QUEUE_RECEIVE, should_notify);
+        }
+        Ok(())
+    }
+
+    /// Receives a [`RxBuffer`] from network. If currently no data, returns an
+    /// error with type [`Error::NotReady`].
+    ///
+    /// It will try to pop a buffer that completed data reception in the
+    /// NIC queue.
+    pub fn receive(&mut self) -> Result<RxBuffer<H>> {
+        self.fill_rx_queue()?;
+        let token = self.recv_queue.peek_used().ok_or(Error::NotReady)?;
+        let Some(mut rx_buf) = self
+            .rx_buffers
+            .get_mut(usize::from(token))
+            .and_then(Option::take)
+        else {
+            // The device used a descriptor chain which we didn't give it a buffer for, so its
+            // descriptors can't be recycled. Skip the entry so that later packets can still be
+            // received, and so that it is only counted once.
+            self.recv_queue.skip_used();
+            self.stats.rx_wrong_token += 1;
+            return Err(Error::WrongToken);
+        };
+        let len = self.recv_queue.pop_used_shared(token)? as usize;
+        let Some(packet_len) = len.checked_sub(NET_HDR_SIZE) else {
+            // Dropping the buffer returns it to the pool, so it will be reused.
+            self.stats.rx_too_short += 1;
+            return Err(Error::IoError);
+        };
+        rx_buf.set_packet_len(packet_len);
+        self.stats.rx_packets += 1;
+        self.stats.rx_bytes += packet_len as u64;
+        Ok(rx_buf)
+    }
+
+    /// Gives back the ownership of `rx_buf`, and recycles it for next use.
+    ///
+    /// It will add the buffer back to the NIC queue. Dropping the buffer has the same effect,
+    /// except that it is only added back on the next call to [`receive`](Self::receive).
+    pub fn recycle_rx_buffer(&mut self, rx_buf: RxBuffer<H>) -> Result {
+        drop(rx_buf);
+        self.fill_rx_queue()
+    }
+
+    /// Allocate a new buffer for transmitting a packet of `packet_len` bytes.
+    ///
+    /// Returns [`Error::InvalidParam`] if the packet is too big for the buffers, or
+    /// [`Error::QueueFull`] if all transmit buffers are already in use.
+    pub fn new_tx_buffer(&self, packet_len: usize) -> Result<TxBuffer<H>> {
+        TxBuffer::new(&self.tx_pool, packet_len)
+    }
+
+    /// Sends a [`TxBuffer`] to the network, and blocks until the request
+    /// completed.
+    ///
+    /// This can't be used while there are buffers queued by
+    /// [`transmit_begin`](Self::transmit_begin) which have not yet been reclaimed by
+    /// [`poll_transmit`](Self::poll_transmit), and will return [`Error::AlreadyUsed`] in that case.
+    pub fn send(&mut self, tx_buf: TxBuffer<H>) -> Result {
+        if self.tx_buffers.iter().any(Option::is_some) {
+            return Err(Error::AlreadyUsed);
+        }
+        let token = self.transmit_begin(tx_buf)?;
+        self.notify_transmit();
+        // Wait until the device has sent the packet.
+        while self.send_queue.peek_used() != Some(token) {
+            spin_loop();
+        }
+        self.poll_transmit()?;
+        Ok(())
+    }
+
+    /// Adds a [`TxBuffer`] to the transmit queue, and returns immediately without waiting for the
+    /// device to send it.
+    ///
+    /// The device is not notified until [`notify_transmit`](Self::notify_transmit) is called, so
+    /// several buffers can be queued and then submitted together. Once the device has sent the
+    /// packet the buffer can be reclaimed with [`poll_transmit`](Self::poll_transmit).
+    ///
+    /// Returns the token of the queued request. If there is not enough space in the queue, returns
+    /// [`Error::QueueFull`] and drops the buffer, so callers should check
+    /// [`can_send`](Self::can_send) first.
+    pub fn transmit_begin(&mut self, tx_buf: TxBuffer<H>) -> Result<u16> {
+        if !self.can_send() {
+            self.stats.tx_queue_full += 1;
+            return Err(Error::QueueFull);
+        }
+        let paddr = tx_buf.paddr();
+        let len = tx_buf.as_bytes().len();
+        // Safe because the buffer is part of `tx_pool`, which stays shared with the device for as
+        // long as the queue exists, and it is kept in `tx_buffers` until `poll_transmit` pops it
+        // from the queue.
+        let token = unsafe {
+            if self.any_layout {
+                self.send_queue.add_shared(&[(paddr, len)], &[])
+            } else {
+                self.send_queue.add_shared(
+                    &[
+                        (paddr, NET_HDR_SIZE),
+                        (paddr + NET_HDR_SIZE, len - NET_HDR_SIZE),
+                    ],
+                    &[],
+                )
+            }
+        }?;
+        // `tx_buffers[token]` is expected to be `None` since the descriptor chain starting at
+        // `token` was free.
+        if self.tx_buffers[token as usize].is_some() {
+            return Err(Error::WrongToken);
+        }
+        self.tx_buffers[token as usize] = Some(tx_buf);
+        self.tx_notify_pending = true;
+        Ok(token)
+    }
+
+    /// Notifies the device about all buffers added by [`transmit_begin`](Self::transmit_begin)
+    /// since the last notification, unless the device has suppressed notifications.
+    pub fn notify_transmit(&mut self) {
+        if self.tx_notify_pending {
+            self.tx_notify_pending = false;
+            let should_notify = self.send_queue.should_notify();
+            self.notify_if(QUEUE_TRANSMIT, should_notify);
+        }
+    }
+
+    /// Notifies the given queue if `should_notify` is true, and counts the notification as either
+    /// sent or suppressed.
+    fn notify_if(&mut self, queue: u16, should_notify: bool) {
+        if should_notify {
+            self.transport.notify(queue);
+            self.stats.notifications_sent += 1;
+        } else {
+            self.stats.notifications_suppressed += 1;
+        }
+    }
+
+    /// Reclaims a [`TxBuffer`] which the device has finished sending, so that it can be reused or
+    /// dropped. If no queued packet has been sent yet, returns an error with type
+    /// [`Error::NotReady`].
+    ///
+    /// Buffers are returned in the order in which the device finished with them.
+    pub fn poll_transmit(&mut self) -> Result<TxBuffer<H>> {
+        let token = self.send_queue.peek_used().ok_or(Error::NotReady)?;
+        let tx_buf = self.tx_buffers[token as usize]
+            .take()
+            .ok_or(Error::WrongToken)?;
+        self.send_queue.pop_used_shared(token)?;
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += tx_buf.packet_len() as u64;
+        Ok(tx_buf)
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONet<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.queue_unset(QUEUE_RECEIVE);
+        self.transport.queue_unset(QUEUE_TRANSMIT);
+        if self.ctrl_queue.is_some() {
+            self.transport.queue_unset(QUEUE_CONTROL);
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Features: u64 {
+        /// Device handles packets with partial checksum.
+        /// This "checksum offload" is a common feature on modern network cards.
+        const CSUM = 1 << 0;
+        /// Driver handles packets with partial checksum.
+        const GUEST_CSUM = 1 << 1;
+        /// Control channel offloads reconfiguration support.
+        const CTRL_GUEST_OFFLOADS = 1 << 2;
+        /// Device maximum MTU reporting is supported.
+        ///
+        /// If offered by the device, device advises driver about the value of
+        /// its maximum MTU. If negotiated, the driver uses mtu as the maximum
+        /// MTU value.
+        const MTU = 1 << 3;
+        /// Device has given MAC address.
+        const MAC = 1 << 5;
+        /// Device handles packets with any GSO type. (legacy)
+        const GSO = 1 << 6;
+        /// Driver can receive TSOv4.
+        const GUEST_TSO4 = 1 << 7;
+        /// Driver can receive TSOv6.
+        const GUEST_TSO6 = 1 << 8;
+        /// Driver can receive TSO with ECN.
+        const GUEST_ECN = 1 << 9;
+        /// Driver can receive UFO.
+        const GUEST_UFO = 1 << 10;
+        /// Device can receive TSOv4.
+        const HOST_TSO4 = 1 << 11;
+        /// Device can receive TSOv6.
+        const HOST_TSO6 = 1 << 12;
+        /// Device can receive TSO with ECN.
+        const HOST_ECN = 1 << 13;
+        /// Device can receive UFO.
+        const HOST_UFO = 1 << 14;
+        /// Driver can merge receive buffers.
+        const MRG_RXBUF = 1 << 15;
+        /// Configuration status field is available.
+        const STATUS = 1 << 16;
+        /// Control channel is available.
+        const CTRL_VQ = 1 << 17;
+        /// Control channel RX mode support.
+        const CTRL_RX = 1 << 18;
+        /// Control channel VLAN filtering.
+        const CTRL_VLAN = 1 << 19;
+        ///
+        const CTRL_RX_EXTRA = 1 << 20;
+        /// Driver can send gratuitous packets.
+        const GUEST_ANNOUNCE = 1 << 21;
+        /// Device supports multiqueue with automatic receive steering.
+        const MQ = 1 << 22;
+        /// Set MAC address through control channel.
+        const CTL_MAC_ADDR = 1 << 23;
+
+        // device independent
+        const ANY_LAYOUT = 1 << 27;
+        const RING_INDIRECT_DESC = 1 << 28;
+        const RING_EVENT_IDX = 1 << 29;
+        const VERSION_1 = 1 << 32; // legacy
+    }
+}
+
+bitflags! {
+    /// Receive offloads which the device may apply to packets before passing them to the driver.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct GuestOffloads: u64 {
+        /// The device may pass on packets with a partial checksum, or mark checksums as already
+        /// validated.
+        const CSUM = 1 << 1;
+        /// The device may coalesce TCPv4 segments.
+        const TSO4 = 1 << 7;
+        /// The device may coalesce TCPv6 segments.
+        const TSO6 = 1 << 8;
+        /// The device may coalesce TCP segments with ECN.
+        const ECN = 1 << 9;
+        /// The device may coalesce UDP fragments.
+        const UFO = 1 << 10;
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Status: u16 {
+        const LINK_UP = 1;
+        const ANNOUNCE = 2;
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct InterruptStatus : u32 {
+        const USED_RING_UPDATE = 1 << 0;
+        const CONFIGURATION_CHANGE = 1 << 1;
+    }
+}
+
+#[repr(C)]
+struct Config {
+    mac: ReadOnly<EthernetAddress>,
+    status: ReadOnly<Status>,
+    max_virtqueue_pairs: ReadOnly<u16>,
+    mtu: ReadOnly<u16>,
+}
+
+type EthernetAddress = [u8; 6];
+
+/// VirtIO 5.1.6 Device Operation:
+///
+/// Packets are transmitted by placing them in the transmitq1. . .transmitqN,
+/// and buffers for incoming packets are placed in the receiveq1. . .receiveqN.
+/// In each case, the packet itself is preceded by a header.
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, FromBytes)]
+pub struct VirtioNetHdr {
+    flags: Flags,
+    gso_type: GsoType,
+    hdr_len: u16, // cannot rely on this
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    // num_buffers: u16, // only available when the feature MRG_RXBUF is negotiated.
+    // payload starts from here
+}
+
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, PartialEq)]
+#[repr(transparent)]
+struct Flags(u8);
+
+bitflags! {
+    impl Flags: u8 {
+        const NEEDS_CSUM = 1;
+        const DATA_VALID = 2;
+        const RSC_INFO   = 4;
+    }
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, PartialEq)]
+struct GsoType(u8);
+
+impl GsoType {
+    const NONE: GsoType = GsoType(0);
+    const TCPV4: GsoType = GsoType(1);
+    const UDP: GsoType = GsoType(3);
+    const TCPV6: GsoType = GsoType(4);
+    const ECN: GsoType = GsoType(0x80);
+}
+
+/// The header of a request on the control queue, which is followed by the command-specific data
+/// and then a [`CtrlAck`] written by the device.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes)]
+struct CtrlHeader {
+    class: CtrlClass,
+    command: u8,
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, PartialEq)]
+struct CtrlClass(u8);
+
+impl CtrlClass {
+    const GUEST_OFFLOADS: CtrlClass = CtrlClass(5);
+}
+
+/// The command of the `GUEST_OFFLOADS` class, which takes a little-endian `u64` of offload bits.
+const CTRL_GUEST_OFFLOADS_SET: u8 = 0;
+
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, PartialEq)]
+struct CtrlAck(u8);
+
+impl CtrlAck {
+    const OK: CtrlAck = CtrlAck(0);
+    const ERR: CtrlAck = CtrlAck(1);
+}
+
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+/// The control queue comes after the single receive and transmit queue pair, as multiqueue is not
+/// negotiated.
+const QUEUE_CONTROL: u16 = 2;
+/// A control request needs three descriptors, for the header, the data and the ack.
+const CTRL_QUEUE_SIZE: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use core::ptr::NonNull;
+    use std::{
+        sync::{atomic::Ordering, Mutex},
+        thread,
+    };
+
+    const QUEUE_SIZE: usize = 4;
+
+    fn tx_buffer(
+        net: &VirtIONet<FakeHal, FakeTransport<Config>, QUEUE_SIZE>,
+        packet: &[u8],
+    ) -> TxBuffer<FakeHal> {
+        let mut tx_buf = net.new_tx_buffer(packet.len()).unwrap();
+        tx_buf.packet_mut().copy_from_slice(packet);
+        tx_buf
+    }
+
+    #[test]
+    fn transmit_batch() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+
+        // Queue two packets, which fills the queue, without notifying the device.
+        net.transmit_begin(tx_buffer(&net, b"first")).unwrap();
+        net.transmit_begin(tx_buffer(&net, b"second")).unwrap();
+        assert!(!net.can_send());
+        assert!(matches!(
+            net.transmit_begin(tx_buffer(&net, b"third")),
+            Err(Error::QueueFull)
+        ));
+        assert!(matches!(
+            net.send(tx_buffer(&net, b"third")),
+            Err(Error::AlreadyUsed)
+        ));
+        assert!(!state.lock().unwrap().queues[usize::from(QUEUE_TRANSMIT)]
+            .notified
+            .load(Ordering::SeqCst));
+        assert!(matches!(net.poll_transmit(), Err(Error::NotReady)));
+
+        // A single notification covers both of them.
+        net.notify_transmit();
+        assert!(state.lock().unwrap().queues[usize::from(QUEUE_TRANSMIT)]
+            .notified
+            .swap(false, Ordering::SeqCst));
+        net.notify_transmit();
+        assert!(!state.lock().unwrap().queues[usize::from(QUEUE_TRANSMIT)]
+            .notified
+            .load(Ordering::SeqCst));
+
+        for expected in [&b"first"[..], b"second"] {
+            let data = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMIT);
+            assert_eq!(&data[..NET_HDR_SIZE], VirtioNetHdr::default().as_bytes());
+            assert_eq!(&data[NET_HDR_SIZE..], expected);
+        }
+
+        // The buffers can now be reclaimed, in order.
+        assert_eq!(net.poll_transmit().unwrap().packet(), b"first");
+        assert_eq!(net.poll_transmit().unwrap().packet(), b"second");
+        assert!(matches!(net.poll_transmit(), Err(Error::NotReady)));
+        assert!(net.can_send());
+    }
+
+    #[test]
+    fn stats() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+        // The receive queue is notified once when the driver is created.
+        assert_eq!(
+            net.stats(),
+            NetStats {
+                notifications_sent: 1,
+                ..NetStats::default()
+            }
+        );
+
+        // Send a packet, and try to send more than fit in the queue.
+        net.transmit_begin(tx_buffer(&net, b"hello")).unwrap();
+        net.transmit_begin(tx_buffer(&net, b"world!")).unwrap();
+        assert!(matches!(
+            net.transmit_begin(tx_buffer(&net, b"full")),
+            Err(Error::QueueFull)
+        ));
+        net.notify_transmit();
+        for _ in 0..2 {
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMIT);
+        }
+        net.poll_transmit().unwrap();
+        net.poll_transmit().unwrap();
+
+        // Receive a packet, and one which is too short to have a header.
+        let mut packet = VirtioNetHdr::default().as_bytes().to_vec();
+        packet.extend_from_slice(b"abc");
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &packet);
+        let rx_buf = net.receive().unwrap();
+        net.recycle_rx_buffer(rx_buf).unwrap();
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &[1, 2, 3]);
+        assert!(matches!(net.receive(), Err(Error::IoError)));
+
+        assert_eq!(
+            net.stats(),
+            NetStats {
+                tx_packets: 2,
+                tx_bytes: 11,
+                rx_packets: 1,
+                rx_bytes: 3,
+                rx_wrong_token: 0,
+                rx_too_short: 1,
+                tx_queue_full: 1,
+                notifications_sent: 3,
+                notifications_suppressed: 0,
+                interrupts_acked: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn receive_wrong_token() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+
+        let mut packet = VirtioNetHdr::default().as_bytes().to_vec();
+        packet.extend_from_slice(b"abc");
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &packet);
+        // Lose track of the buffer which the device used, as if it had returned the wrong token.
+        let token = net.recv_queue.peek_used().unwrap();
+        core::mem::forget(net.rx_buffers[usize::from(token)].take());
+
+        // The bad entry is only reported once, and doesn't stop later packets being received.
+        assert!(matches!(net.receive(), Err(Error::WrongToken)));
+        assert!(matches!(net.receive(), Err(Error::NotReady)));
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &packet);
+        assert_eq!(net.receive().unwrap().packet(), b"abc");
+        assert_eq!(net.stats().rx_wrong_token, 1);
+        assert_eq!(net.stats().rx_packets, 1);
+    }
+
+    #[test]
+    fn mtu_too_big_for_buffers() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(9000),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: (Features::MAC | Features::MTU).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+
+        assert_eq!(net.mtu(), None);
+        assert_eq!(net.max_packet_len(), 2048 - NET_HDR_SIZE);
+        assert_eq!(state.lock().unwrap().driver_features, Features::MAC.bits());
+    }
+
+    #[test]
+    fn set_guest_offloads() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: (Features::MAC
+                | Features::CTRL_VQ
+                | Features::CTRL_GUEST_OFFLOADS
+                | Features::GUEST_CSUM
+                | Features::GUEST_TSO4)
+                .bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Start a thread to simulate the device handling control requests. It accepts the first
+        // two and rejects the third.
+        let handle = thread::spawn(move || {
+            for (expected, ack) in [
+                (GuestOffloads::empty(), CtrlAck::OK),
+                (GuestOffloads::CSUM, CtrlAck::OK),
+                (GuestOffloads::TSO4, CtrlAck::ERR),
+            ] {
+                State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+                state.lock().unwrap().read_write_queue::<CTRL_QUEUE_SIZE>(
+                    QUEUE_CONTROL,
+                    |request| {
+                        let mut expected_request = CtrlHeader {
+                            class: CtrlClass::GUEST_OFFLOADS,
+                            command: CTRL_GUEST_OFFLOADS_SET,
+                        }
+                        .as_bytes()
+                        .to_vec();
+                        expected_request.extend_from_slice(&expected.bits().to_le_bytes());
+                        assert_eq!(request, expected_request);
+                        ack.as_bytes().to_vec()
+                    },
+                );
+            }
+        });
+
+        let mut net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+        assert_eq!(
+            net.supported_guest_offloads(),
+            GuestOffloads::CSUM | GuestOffloads::TSO4
+        );
+        assert_eq!(net.guest_offloads(), GuestOffloads::empty());
+
+        assert_eq!(
+            net.set_guest_offloads(GuestOffloads::UFO),
+            Err(Error::Unsupported)
+        );
+        net.set_guest_offloads(GuestOffloads::CSUM).unwrap();
+        assert_eq!(net.guest_offloads(), GuestOffloads::CSUM);
+        assert_eq!(
+            net.set_guest_offloads(GuestOffloads::TSO4),
+            Err(Error::IoError)
+        );
+        assert_eq!(net.guest_offloads(), GuestOffloads::CSUM);
+
+        handle.join().unwrap();
+    }
+}
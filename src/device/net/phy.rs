@@ -0,0 +1,295 @@
+//! Implementation of the smoltcp `phy::Device` trait for VirtIO network devices.
+
+use super::{GuestOffloads, RxBuffer, VirtIONet, ETHERNET_HEADER_LEN};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Error;
+use alloc::vec;
+use core::cell::RefCell;
+use core::cmp::min;
+use log::warn;
+use smoltcp::phy::{self, Checksum, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
+
+/// The IP MTU to assume if the device doesn't report one.
+const DEFAULT_MTU: usize = 1500;
+
+/// An adapter which lets a [`VirtIONet`] be used as a smoltcp [`Device`](phy::Device).
+///
+/// Received packets are passed to smoltcp in place, and their buffers are given back to the device
+/// as soon as the corresponding [`NetRxToken`] is consumed or dropped.
+///
+/// Checksums are computed in software. They are also verified in software unless the
+/// [`GuestOffloads::CSUM`] offload is enabled, in which case the device may pass on packets with
+/// partial checksums, so smoltcp is told not to verify TCP and UDP checksums. The segmentation
+/// offloads should be left disabled (as they are by default) on a driver wrapped by this adapter.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use smoltcp::iface::{Config, Interface, SocketSet};
+/// use smoltcp::time::Instant;
+/// use virtio_drivers::device::net::{NetDevice, VirtIONet};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let net = VirtIONet::<HalImpl, _, 16>::new(transport, 2048)?;
+/// let mut device = NetDevice::new(net);
+///
+/// let config = Config::new(device.ethernet_address().into());
+/// let mut iface = Interface::new(config, &mut device, Instant::ZERO);
+/// let mut sockets = SocketSet::new(vec![]);
+/// iface.poll(Instant::ZERO, &mut device, &mut sockets);
+/// # Ok(())
+/// # }
+/// ```
+pub struct NetDevice<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    inner: RefCell<VirtIONet<H, T, QUEUE_SIZE>>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> NetDevice<H, T, QUEUE_SIZE> {
+    /// Wraps the given VirtIO network driver.
+    pub fn new(net: VirtIONet<H, T, QUEUE_SIZE>) -> Self {
+        Self {
+            inner: RefCell::new(net),
+        }
+    }
+
+    /// Returns the MAC address of the device.
+    pub fn ethernet_address(&self) -> EthernetAddress {
+        EthernetAddress(self.inner.borrow().mac_address())
+    }
+
+    /// Returns a mutable reference to the underlying driver, e.g. to acknowledge interrupts.
+    pub fn get_mut(&mut self) -> &mut VirtIONet<H, T, QUEUE_SIZE> {
+        self.inner.get_mut()
+    }
+
+    /// Unwraps the underlying driver.
+    pub fn into_inner(self) -> VirtIONet<H, T, QUEUE_SIZE> {
+        self.inner.into_inner()
+    }
+
+    /// Drops all transmit buffers which the device has finished sending, to free up space in the
+    /// transmit queue.
+    fn reclaim_transmitted(&mut self) {
+        let net = self.inner.get_mut();
+        loop {
+            match net.poll_transmit() {
+                Ok(_) => {}
+                Err(Error::NotReady) => break,
+                Err(e) => {
+                    warn!("Failed to reclaim transmit buffer: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> phy::Device for NetDevice<H, T, QUEUE_SIZE> {
+    type RxToken<'a>
+        = NetRxToken<'a, H, T, QUEUE_SIZE>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = NetTxToken<'a, H, T, QUEUE_SIZE>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.reclaim_transmitted();
+        match self.inner.get_mut().receive() {
+            Ok(rx_buf) => Some((
+                NetRxToken {
+                    device: &self.inner,
+                    rx_buf: Some(rx_buf),
+                },
+                NetTxToken {
+                    device: &self.inner,
+                },
+            )),
+            Err(Error::NotReady) => None,
+            Err(e) => {
+                warn!("Failed to receive packet: {}", e);
+                None
+            }
+        }
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.reclaim_transmitted();
+        if self.inner.get_mut().can_send() {
+            Some(NetTxToken {
+                device: &self.inner,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let net = self.inner.borrow();
+        let mtu = net.mtu().map_or(DEFAULT_MTU, usize::from);
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = min(ETHERNET_HEADER_LEN + mtu, net.max_packet_len());
+        if net.guest_offloads().contains(GuestOffloads::CSUM) {
+            caps.checksum.tcp = Checksum::Tx;
+            caps.checksum.udp = Checksum::Tx;
+        }
+        caps
+    }
+}
+
+/// A token to receive a single packet from a [`NetDevice`].
+///
+/// The receive buffer is recycled to the device when the token is consumed or dropped.
+pub struct NetRxToken<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    device: &'a RefCell<VirtIONet<H, T, QUEUE_SIZE>>,
+    /// This is only `None` once the buffer has been recycled.
+    rx_buf: Option<RxBuffer<H>>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> NetRxToken<'_, H, T, QUEUE_SIZE> {
+    /// Gives the receive buffer back to the device, if it hasn't been already.
+    fn recycle(&mut self) {
+        if let Some(rx_buf) = self.rx_buf.take() {
+            if let Err(e) = self.device.borrow_mut().recycle_rx_buffer(rx_buf) {
+                warn!("Failed to recycle receive buffer: {}", e);
+            }
+        }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> phy::RxToken
+    for NetRxToken<'_, H, T, QUEUE_SIZE>
+{
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let result = f(self.rx_buf.as_mut().unwrap().packet_mut());
+        self.recycle();
+        result
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for NetRxToken<'_, H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        self.recycle();
+    }
+}
+
+/// A token to transmit a single packet on a [`NetDevice`].
+///
+/// The packet is queued without waiting for the device to send it, and its buffer is freed by a
+/// later call to [`receive`](phy::Device::receive) or [`transmit`](phy::Device::transmit).
+pub struct NetTxToken<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    device: &'a RefCell<VirtIONet<H, T, QUEUE_SIZE>>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> phy::TxToken
+    for NetTxToken<'_, H, T, QUEUE_SIZE>
+{
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut device = self.device.borrow_mut();
+        let mut tx_buf = match device.new_tx_buffer(len) {
+            Ok(tx_buf) => tx_buf,
+            Err(e) => {
+                // smoltcp expects the packet to be written even if it can't be sent, so let it
+                // write to a temporary buffer which is then dropped.
+                warn!("Failed to allocate transmit buffer: {}", e);
+                return f(&mut vec![0; len]);
+            }
+        };
+        let result = f(tx_buf.packet_mut());
+        match device.transmit_begin(tx_buf) {
+            Ok(_) => device.notify_transmit(),
+            Err(e) => warn!("Failed to send packet: {}", e),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::net::{Config, Features, Status, VirtioNetHdr, QUEUE_RECEIVE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+        volatile::ReadOnly,
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use smoltcp::phy::{Device, RxToken};
+    use std::sync::Mutex;
+    use zerocopy::AsBytes;
+
+    const QUEUE_SIZE: usize = 2;
+
+    fn packet(contents: &[u8]) -> Vec<u8> {
+        let mut packet = VirtioNetHdr::default().as_bytes().to_vec();
+        packet.extend_from_slice(contents);
+        packet
+    }
+
+    #[test]
+    fn receive_recycles_buffers() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(1400),
+        };
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: (Features::MAC | Features::MTU).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let net =
+            VirtIONet::<FakeHal, FakeTransport<Config>, QUEUE_SIZE>::new(transport, 2048).unwrap();
+        let mut device = NetDevice::new(net);
+
+        assert_eq!(
+            device.ethernet_address(),
+            EthernetAddress([1, 2, 3, 4, 5, 6])
+        );
+        assert_eq!(
+            device.capabilities().max_transmission_unit,
+            ETHERNET_HEADER_LEN + 1400
+        );
+        assert!(device.receive(Instant::ZERO).is_none());
+
+        // Receive more packets than there are buffers, which only works if each buffer is given
+        // back to the device, whether or not the token is consumed.
+        for i in 0..(QUEUE_SIZE as u8 * 2) {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVE, &packet(&[i, 42]));
+            let (rx_token, _) = device.receive(Instant::ZERO).unwrap();
+            if i % 2 == 0 {
+                rx_token.consume(|buffer| assert_eq!(buffer, &[i, 42]));
+            }
+        }
+    }
+}
@@ -53,6 +53,10 @@ impl<H: Hal> Dma<H> {
     }
 }
 
+// Safe because the DMA memory is owned by the `Dma` and isn't tied to any particular thread, and
+// `Hal` only has static methods.
+unsafe impl<H: Hal> Send for Dma<H> {}
+
 impl<H: Hal> Drop for Dma<H> {
     fn drop(&mut self) {
         // Safe because the memory was previously allocated by `dma_alloc` in `Dma::new`, not yet
@@ -52,6 +52,10 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
     last_used_idx: u16,
 }
 
+// Safe because the pointers only refer to the DMA memory owned by `layout`, which is only accessed
+// through the `VirtQueue`.
+unsafe impl<H: Hal, const SIZE: usize> Send for VirtQueue<H, SIZE> {}
+
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// Create a new VirtQueue.
     pub fn new<T: Transport>(transport: &mut T, idx: u16) -> Result<Self> {
@@ -148,13 +152,68 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             self.write_desc(last);
         }
 
+        self.finish_chain(head, last, inputs.len() + outputs.len());
+        Ok(head)
+    }
+
+    /// Adds buffers which are already shared with the device, such as parts of a [`Dma`] region,
+    /// to the virtqueue, and returns a token.
+    ///
+    /// Each buffer is given as its physical address and length. Unlike [`add`](Self::add) this
+    /// doesn't call [`Hal::share`], so no copying or mapping is needed.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must remain valid and shared with the device, and must not be accessed by the
+    /// driver until a call to `pop_used_shared` with the returned token succeeds.
+    pub unsafe fn add_shared(
+        &mut self,
+        inputs: &[(PhysAddr, usize)],
+        outputs: &[(PhysAddr, usize)],
+    ) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        if inputs.len() + outputs.len() + self.num_used as usize > SIZE {
+            return Err(Error::QueueFull);
+        }
+
+        // allocate descriptors from free list
+        let head = self.free_head;
+        let mut last = self.free_head;
+
+        let buffers = inputs
+            .iter()
+            .map(|buffer| (buffer, BufferDirection::DriverToDevice))
+            .chain(
+                outputs
+                    .iter()
+                    .map(|buffer| (buffer, BufferDirection::DeviceToDriver)),
+            );
+        for (&(paddr, len), direction) in buffers {
+            // Write to desc_shadow then copy.
+            let desc = &mut self.desc_shadow[usize::from(self.free_head)];
+            desc.set_shared_buf(paddr, len, direction, DescFlags::NEXT);
+            last = self.free_head;
+            self.free_head = desc.next;
+
+            self.write_desc(last);
+        }
+
+        self.finish_chain(head, last, inputs.len() + outputs.len());
+        Ok(head)
+    }
+
+    /// Terminates the descriptor chain of `count` descriptors from `head` to `last` which has just
+    /// been written, and makes it available to the device.
+    fn finish_chain(&mut self, head: u16, last: u16, count: usize) {
         // set last_elem.next = NULL
         self.desc_shadow[usize::from(last)]
             .flags
             .remove(DescFlags::NEXT);
         self.write_desc(last);
 
-        self.num_used += (inputs.len() + outputs.len()) as u16;
+        self.num_used += count as u16;
 
         let avail_slot = self.avail_idx & (SIZE as u16 - 1);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
@@ -175,8 +234,6 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         // Write barrier so that device can see change to available index after this method returns.
         fence(Ordering::SeqCst);
-
-        Ok(head)
     }
 
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
@@ -255,6 +312,15 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Skips the next element of the used ring without recycling its descriptors, for when the
+    /// device has returned a token which doesn't belong to any outstanding request. Any descriptors
+    /// it refers to are leaked.
+    pub(crate) fn skip_used(&mut self) {
+        if self.can_pop() {
+            self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        }
+    }
+
     /// Returns the number of free descriptors.
     pub fn available_desc(&self) -> usize {
         SIZE - self.num_used as usize
@@ -322,6 +388,47 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) -> Result<u32> {
+        let len = self.next_used(token)?;
+
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(token, inputs, outputs);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        Ok(len)
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// This must only be used for tokens returned by [`add_shared`](Self::add_shared), as the
+    /// buffers are not unshared.
+    pub fn pop_used_shared(&mut self, token: u16) -> Result<u32> {
+        let len = self.next_used(token)?;
+
+        let original_free_head = self.free_head;
+        self.free_head = token;
+        let mut next = Some(token);
+        while let Some(desc_index) = next {
+            let desc = &mut self.desc_shadow[usize::from(desc_index)];
+            desc.unset_buf();
+            self.num_used -= 1;
+            next = desc.next();
+            if next.is_none() {
+                desc.next = original_free_head;
+            }
+
+            self.write_desc(desc_index);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        Ok(len)
+    }
+
+    /// Checks that the given token is next on the device used queue, and returns the total buffer
+    /// length which was used (written) by the device, without popping it.
+    fn next_used(&self, token: u16) -> Result<u32> {
         if !self.can_pop() {
             return Err(Error::NotReady);
         }
@@ -342,13 +449,6 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             // The device used a different descriptor chain to the one we were expecting.
             return Err(Error::WrongToken);
         }
-
-        // Safe because the caller ensures the buffers are valid and match the descriptor.
-        unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
-        }
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
-
         Ok(len)
     }
 }
@@ -522,10 +622,21 @@ impl Descriptor {
         extra_flags: DescFlags,
     ) {
         // Safe because our caller promises that the buffer is valid.
-        unsafe {
-            self.addr = H::share(buf, direction) as u64;
-        }
-        self.len = buf.len() as u32;
+        let paddr = unsafe { H::share(buf, direction) };
+        self.set_shared_buf(paddr, buf.len(), direction, extra_flags);
+    }
+
+    /// Sets the buffer address, length and flags for a buffer which is already shared with the
+    /// device.
+    fn set_shared_buf(
+        &mut self,
+        paddr: PhysAddr,
+        len: usize,
+        direction: BufferDirection,
+        extra_flags: DescFlags,
+    ) {
+        self.addr = paddr as u64;
+        self.len = len as u32;
         self.flags = extra_flags
             | match direction {
                 BufferDirection::DeviceToDriver => DescFlags::WRITE,
@@ -22,6 +22,10 @@ pub struct FakeTransport<C: 'static> {
     pub state: Arc<Mutex<State>>,
 }
 
+// Safe because the config space is owned by the test which created the transport, and is only
+// accessed through the transport while it exists.
+unsafe impl<C> Send for FakeTransport<C> {}
+
 impl<C> Transport for FakeTransport<C> {
     fn device_type(&self) -> DeviceType {
         self.device_type
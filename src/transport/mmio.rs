@@ -1,6 +1,6 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -216,7 +216,24 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region selector
+    ///
+    /// Writing to this register selects the shared memory region that the following operations
+    /// on the SHMLen and SHMBase registers apply to.
+    shm_sel: WriteOnly<u32>,
+
+    /// Shared memory region length, or all ones if the selected region doesn't exist
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+
+    /// Shared memory region guest physical base address
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 15],
 
     config_generation: ReadOnly<u32>,
 }
@@ -266,6 +283,12 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: ReadOnly::new(u32::MAX),
+            shm_len_high: ReadOnly::new(u32::MAX),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
@@ -502,6 +525,30 @@ impl Transport for MmioTransport {
         }
         Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
     }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            // Shared memory regions are only supported by the modern interface.
+            return None;
+        }
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        let (len, paddr) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            let len = u64::from(volread!(self.header, shm_len_low))
+                | u64::from(volread!(self.header, shm_len_high)) << 32;
+            let paddr = u64::from(volread!(self.header, shm_base_low))
+                | u64::from(volread!(self.header, shm_base_high)) << 32;
+            (len, paddr)
+        };
+        if len == u64::MAX {
+            None
+        } else {
+            Some(SharedMemoryRegion {
+                paddr: paddr as PhysAddr,
+                len,
+            })
+        }
+    }
 }
 
 impl Drop for MmioTransport {
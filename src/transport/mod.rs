@@ -89,6 +89,24 @@ pub trait Transport {
 
     /// Gets the pointer to the config space.
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Returns the location of the shared memory region with the given ID, or `None` if the
+    /// device doesn't have such a region.
+    ///
+    /// Ref: virtio 2.10 Shared Memory Regions
+    fn shared_memory_region(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
+    }
+}
+
+/// A region of memory shared between the device and the driver, which is mapped by the device
+/// into the guest physical address space.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The guest physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The length of the region in bytes.
+    pub len: u64,
 }
 
 bitflags! {
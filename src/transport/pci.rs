@@ -3,7 +3,7 @@
 pub mod bus;
 
 use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
@@ -17,6 +17,7 @@ use core::{
     mem::{align_of, size_of},
     ptr::{addr_of_mut, NonNull},
 };
+use log::warn;
 
 /// The PCI vendor ID for VirtIO devices.
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
@@ -40,6 +41,13 @@ const CAP_BAR_OFFSET_OFFSET: u8 = 8;
 const CAP_LENGTH_OFFSET: u8 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
 const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HI_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HI_OFFSET: u8 = 20;
+
+/// The maximum number of shared memory regions which the transport keeps track of.
+const MAX_SHARED_MEMORY_REGIONS: usize = 8;
 
 /// Common configuration.
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -49,6 +57,8 @@ const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// Shared memory region.
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
 
 fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -93,6 +103,8 @@ pub struct PciTransport {
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<NonNull<[u32]>>,
+    /// The shared memory regions of the device, along with their IDs.
+    shared_memory_regions: [Option<(u8, SharedMemoryRegion)>; MAX_SHARED_MEMORY_REGIONS],
 }
 
 impl PciTransport {
@@ -118,6 +130,8 @@ impl PciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shared_memory_cfgs = [None; MAX_SHARED_MEMORY_REGIONS];
+        let mut num_shared_memory_cfgs = 0;
         for capability in root.capabilities(device_function) {
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
@@ -153,6 +167,28 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG
+                    if cap_len >= 24 && num_shared_memory_cfgs < MAX_SHARED_MEMORY_REGIONS =>
+                {
+                    let id = (root
+                        .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_OFFSET_HI_OFFSET,
+                    );
+                    let length_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_LENGTH_HI_OFFSET,
+                    );
+                    shared_memory_cfgs[num_shared_memory_cfgs] = Some((
+                        id,
+                        struct_info.bar,
+                        u64::from(offset_hi) << 32 | u64::from(struct_info.offset),
+                        u64::from(length_hi) << 32 | u64::from(struct_info.length),
+                    ));
+                    num_shared_memory_cfgs += 1;
+                }
                 _ => {}
             }
         }
@@ -187,6 +223,17 @@ impl PciTransport {
             None
         };
 
+        let mut shared_memory_regions = [None; MAX_SHARED_MEMORY_REGIONS];
+        for (region, cfg) in shared_memory_regions.iter_mut().zip(shared_memory_cfgs) {
+            if let Some((id, bar, offset, length)) = cfg {
+                // A bad shared memory region shouldn't stop the rest of the device from working.
+                match get_bar_shared_memory(root, device_function, bar, offset, length) {
+                    Ok(shared_memory) => *region = Some((id, shared_memory)),
+                    Err(e) => warn!("Ignoring shared memory region {}: {}", id, e),
+                }
+            }
+        }
+
         Ok(Self {
             device_type,
             device_function,
@@ -195,6 +242,7 @@ impl PciTransport {
             notify_off_multiplier,
             isr_status,
             config_space,
+            shared_memory_regions,
         })
     }
 }
@@ -339,6 +387,14 @@ impl Transport for PciTransport {
             Err(Error::ConfigSpaceMissing)
         }
     }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.shared_memory_regions
+            .iter()
+            .flatten()
+            .find(|(region_id, _)| *region_id == id)
+            .map(|(_, region)| *region)
+    }
 }
 
 impl Drop for PciTransport {
@@ -393,7 +449,7 @@ fn get_bar_region<H: Hal, T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
+    if u64::from(struct_info.offset + struct_info.length) > bar_size
         || size_of::<T>() > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
@@ -411,6 +467,33 @@ fn get_bar_region<H: Hal, T>(
     Ok(vaddr.cast())
 }
 
+/// Returns the location of a shared memory region within some BAR.
+///
+/// Unlike other structures the region isn't mapped, as it may be very large and the device
+/// decides which parts of it are in use.
+fn get_bar_shared_memory(
+    root: &mut PciRoot,
+    device_function: DeviceFunction,
+    bar: u8,
+    offset: u64,
+    length: u64,
+) -> Result<SharedMemoryRegion, VirtioPciError> {
+    let bar_info = root.bar_info(device_function, bar)?;
+    let (bar_address, bar_size) = bar_info
+        .memory_address_size()
+        .ok_or(VirtioPciError::UnexpectedIoBar)?;
+    if bar_address == 0 {
+        return Err(VirtioPciError::BarNotAllocated(bar));
+    }
+    if !matches!(offset.checked_add(length), Some(end) if end <= bar_size) {
+        return Err(VirtioPciError::BarOffsetOutOfRange);
+    }
+    Ok(SharedMemoryRegion {
+        paddr: (bar_address + offset) as PhysAddr,
+        len: length,
+    })
+}
+
 fn get_bar_region_slice<H: Hal, T>(
     root: &mut PciRoot,
     device_function: DeviceFunction,
@@ -271,15 +271,23 @@ impl PciRoot {
         } else {
             // Memory space
             let mut address = u64::from(bar_orig & 0xfffffff0);
+            let mut size = u64::from(size);
             let prefetchable = bar_orig & 0x00000008 != 0;
             let address_type = MemoryBarType::try_from(((bar_orig & 0x00000006) >> 1) as u8)?;
             if address_type == MemoryBarType::Width64 {
                 if bar_index >= 5 {
                     return Err(PciError::InvalidBarType);
                 }
-                let address_top =
-                    self.config_read_word(device_function, BAR0_OFFSET + 4 * (bar_index + 1));
+                let top_offset = BAR0_OFFSET + 4 * (bar_index + 1);
+                let address_top = self.config_read_word(device_function, top_offset);
                 address |= u64::from(address_top) << 32;
+
+                // The size of a 64-bit BAR also depends on the upper half of the mask.
+                self.config_write_word(device_function, top_offset, 0xffffffff);
+                let size_mask_top = self.config_read_word(device_function, top_offset);
+                self.config_write_word(device_function, top_offset, address_top);
+                let size_mask = u64::from(size_mask_top) << 32 | u64::from(size_mask);
+                size = (!(size_mask & !0xf)).wrapping_add(1);
             }
             Ok(BarInfo::Memory {
                 address_type,
@@ -329,7 +337,7 @@ pub enum BarInfo {
         /// The memory address, always 16-byte aligned.
         address: u64,
         /// The size of the BAR in bytes.
-        size: u32,
+        size: u64,
     },
     /// The BAR is for an I/O region.
     IO {
@@ -355,7 +363,7 @@ impl BarInfo {
 
     /// Returns the address and size of this BAR if it is a memory bar, or `None` if it is an IO
     /// BAR.
-    pub fn memory_address_size(&self) -> Option<(u64, u32)> {
+    pub fn memory_address_size(&self) -> Option<(u64, u64)> {
         if let Self::Memory { address, size, .. } = self {
             Some((*address, *size))
         } else {